@@ -3,15 +3,31 @@
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 
-use fdtables::*;
+use fdtables::{dashmaparrayglobal, dashmapvecglobal, muthashmaxglobal, vanillaglobal};
+use fdtables::{threei, FdTableBackend};
 
 use std::thread;
 
 use std::time::Duration;
 
+// Every implementation is benchmarked in the same run, through the
+// FdTableBackend trait.
 pub fn run_benchmark(c: &mut Criterion) {
+    run_benchmark_for::<dashmaparrayglobal::FdTables>(c);
+    run_benchmark_for::<dashmapvecglobal::FdTables>(c);
+    run_benchmark_for::<vanillaglobal::FdTables>(c);
+    run_benchmark_for::<muthashmaxglobal::FdTables>(c);
+}
+
+pub fn run_benchmark_for<B: FdTableBackend>(c: &mut Criterion) {
+    let backend = B::new();
+    let fdt = &backend;
+    let algoname = B::ALGONAME;
+
+    fdt.init_empty_cage(threei::TESTING_CAGEID);
+
     // I'm going to do some simple calls using fdtables in this file
-    let mut group = c.benchmark_group("fdtables basics");
+    let mut group = c.benchmark_group(format!("fdtables basics/{}", algoname));
 
     // Reduce the time to reduce disk space needed and go faster.
     // Default is 5s...
@@ -20,66 +36,120 @@ pub fn run_benchmark(c: &mut Criterion) {
     // Shorten the warm up time as well from 3s to this...
     group.warm_up_time(Duration::from_secs(1));
 
-    let fd1 = get_unused_virtual_fd(threei::TESTING_CAGEID, 10, true, 100).unwrap();
-    let fd2 = get_unused_virtual_fd(threei::TESTING_CAGEID, 20, true, 1).unwrap();
-    let fd3 = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 10).unwrap();
+    let fd1 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 10, true, 100)
+        .unwrap();
+    let fd2 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 20, true, 1)
+        .unwrap();
+    let fd3 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 10)
+        .unwrap();
 
     // I'm going to insert three items, then do 10000 queries, then clean up...
-    group.bench_function(format!("{}/st: trans (10K)", ALGONAME), |b| {
+    group.bench_function(format!("{}/st: trans (10K)", algoname), |b| {
         b.iter(|| {
             for _ in 0..1000 {
-                translate_virtual_fd(threei::TESTING_CAGEID, fd1).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd2).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd3).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd1).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd2).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd3).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd1).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd2).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd3).unwrap();
-                translate_virtual_fd(threei::TESTING_CAGEID, fd1).unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd1)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd2)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd3)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd1)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd2)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd3)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd1)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd2)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd3)
+                    .unwrap();
+                fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd1)
+                    .unwrap();
             }
         })
     });
 
-    refresh();
+    fdt.refresh();
 
     // only do 1000 because 1024 is a common lower bound
-    group.bench_function(format!("{}/st: getvirt (1K)", ALGONAME), |b| {
+    group.bench_function(format!("{}/st: getvirt (1K)", algoname), |b| {
         b.iter(|| {
             for _ in 0..1000 {
-                _ = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 10).unwrap();
+                _ = fdt
+                    .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 10)
+                    .unwrap();
             }
             // unfortunately, we need to clean up, or else we will
             // get an exception due to the table being full...
-            refresh();
+            fdt.refresh();
         })
     });
 
-    // Check get_optionalinfo...
-    let fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 10).unwrap();
-    group.bench_function(format!("{}/st: get_opt (10K)", ALGONAME), |b| {
+    // A server with hundreds of sockets open gets a new fd on every accept.
+    // Check how much the fds that are already open slow this down.  The new
+    // fds are close on exec, so exec cleans them up (but leaves the others).
+    for fdcount in [0, 16, 256, 1000].iter() {
+        for _ in 0..*fdcount {
+            let _fd = fdt
+                .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, false, 10)
+                .unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::new(
+                format!("{}/st: getvirt+exec (16, open fds:{})", algoname, fdcount),
+                fdcount,
+            ),
+            fdcount,
+            |b, _fdcount| {
+                b.iter(|| {
+                    for _ in 0..16 {
+                        _ = fdt
+                            .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 40, true, 10)
+                            .unwrap();
+                    }
+                    fdt.empty_fds_for_exec(threei::TESTING_CAGEID);
+                })
+            },
+        );
+        fdt.refresh();
+    }
+
+    // Check reading the perfdinfo...
+    let fd = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 10)
+        .unwrap();
+    group.bench_function(format!("{}/st: get_perfdinfo (10K)", algoname), |b| {
         b.iter(|| {
             for _ in 0..10000 {
-                _ = get_optionalinfo(threei::TESTING_CAGEID, fd).unwrap();
+                _ = fdt
+                    .translate_virtual_fd(threei::TESTING_CAGEID, fd)
+                    .unwrap()
+                    .perfdinfo;
             }
         })
     });
 
-    refresh();
+    fdt.refresh();
 
-    // flip the set_optionalinfo data...
-    let fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 10).unwrap();
-    group.bench_function(format!("{}/st: set_opt (10K)", ALGONAME), |b| {
+    // flip the perfdinfo data...
+    let fd = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 10)
+        .unwrap();
+    group.bench_function(format!("{}/st: set_perfdinfo (10K)", algoname), |b| {
         b.iter(|| {
             for _ in 0..5000 {
-                _ = set_optionalinfo(threei::TESTING_CAGEID, fd, 100).unwrap();
-                _ = set_optionalinfo(threei::TESTING_CAGEID, fd, 200).unwrap();
+                fdt.set_perfdinfo(threei::TESTING_CAGEID, fd, 100).unwrap();
+                fdt.set_perfdinfo(threei::TESTING_CAGEID, fd, 200).unwrap();
             }
         })
     });
 
-    refresh();
+    fdt.refresh();
 
     // TODO: I'd love to count memory use in these tests too.  It really
     // varies widely...
@@ -88,38 +158,69 @@ pub fn run_benchmark(c: &mut Criterion) {
     for fdcount in [1, 4, 16, 64, 256, 1024].iter() {
         // Setup the fds up front, outside of the benchmark...
         for _ in 0..*fdcount {
-            let _fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, false, 10).unwrap();
+            let _fd = fdt
+                .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, false, 10)
+                .unwrap();
         }
+        // Every fork copies fdcount fds, which would go over the total fd
+        // limit long before the refresh below.  Lift it for this benchmark.
+        fdt.set_total_fd_limit(u64::MAX);
         let mut cagenumtouse = 1;
         group.bench_with_input(
-            BenchmarkId::new(format!("{}/st: fork (fds:{})", ALGONAME, fdcount), fdcount),
+            BenchmarkId::new(format!("{}/st: fork (fds:{})", algoname, fdcount), fdcount),
             fdcount,
             |b, _fdcount| {
                 b.iter({
                     || {
-                        copy_fdtable_for_cage(threei::TESTING_CAGEID, cagenumtouse).unwrap();
+                        fdt.copy_fdtable_for_cage(threei::TESTING_CAGEID, cagenumtouse)
+                            .unwrap();
                         // Get a new cage each time...
                         cagenumtouse += 1;
                         // The number of cages may grow large and this could
                         // also skew the results...  Reset after 100...
-                        //
-                        // Also, if I ever get around to limiting the global
-                        // fds, this will panic...
                         if cagenumtouse % 100 == 0 {
-                            refresh();
+                            fdt.refresh();
+                            fdt.set_total_fd_limit(u64::MAX);
                         }
                     }
                 })
             },
         );
-        refresh();
+        fdt.refresh();
+    }
+    fdt.refresh();
+
+    // check fork followed by exec in the child, which is how most processes
+    // are started...  Half of the fds are close-on-exec.  The child exits
+    // after, so the number of cages doesn't grow.
+    for fdcount in [1, 4, 16, 64, 256, 1024].iter() {
+        for fdnum in 0..*fdcount {
+            let _fd = fdt
+                .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, fdnum % 2 == 1, 10)
+                .unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}/st: fork+exec+exit (fds:{})", algoname, fdcount), fdcount),
+            fdcount,
+            |b, _fdcount| {
+                b.iter({
+                    || {
+                        fdt.copy_fdtable_for_cage(threei::TESTING_CAGEID, 1)
+                            .unwrap();
+                        fdt.empty_fds_for_exec(1);
+                        fdt.remove_cage_from_fdtable(1);
+                    }
+                })
+            },
+        );
+        fdt.refresh();
     }
-    refresh();
+    fdt.refresh();
 
     // check remove_cage_from_fdtable (exit) time...
     for fdcount in [1, 4, 16, 64, 256, 1024].iter() {
         group.bench_with_input(
-            BenchmarkId::new(format!("{}/st: exit (fds:{})", ALGONAME, fdcount), fdcount),
+            BenchmarkId::new(format!("{}/st: exit (fds:{})", algoname, fdcount), fdcount),
             fdcount,
             |b, _fdcount| {
                 b.iter({
@@ -128,24 +229,25 @@ pub fn run_benchmark(c: &mut Criterion) {
                         // only want to check the empty_fds_for_exec() call
                         // time...
                         for _ in 0..*fdcount {
-                            let _fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, false, 10)
+                            let _fd = fdt
+                                .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, false, 10)
                                 .unwrap();
                         }
-                        remove_cage_from_fdtable(threei::TESTING_CAGEID);
+                        fdt.remove_cage_from_fdtable(threei::TESTING_CAGEID);
                         // need to re-add the cage...
-                        refresh();
+                        fdt.refresh();
                     }
                 })
             },
         );
     }
-    refresh();
+    fdt.refresh();
 
     // check on empty_fds_for_exec with the flag set to false...
     for fdcount in [1, 4, 16, 64, 256, 1024].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/st: exec (false) (fds:{})", ALGONAME, fdcount),
+                format!("{}/st: exec (false) (fds:{})", algoname, fdcount),
                 fdcount,
             ),
             fdcount,
@@ -156,23 +258,24 @@ pub fn run_benchmark(c: &mut Criterion) {
                         // only want to check the empty_fds_for_exec() call
                         // time...
                         for _ in 0..*fdcount {
-                            let _fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, false, 10)
+                            let _fd = fdt
+                                .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, false, 10)
                                 .unwrap(); // Notice the false here!
                         }
-                        empty_fds_for_exec(threei::TESTING_CAGEID);
-                        refresh();
+                        fdt.empty_fds_for_exec(threei::TESTING_CAGEID);
+                        fdt.refresh();
                     }
                 })
             },
         );
     }
-    refresh();
+    fdt.refresh();
 
     // Now, check on empty_fds_for_exec with the flag set to true...
     for fdcount in [1, 4, 16, 64, 256, 1024].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/st: exec (true) (fds:{})", ALGONAME, fdcount),
+                format!("{}/st: exec (true) (fds:{})", algoname, fdcount),
                 fdcount,
             ),
             fdcount,
@@ -183,68 +286,83 @@ pub fn run_benchmark(c: &mut Criterion) {
                         // only want to check the empty_fds_for_exec() call
                         // time...
                         for _ in 0..*fdcount {
-                            let _fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 10)
+                            let _fd = fdt
+                                .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 10)
                                 .unwrap(); // Notice the true here!
                         }
-                        empty_fds_for_exec(threei::TESTING_CAGEID);
-                        //refresh(); <- Don't need this because the prior
+                        fdt.empty_fds_for_exec(threei::TESTING_CAGEID);
+                        //fdt.refresh(); <- Don't need this because the prior
                         // line cleans up for me!
                     }
                 })
             },
         );
     }
-    refresh();
+    fdt.refresh();
 
     // ---------------- MULTI-THREADED / 1 cage TESTS ------------------  //
 
     // -- Multithreaded benchmark 1: 100K translate calls --
 
-    let fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 10, true, 100).unwrap();
-    let fd2 = get_unused_virtual_fd(threei::TESTING_CAGEID, 20, true, 200).unwrap();
-    let fd3 = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 300).unwrap();
+    let fd = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 10, true, 100)
+        .unwrap();
+    let fd2 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 20, true, 200)
+        .unwrap();
+    let fd3 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 300)
+        .unwrap();
 
     for threadcount in [1, 2, 4, 8, 16].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/[mt1c:{}] trans_virtfd (100K)", ALGONAME, threadcount),
+                format!("{}/[mt1c:{}] trans_virtfd (100K)", algoname, threadcount),
                 threadcount,
             ),
             threadcount,
             |b, threadcount| {
                 b.iter({
                     || {
-                        let mut thread_handle_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                        for _numthreads in 0..*threadcount {
-                            // Need to borrow so the lifetime can live outside
-                            // the thread's closure
-                            let thisthreadcount = *threadcount;
-
-                            thread_handle_vec.push(thread::spawn(move || {
-                                // Do 10K / threadcount of 10 requests each.  100K total
-                                for _ in 0..10000 / thisthreadcount {
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd2).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd2).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd2).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd3).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd3).unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd3).unwrap();
-                                }
-                            }));
-                        }
-                        for handle in thread_handle_vec {
-                            handle.join().unwrap();
-                        }
+                        thread::scope(|s| {
+                            for _numthreads in 0..*threadcount {
+                                // Need to borrow so the lifetime can live outside
+                                // the thread's closure
+                                let thisthreadcount = *threadcount;
+
+                                s.spawn(move || {
+                                    // Do 10K / threadcount of 10 requests each.  100K total
+                                    for _ in 0..10000 / thisthreadcount {
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd2)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd2)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd2)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd3)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd3)
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd3)
+                                            .unwrap();
+                                    }
+                                });
+                            }
+                        });
                     }
                 })
             },
         );
     }
-    refresh();
+    fdt.refresh();
 
     // -- Multithreaded benchmark 2: get / translate interleaved --
 
@@ -253,37 +371,38 @@ pub fn run_benchmark(c: &mut Criterion) {
     for threadcount in [1, 2, 4, 8, 16].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/[mt1c:{}] get_trans (1K per)", ALGONAME, threadcount),
+                format!("{}/[mt1c:{}] get_trans (1K per)", algoname, threadcount),
                 threadcount,
             ),
             threadcount,
             |b, threadcount| {
                 b.iter({
                     || {
-                        let mut thread_handle_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                        for _numthreads in 0..*threadcount {
-                            // Need to borrow so the lifetime can live outside
-                            // the thread's closure
-                            let thisthreadcount = *threadcount;
-
-                            thread_handle_vec.push(thread::spawn(move || {
-                                // Do 1K / threadcount
-                                for _ in 0..1000 / thisthreadcount {
-                                    let fd = get_unused_virtual_fd(
-                                        threei::TESTING_CAGEID,
-                                        10,
-                                        true,
-                                        100,
-                                    )
-                                    .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID, fd).unwrap();
-                                }
-                            }));
-                        }
-                        for handle in thread_handle_vec {
-                            handle.join().unwrap();
-                        }
-                        refresh();
+                        thread::scope(|s| {
+                            for _numthreads in 0..*threadcount {
+                                // Need to borrow so the lifetime can live outside
+                                // the thread's closure
+                                let thisthreadcount = *threadcount;
+
+                                s.spawn(move || {
+                                    // Do 1K / threadcount
+                                    for _ in 0..1000 / thisthreadcount {
+                                        let fd = fdt
+                                            .get_unused_virtual_fd(
+                                                threei::TESTING_CAGEID,
+                                                0,
+                                                10,
+                                                true,
+                                                100,
+                                            )
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(threei::TESTING_CAGEID, fd)
+                                            .unwrap();
+                                    }
+                                });
+                            }
+                        });
+                        fdt.refresh();
                     }
                 })
             },
@@ -297,36 +416,36 @@ pub fn run_benchmark(c: &mut Criterion) {
     for threadcount in [1, 2, 4, 8, 16].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/[mt1c:{}] get_close (10K)", ALGONAME, threadcount),
+                format!("{}/[mt1c:{}] get_close (10K)", algoname, threadcount),
                 threadcount,
             ),
             threadcount,
             |b, threadcount| {
                 b.iter({
                     || {
-                        let mut thread_handle_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                        for _numthreads in 0..*threadcount {
-                            // Need to borrow so the lifetime can live outside
-                            // the thread's closure
-                            let thisthreadcount = *threadcount;
-
-                            thread_handle_vec.push(thread::spawn(move || {
-                                // Do 100K / threadcount each
-                                for _ in 0..10000 / thisthreadcount {
-                                    let fd = get_unused_virtual_fd(
-                                        threei::TESTING_CAGEID,
-                                        10,
-                                        true,
-                                        100,
-                                    )
-                                    .unwrap();
-                                    close_virtualfd(threei::TESTING_CAGEID, fd).unwrap();
-                                }
-                            }));
-                        }
-                        for handle in thread_handle_vec {
-                            handle.join().unwrap();
-                        }
+                        thread::scope(|s| {
+                            for _numthreads in 0..*threadcount {
+                                // Need to borrow so the lifetime can live outside
+                                // the thread's closure
+                                let thisthreadcount = *threadcount;
+
+                                s.spawn(move || {
+                                    // Do 100K / threadcount each
+                                    for _ in 0..10000 / thisthreadcount {
+                                        let fd = fdt
+                                            .get_unused_virtual_fd(
+                                                threei::TESTING_CAGEID,
+                                                0,
+                                                10,
+                                                true,
+                                                100,
+                                            )
+                                            .unwrap();
+                                        fdt.close_virtualfd(threei::TESTING_CAGEID, fd).unwrap();
+                                    }
+                                });
+                            }
+                        });
                     }
                 })
             },
@@ -337,65 +456,100 @@ pub fn run_benchmark(c: &mut Criterion) {
 
     // -- Multithreaded benchmark 1: 100K translate calls --
 
-    let fd = get_unused_virtual_fd(threei::TESTING_CAGEID, 10, true, 100).unwrap();
-    let fd2 = get_unused_virtual_fd(threei::TESTING_CAGEID, 20, true, 200).unwrap();
-    let fd3 = get_unused_virtual_fd(threei::TESTING_CAGEID, 30, true, 300).unwrap();
+    let fd = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 10, true, 100)
+        .unwrap();
+    let fd2 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 20, true, 200)
+        .unwrap();
+    let fd3 = fdt
+        .get_unused_virtual_fd(threei::TESTING_CAGEID, 0, 30, true, 300)
+        .unwrap();
     for val in 1..16 {
         // I'm just going to assume I can increment these...
-        copy_fdtable_for_cage(threei::TESTING_CAGEID, threei::TESTING_CAGEID + val).unwrap();
+        fdt.copy_fdtable_for_cage(threei::TESTING_CAGEID, threei::TESTING_CAGEID + val)
+            .unwrap();
     }
 
     for threadcount in [1, 2, 4, 8, 16].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/[mtmc:{}] trans_virtfd (100K)", ALGONAME, threadcount),
+                format!("{}/[mtmc:{}] trans_virtfd (100K)", algoname, threadcount),
                 threadcount,
             ),
             threadcount,
             |b, threadcount| {
                 b.iter({
                     || {
-                        let mut thread_handle_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                        for numthreads in 0..*threadcount {
-                            // Need to borrow so the lifetime can live outside
-                            // the thread's closure
-                            let thisthreadcount = *threadcount;
-
-                            thread_handle_vec.push(thread::spawn(move || {
-                                // Do 10K / threadcount of 10 requests each.  100K total
-                                for _ in 0..10000 / thisthreadcount {
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd)
+                        thread::scope(|s| {
+                            for numthreads in 0..*threadcount {
+                                // Need to borrow so the lifetime can live outside
+                                // the thread's closure
+                                let thisthreadcount = *threadcount;
+
+                                s.spawn(move || {
+                                    // Do 10K / threadcount of 10 requests each.  100K total
+                                    for _ in 0..10000 / thisthreadcount {
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd2)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd2,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd2)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd2,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd2)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd2,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd3)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd3,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd3)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd3,
+                                        )
                                         .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd3)
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd3,
+                                        )
                                         .unwrap();
-                                }
-                            }));
-                        }
-                        for handle in thread_handle_vec {
-                            handle.join().unwrap();
-                        }
+                                    }
+                                });
+                            }
+                        });
                     }
                 })
             },
         );
     }
-    refresh();
+    fdt.refresh();
 
     // -- Multithreaded benchmark 2: get / translate interleaved --
 
@@ -404,7 +558,7 @@ pub fn run_benchmark(c: &mut Criterion) {
     for threadcount in [1, 2, 4, 8, 16].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/[mtmc:{}] get_trans (1K per)", ALGONAME, threadcount),
+                format!("{}/[mtmc:{}] get_trans (1K per)", algoname, threadcount),
                 threadcount,
             ),
             threadcount,
@@ -413,53 +567,57 @@ pub fn run_benchmark(c: &mut Criterion) {
                     || {
                         // setup the empty cages
                         for numthreads in 1..*threadcount {
-                            copy_fdtable_for_cage(
+                            fdt.copy_fdtable_for_cage(
                                 threei::TESTING_CAGEID,
                                 threei::TESTING_CAGEID + numthreads,
                             )
                             .unwrap();
                         }
 
-                        let mut thread_handle_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                        for numthreads in 0..*threadcount {
-                            // Need to borrow so the lifetime can live outside
-                            // the thread's closure
-                            let thisthreadcount = *threadcount;
-                            // make a copy for this cage...
-
-                            thread_handle_vec.push(thread::spawn(move || {
-                                // Do 1K / threadcount
-                                for _ in 0..1000 / thisthreadcount {
-                                    let fd = get_unused_virtual_fd(
-                                        threei::TESTING_CAGEID + numthreads,
-                                        10,
-                                        true,
-                                        100,
-                                    )
-                                    .unwrap();
-                                    translate_virtual_fd(threei::TESTING_CAGEID + numthreads, fd)
+                        thread::scope(|s| {
+                            for numthreads in 0..*threadcount {
+                                // Need to borrow so the lifetime can live outside
+                                // the thread's closure
+                                let thisthreadcount = *threadcount;
+                                // make a copy for this cage...
+
+                                s.spawn(move || {
+                                    // Do 1K / threadcount
+                                    for _ in 0..1000 / thisthreadcount {
+                                        let fd = fdt
+                                            .get_unused_virtual_fd(
+                                                threei::TESTING_CAGEID + numthreads,
+                                                0,
+                                                10,
+                                                true,
+                                                100,
+                                            )
+                                            .unwrap();
+                                        fdt.translate_virtual_fd(
+                                            threei::TESTING_CAGEID + numthreads,
+                                            fd,
+                                        )
                                         .unwrap();
-                                }
-                            }));
-                        }
-                        for handle in thread_handle_vec {
-                            handle.join().unwrap();
-                        }
-                        refresh();
+                                    }
+                                });
+                            }
+                        });
+                        fdt.refresh();
                     }
                 })
             },
         );
     }
 
-    refresh();
+    fdt.refresh();
 
     // -- Multithreaded benchmark 3: get / close interleaved --
 
     // dup the cage tables as this is different cages for each...
     for val in 1..16 {
         // I'm just going to assume I can increment these...
-        copy_fdtable_for_cage(threei::TESTING_CAGEID, threei::TESTING_CAGEID + val).unwrap();
+        fdt.copy_fdtable_for_cage(threei::TESTING_CAGEID, threei::TESTING_CAGEID + val)
+            .unwrap();
     }
 
     // I will always do 100K requests (split amongst some number of threads)
@@ -467,36 +625,36 @@ pub fn run_benchmark(c: &mut Criterion) {
     for threadcount in [1, 2, 4, 8, 16].iter() {
         group.bench_with_input(
             BenchmarkId::new(
-                format!("{}/[mtmc:{}] get_close (10K)", ALGONAME, threadcount),
+                format!("{}/[mtmc:{}] get_close (10K)", algoname, threadcount),
                 threadcount,
             ),
             threadcount,
             |b, threadcount| {
                 b.iter({
                     || {
-                        let mut thread_handle_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                        for _numthreads in 0..*threadcount {
-                            // Need to borrow so the lifetime can live outside
-                            // the thread's closure
-                            let thisthreadcount = *threadcount;
-
-                            thread_handle_vec.push(thread::spawn(move || {
-                                // Do 100K / threadcount each
-                                for _ in 0..10000 / thisthreadcount {
-                                    let fd = get_unused_virtual_fd(
-                                        threei::TESTING_CAGEID,
-                                        10,
-                                        true,
-                                        100,
-                                    )
-                                    .unwrap();
-                                    close_virtualfd(threei::TESTING_CAGEID, fd).unwrap();
-                                }
-                            }));
-                        }
-                        for handle in thread_handle_vec {
-                            handle.join().unwrap();
-                        }
+                        thread::scope(|s| {
+                            for _numthreads in 0..*threadcount {
+                                // Need to borrow so the lifetime can live outside
+                                // the thread's closure
+                                let thisthreadcount = *threadcount;
+
+                                s.spawn(move || {
+                                    // Do 100K / threadcount each
+                                    for _ in 0..10000 / thisthreadcount {
+                                        let fd = fdt
+                                            .get_unused_virtual_fd(
+                                                threei::TESTING_CAGEID,
+                                                0,
+                                                10,
+                                                true,
+                                                100,
+                                            )
+                                            .unwrap();
+                                        fdt.close_virtualfd(threei::TESTING_CAGEID, fd).unwrap();
+                                    }
+                                });
+                            }
+                        });
                     }
                 })
             },
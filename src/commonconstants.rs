@@ -1,7 +1,12 @@
 // This file exists to make it easier to vary a single file of constants
 // instead of editing each implementation...
 
-/// Per-process maximum number of fds...
+use crate::fdtableerror::FdTableError;
+
+use std::sync::Arc;
+
+/// Per-process maximum number of fds...  This is also the default (and the
+/// largest allowed) soft and hard limit for a cage.  See `set_fd_limit`.
 pub const FD_PER_PROCESS_MAX: u64 = 1024;
 
 // /// Use this to indicate there isn't a real fd backing an item
@@ -24,7 +29,11 @@ pub const FDT_KINDEPOLL: u32 = 0xff00_0002;
 #[doc = include_str!("../docs/fdtableentry.md")]
 #[derive(Clone, Copy, Hash, Debug, PartialEq, Eq)]
 /// This is a table entry, looked up by virtual fd.
-pub struct FDTableEntry {
+///
+/// `P` is the type of the per-fd payload in `perfdinfo`.  The global tables
+/// (and the free functions) use `u64`.  Use an `FdTables` instance to store
+/// something else.
+pub struct FDTableEntry<P = u64> {
     /// This is the kind of fd which it is.  These are user defined values
     /// so they can track what this means.  Appropriate for storing information
     /// which is global to all virtual fds that track this.
@@ -39,22 +48,110 @@ pub struct FDTableEntry {
     pub should_cloexec: bool,
     /// Used to store fd specific extra information, such as flags or similar
     /// which may differ for different 'dup'ed copies of a fd.   Whatever
-    /// the user desires may be placed here.
-    pub perfdinfo: u64,
+    /// the user desires may be placed here.  It is cloned when the fd is
+    /// copied (fork / dup) and is dropped when the fd is closed (after the
+    /// close handler gets it).
+    pub perfdinfo: P,
+}
+
+/// What to do when a call names a cage that isn't in the fdtable.  Set
+/// with `set_unknown_cage_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum UnknownCagePolicy {
+    /// Panic.  An unknown cage usually means a bookkeeping bug somewhere, so
+    /// this is the default (and is the strictest mode for debugging).
+    #[default]
+    Panic,
+    /// Return `FdTableError::UnknownCage` (`ESRCH`).  Calls that don't return
+    /// a `Result` act as though the cage has no fds.
+    Error,
+    /// Create an empty fdtable for the cage, and then do the call as usual.
+    /// Useful if cages are created lazily.
+    AutoCreate,
 }
 
 #[allow(non_snake_case)]
 /// A function used when registering close handlers which does nothing...
 /// It is the default if no close handlers are defined
-pub const fn NULL_FUNC(_: FDTableEntry, _: u64) {}
+// Not a const fn, because it has to drop the entry (and so the payload).
+pub fn NULL_FUNC<P>(_: FDTableEntry<P>, _: u64) {}
+
+/// A close handler which can capture state, for `add_close_handlers`.  It is
+/// called with the closed entry and the number of fds which still refer to
+/// its `(fdkind, underfd)`.
+pub type CloseHandler<P = u64> = Box<dyn Fn(FDTableEntry<P>, u64) + Send + Sync>;
+
+/// Returned by `add_close_handlers`.  Pass it to `remove_close_handlers` to
+/// remove those handlers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CloseHandlerToken(pub(crate) u64);
+
+/// Why a reference to an `(fdkind, underfd)` was added.  Passed to the
+/// handlers from `add_open_handler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive] // More ways to add a reference may come later...
+pub enum OpenReason {
+    /// A new fd from `get_unused_virtual_fd`, `get_unused_virtual_fd_from`,
+    /// or `get_specific_virtual_fd`, or an fd changed to refer to it with
+    /// `update_virtualfd_entry`.
+    Allocate,
+    /// An fd copied by `dup_virtualfd`, `dup2_virtualfd`, or
+    /// `dup3_virtualfd`.
+    Dup,
+    /// A table copied by `copy_fdtable_for_cage`, or by a cage which stops
+    /// sharing a table (`unshare_fdtable_for_cage`, or exec).
+    Fork,
+    /// An fd copied to another cage by `transfer_virtualfds` or
+    /// `getfd_virtualfd`.
+    Transfer,
+}
+
+/// A handler for `add_open_handler`.  It is called with the new entry, the
+/// number of fds which refer to its `(fdkind, underfd)` once the call that
+/// added it is done, and why the reference was added.  The count is read
+/// just before the handler is called, so it includes every reference that
+/// call added, not just the ones before this one.
+pub type OpenHandler<P = u64> = Box<dyn Fn(&FDTableEntry<P>, u64, OpenReason) + Send + Sync>;
+
+/// Returned by `add_open_handler`.  Pass it to `remove_open_handler` to
+/// remove that handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OpenHandlerToken(pub(crate) u64);
+
+/// Decides if a cage may copy another cage's fd with `getfd_virtualfd`.  It
+/// is given the requesting cageid, the target cageid, and the target's
+/// virtual fd, and returns true to allow the copy.  Set with
+/// `set_getfd_policy`.  It may be a closure, so it can capture the grate's
+/// own state (like which cages were granted access).
+pub type GetfdPolicy = Arc<dyn Fn(u64, u64, u64) -> bool + Send + Sync>;
+
+/// Told about every `getfd_virtualfd` call: the requesting cageid, the target
+/// cageid, the target's virtual fd, and what the call returned.  Set with
+/// `set_getfd_audit`.  Like `GetfdPolicy`, it may be a closure.
+pub type GetfdAudit = Arc<dyn Fn(u64, u64, u64, Result<u64, FdTableError>) + Send + Sync>;
+
+#[allow(non_snake_case)]
+/// The default getfd policy.  A cage may only copy its own fds, so any
+/// cross-cage copy has to be allowed by a policy the grate sets.
+#[must_use]
+pub const fn GETFD_SAME_CAGE_ONLY(requestercageid: u64, targetcageid: u64, _: u64) -> bool {
+    requestercageid == targetcageid
+}
+
+#[allow(non_snake_case)]
+/// A getfd audit function which does nothing.  It is the default.
+pub const fn GETFD_NULL_AUDIT(_: u64, _: u64, _: u64, _: Result<u64, FdTableError>) {}
 
-// BUG / TODO: Use this in some sane way...
-#[allow(dead_code)]
-/// Global maximum number of fds... (checks may not be implemented)
+/// Default maximum number of fds open across all cages.  Going over this
+/// returns ENFILE.  Change it at runtime with `set_total_fd_limit`.
 pub const TOTAL_FD_MAX: u64 = 4096;
 
 // replicating these constants here so this can compile on systems other than
 // Linux...  Copied from Rust's libc.
+/// copied from libc.  Flag for `close_virtualfd_range`.
+pub const CLOSE_RANGE_UNSHARE: u32 = 0x2;
+/// copied from libc.  Flag for `close_virtualfd_range`.
+pub const CLOSE_RANGE_CLOEXEC: u32 = 0x4;
 /// copied from libc
 pub const EPOLL_CTL_ADD: i32 = 1;
 /// copied from libc
@@ -1,17 +1,31 @@
-//  DashMap<u64,[Option<FDTableEntry>;FD_PER_PROCESSS_MAX]>  Space is ~24KB 
+//! The `DashMapArrayGlobal` fdtable implementation: a `DashMap` of cages, each holding a fixed size array of entries.
+//!
+//! Use the functions re-exported at the top of the crate instead of this
+//! module directly, unless you need this specific algorithm.
+
+//  DashMap<u64,[Option<FDTableEntry<P>>;FD_PER_PROCESSS_MAX]>  Space is ~24KB
 //  per cage w/ 1024 fds?!?
 //      Static DashMap.  Let's see if having the FDTableEntries be a static
 //      array is any faster...
 
 use crate::threei;
 
+use crate::fdtableerror::FdTableError;
+
+use crate::fdtablebackend::FdTableBackend;
+
+use crate::fdbitmap::FdBitmap;
+
 use dashmap::DashMap;
 
 use lazy_static::lazy_static;
 
 use std::collections::HashMap;
 
-use std::sync::Mutex;
+use std::ops::{Deref, DerefMut};
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 // This uses a Dashmap (for cages) with an array of FDTableEntry items.
 
@@ -25,976 +39,3286 @@ pub const ALGONAME: &str = "DashMapArrayGlobal";
 // It's fairly easy to check the fd count on a per-process basis (I just check
 // when I would add a new fd).
 //
-// TODO: I will ignore the total limit for now.  I would ideally do this on
-// every creation, close, fork, etc. but it's a PITA to track this.
+// The total limit is tracked with a counter of all open fds.  Anything which
+// adds fds (allocation, dup, fork) reserves them from the counter first, and
+// _decrement_fdcount gives them back when an fd goes away.
 
 // We will raise a panic anywhere we receive an unknown cageid.  This frankly
 // should not be possible and indicates some sort of internal error in our
-// code.  However, other issues, such as an invalid file descriptor when a 
+// code.  However, other issues, such as an invalid file descriptor when a
 // cage makes a call, will be handled by returning the appropriate errno.
 
 // In order to store this information, I'm going to use a DashMap which
-// has keys of (cageid:u64) and values that are an array of FD_PER_PROCESS_MAX
-// Option<FDTableEntry> items. 
+// has keys of (cageid:u64) and values that are an FdRow: an array of
+// FD_PER_PROCESS_MAX Option<FDTableEntry<P>> items, plus a bitmap of which
+// ones are used (so I can find the lowest unused fd quickly).
 //
+// All of the state lives in an FdTables struct, so a caller (like a grate
+// that is nested inside of another grate) can have several independent fd
+// namespaces.  The free functions in this module operate on a global
+// instance, which is what most callers want.
 //
+// Forking copied the parent's whole row and added a reference for every fd in
+// it, which was most of the cost of a fork (and wasted, if the child just
+// calls exec).  Now a row is behind an Arc, and the child of a fork uses the
+// parent's row until one of them changes it.  Then that cage gets its own copy
+// (see _row_mut).  So that a fork doesn't have to touch the fd counts, a
+// reference is to a (row, virtualfd), and counts once for every cage which
+// uses that row.
+
+/// A set of fdtables, along with the close handlers and epoll state that go
+/// with them.
+///
+/// Every instance is completely independent of every other instance.  The
+/// free functions in this module (and at the top of the crate) use a global
+/// instance.
+#[derive(Debug)]
+pub struct FdTables<P = u64> {
+    fdtable: DashMap<u64, Arc<FdRow<P>>>,
+    // This is needed for close and similar functionality.  I need track the
+    // number of times a (fdkind,underfd) is open.  Note that this is across
+    // cages in order to enable a library to have  situations where two cages
+    // have the same fd open.  The (fdkind,underfd) tuple is the key and the
+    // value is which rows it appears in (along with the open file description
+    // those fds share).  With rowusers, this is also the index for
+    // get_fd_references.  If the count reaches 0, the entry is removed.
+    fdcount: DashMap<(u32,u64), FileDescription>,
+    // The cages which use each row (by its rowid).  This is more than one cage
+    // after a fork, until the cages change their tables.  A row is only in
+    // here while some cage uses it.
+    rowusers: DashMap<u64, Vec<u64>>,
+    // Where the next row's id comes from.
+    nextrowid: AtomicU64,
+    // The (soft, hard) limits on the number of fds for each cage, like
+    // RLIMIT_NOFILE.  A cage without an entry has the default limits
+    // (FD_PER_PROCESS_MAX for both), so this only has cages which called
+    // set_fd_limit (or inherited a limit from one that did).
+    fdlimits: DashMap<u64, (u64,u64)>,
+    // Cages whose table isn't stored under their own cageid, mapped to the
+    // key it is stored under.  That is a cage which shares another cage's
+    // table (see share_fdtable_for_cage), or one whose cageid was still the
+    // key of a table other cages use when it got a table.  Tables never move
+    // to another key, so a cage which already looked up its key can't find
+    // its table gone.  A key whose cage is gone (or which never was a cage,
+    // see _new_table_key) is in here as None, so that cageid is unknown.  The
+    // references to a table's fds (in rowusers) and its limits are under its
+    // key too.
+    sharedtables: DashMap<u64,Option<u64>>,
+    // Where the next key which isn't some cage's own cageid comes from.
+    // These count down from the top, away from the cageids.
+    nexttablekey: AtomicU64,
+    // This holds the user registered handlers they want to have called when
+    // a close occurs.  I did this rather than return messy data structures
+    // from the close, exec, and exit handlers because it seemed cleaner...
+    // Each fdkind's list can be added to (see add_close_handlers), and is
+    // copied on write so a close can run it without holding the lock.
+    closehandlertable: Mutex<HashMap<u32,CloseHandlerList<P>>>,
+    nexthandlertoken: AtomicU64,
+    // Run for an fdkind with no handlers of its own (see
+    // register_default_close_handlers), and whether to panic if there
+    // aren't any of those either.
+    defaultclosehandlers: Mutex<Option<CloseHandlerList<P>>>,
+    strictclosehandlers: AtomicBool,
+    // The handlers to call when a reference is added, by fdkind.  The whole
+    // table is copied on write, so an operation can take it (cheaply) before
+    // it starts and call the handlers once it has dropped its locks.
+    openhandlertable: Mutex<OpenHandlerTable<P>>,
+    epolltable: Mutex<EPollTable>,
+    // What to do if a call names a cage I don't know about.  This is only
+    // looked at when that happens, so it's not on the fast path.
+    unknowncagepolicy: Mutex<UnknownCagePolicy>,
+    // Who may copy another cage's fds with getfd_virtualfd, and what is told
+    // about each try.  Only looked at by getfd_virtualfd.
+    getfdhooks: Mutex<GetfdHooks>,
+    // The number of fds open in all cages, and the most that may be open at
+    // once (like file-nr and file-max in /proc/sys/fs).  These are atomics so
+    // they don't need another lock.
+    totalfds: AtomicU64,
+    totalfdlimit: AtomicU64,
+}
 
 // This lets me initialize the code as a global.
 lazy_static! {
 
     #[derive(Debug)]
-    static ref FDTABLE: DashMap<u64, [Option<FDTableEntry>;FD_PER_PROCESS_MAX as usize]> = {
-        let m = DashMap::new();
+    static ref GLOBALFDTABLES: FdTables = {
+        let m = FdTables::new();
         // Insert a cage so that I have something to fork / test later, if need
         // be. Otherwise, I'm not sure how I get this started. I think this
         // should be invalid from a 3i standpoint, etc. Could this mask an
         // error in the future?
-        m.insert(threei::TESTING_CAGEID,[Option::None;FD_PER_PROCESS_MAX as usize]);
+        m.fdtable.insert(threei::TESTING_CAGEID,m._new_row(threei::TESTING_CAGEID));
         m
     };
 }
 
-lazy_static! {
-    // This is needed for close and similar functionality.  I need track the
-    // number of times a (fdkind,underfd) is open.  Note that this is across 
-    // cages in order to enable a library to have  situations where two cages 
-    // have the same fd open.  The (fdkind,underfd) tuple is the key and the 
-    // number of times it appears is the value.  If it reaches 0, the entry 
-    // is removed.
-    #[derive(Debug)]
-    static ref FDCOUNT: DashMap<(u32,u64), u64> = {
-        DashMap::new()
-    };
-
+// A cage's row of the fdtable.  Entries are only added or removed with
+// insert / take, which keep the bitmap of used fds up to date.  (This is also
+// why there is Index but no IndexMut.)  It isn't Clone, since every copy
+// needs its own rowid.
+#[derive(Debug)]
+struct FdRow<P> {
+    // Which row this is, in rowusers and the references in fdcount.
+    rowid: u64,
+    entries: [Option<FDTableEntry<P>>;FD_PER_PROCESS_MAX as usize],
+    used: FdBitmap,
 }
 
-#[doc = include_str!("../docs/init_empty_cage.md")]
-pub fn init_empty_cage(cageid: u64) {
-
-    assert!(!FDTABLE.contains_key(&cageid),"Known cageid in fdtable access");
+impl<P> FdRow<P> {
+    // An empty row.  I can't use [None;N] since the entries aren't Copy if
+    // the payload isn't...
+    fn new(rowid:u64) -> Self {
+        FdRow {
+            rowid,
+            entries: std::array::from_fn(|_| None),
+            used: FdBitmap::new(),
+        }
+    }
 
-    FDTABLE.insert(cageid,[Option::None;FD_PER_PROCESS_MAX as usize]);
-}
+    // Returns the old entry, if there was one.
+    fn insert(&mut self, virtualfd:u64, entry:FDTableEntry<P>) -> Option<FDTableEntry<P>> {
+        self.used.set(virtualfd);
+        self.entries[virtualfd as usize].replace(entry)
+    }
 
-#[doc = include_str!("../docs/translate_virtual_fd.md")]
-pub fn translate_virtual_fd(cageid: u64, virtualfd: u64) -> Result<FDTableEntry, threei::RetVal> {
+    fn take(&mut self, virtualfd:u64) -> Option<FDTableEntry<P>> {
+        self.used.clear(virtualfd);
+        self.entries[virtualfd as usize].take()
+    }
 
-    // They should not be able to pass a new cage I don't know.  I should
-    // always have a table for each cage because each new cage is added at fork
-    // time
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+    // Unlike indexing, this is None (instead of a panic) for an fd past the
+    // end of the row.
+    fn get(&self, virtualfd:u64) -> Option<&FDTableEntry<P>> {
+        self.entries.get(usize::try_from(virtualfd).ok()?)?.as_ref()
+    }
 
-    // Below condition checks if the virtualfd is out of bounds and if yes it throws an error
-    // Note that this assumes that all virtualfd numbers returned < FD_PER_PROCESS_MAX 
-    if virtualfd >= FD_PER_PROCESS_MAX {
-        return Err(threei::Errno::EBADFD as u64);
+    // For changing the fields of an entry.  This can't add or remove one.
+    fn get_mut(&mut self, virtualfd:u64) -> Option<&mut FDTableEntry<P>> {
+        self.entries.get_mut(usize::try_from(virtualfd).ok()?)?.as_mut()
     }
 
-    return match FDTABLE.get(&cageid).unwrap()[virtualfd as usize] {
-        Some(tableentry) => Ok(tableentry),
-        None => Err(threei::Errno::EBADFD as u64),
-    };
-}
+    // How many entries there are.
+    fn count(&self) -> u64 {
+        self.used.count()
+    }
 
+    // The lowest unused fd which is at least minfd, if it's below limit.
+    fn lowest_free_between(&self, minfd:u64, limit:u64) -> Option<u64> {
+        self.used.lowest_free_between(minfd, limit)
+    }
 
-// This is fairly slow if I just iterate sequentially through numbers.
-// However there are not that many to choose from.  I could pop from a list
-// or a set as well...  Likely the best solution is to keep a count of the
-// largest fd handed out and to just use this until you wrap.  This will be
-// super fast for a normal cage and will be correct in the weird case.
-// Right now, I'll just implement the slow path and will speed this up
-// later, if needed.
-#[doc = include_str!("../docs/get_unused_virtual_fd.md")]
-pub fn get_unused_virtual_fd(
-    cageid: u64,
-    fdkind: u32,
-    underfd: u64,
-    should_cloexec: bool,
-    perfdinfo: u64,
-) -> Result<u64, threei::RetVal> {
-
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
-    // Set up the entry so it has the right info...
-    // Note, a HashMap stores its data on the heap!  No need to box it...
-    // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
-    let myentry = FDTableEntry {
-        fdkind,
-        underfd,
-        should_cloexec,
-        perfdinfo,
-    };
+    fn iter(&self) -> std::slice::Iter<'_, Option<FDTableEntry<P>>> {
+        self.entries.iter()
+    }
+}
 
-    let mut myfdrow = FDTABLE.get_mut(&cageid).unwrap();
+impl<P> std::ops::Index<usize> for FdRow<P> {
+    type Output = Option<FDTableEntry<P>>;
 
-    // Check the fds in order.
-    for fdcandidate in 0..FD_PER_PROCESS_MAX {
-        // FIXME: This is likely very slow.  Should do something smarter...
-        if myfdrow[fdcandidate as usize].is_none() {
-            // I just checked.  Should not be there...
-            myfdrow[fdcandidate as usize] = Some(myentry);
-            _increment_fdcount(myentry);
-            return Ok(fdcandidate);
-        }
+    fn index(&self, virtualfd:usize) -> &Self::Output {
+        &self.entries[virtualfd]
     }
-
-    // I must have checked all fds and failed to find one open.  Fail!
-    Err(threei::Errno::EMFILE as u64)
 }
 
-// This is used for things like dup2, which need a specific fd...
-// If the requested_virtualfd is used, I close it...
-#[doc = include_str!("../docs/get_specific_virtual_fd.md")]
-pub fn get_specific_virtual_fd(
-    cageid: u64,
-    requested_virtualfd: u64,
-    fdkind: u32,
-    underfd: u64,
-    should_cloexec: bool,
-    perfdinfo: u64,
-) -> Result<(), threei::RetVal> {
-
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
-
-    // If you ask for a FD number that is too large, I'm going to reject it.
-    // Note that, I need to use the FD_PER_PROCESS_MAX setting because this
-    // is also how I'm tracking how many values you have open.  If this
-    // changed, then these constants could be decoupled...
-    if requested_virtualfd > FD_PER_PROCESS_MAX {
-        return Err(threei::Errno::EBADF as u64);
-    }
-
-    // Set up the entry so it has the right info...
-    // Note, a HashMap stores its data on the heap!  No need to box it...
-    // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
-    let myentry = FDTableEntry {
-        fdkind,
-        underfd,
-        should_cloexec,
-        perfdinfo,
-    };
+// A cage's row, locked so it can be changed.  Only _row_mut makes these, and
+// it makes sure no other cage uses the row first, so the Arc can always be
+// written through.
+struct RowGuard<'a, P>(dashmap::mapref::one::RefMut<'a, u64, Arc<FdRow<P>>>);
 
-    // This is before the FDTABLE action, so if I decrement the same fd, it
-    // calls the intermediate handler instead of the last one.
-    _increment_fdcount(myentry);
-    let myoptionentry = FDTABLE.get(&cageid).unwrap()[requested_virtualfd as usize];
-    // always add the new entry.  I'm doing this first, before I close
-    // the old one because I need to ensure I've cleaned up state correctly
-    // before calling the close handlers...
-    FDTABLE.get_mut(&cageid).unwrap()[requested_virtualfd as usize] = Some(myentry);
+impl<P> Deref for RowGuard<'_, P> {
+    type Target = FdRow<P>;
 
-    // Update the fdcount / close the old entry, if existed
-    if let Some(entry) = myoptionentry {
-        _decrement_fdcount(entry);
+    fn deref(&self) -> &FdRow<P> {
+        &self.0
     }
-
-    Ok(())
 }
 
-// We're just setting a flag here, so this should be pretty straightforward.
-#[doc = include_str!("../docs/set_cloexec.md")]
-pub fn set_cloexec(cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), threei::RetVal> {
+impl<P> DerefMut for RowGuard<'_, P> {
+    fn deref_mut(&mut self) -> &mut FdRow<P> {
+        Arc::get_mut(&mut self.0).unwrap()
+    }
+}
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+// The state shared by every fd that refers to one open file description.
+// Fds share a description exactly when they have the same (fdkind,underfd),
+// which is what dup and fork copy.  It is created by the first
+// fd that refers to it and dropped when the last one is closed.
+#[derive(Clone, Debug, Default)]
+struct FileDescription {
+    // Where the fds that refer to this are: the virtualfds in each row (by
+    // rowid).  Each one counts once for every cage which uses the row (see
+    // _reference_count), so this is grouped by row to count quickly.  The
+    // virtualfds aren't a set since replacing an fd with the same
+    // (fdkind,underfd) adds the new reference before removing the old one,
+    // and they are at the same place.
+    references: HashMap<u64,Vec<u64>>,
+    // Copies of fds which are being passed to another cage (see
+    // transfer_virtualfds).  They aren't in any row yet, but still count.
+    inflight: u64,
+    // Status flags, like O_NONBLOCK and O_APPEND.  See set_status_flags.
+    statusflags: i32,
+    // The file offset.  See set_offset.
+    offset: u64,
+}
 
-    // return EBADFD, if the fd is missing...
-    if FDTABLE.get(&cageid).unwrap()[virtualfd as usize].is_none() {
-        return Err(threei::Errno::EBADFD as u64);
+impl FileDescription {
+    // Removes one of the references at this place.  The order of the
+    // references doesn't matter, so swap_remove is fine.
+    fn remove_reference(&mut self, rowid:u64, virtualfd:u64) {
+        let virtualfds = self.references.get_mut(&rowid).unwrap();
+        let index = virtualfds.iter().position(|&fd| fd == virtualfd).unwrap();
+        virtualfds.swap_remove(index);
+        if virtualfds.is_empty() {
+            self.references.remove(&rowid);
+        }
     }
-    // Set the is_cloexec flag
-    FDTABLE.get_mut(&cageid).unwrap()[virtualfd as usize].as_mut().unwrap().should_cloexec = is_cloexec;
-    Ok(())
 }
 
-// We're setting an opaque value here. This should be pretty straightforward.
-#[doc = include_str!("../docs/set_perfdinfo.md")]
-pub fn set_perfdinfo(
-    cageid: u64,
-    virtualfd: u64,
-    perfdinfo: u64,
-) -> Result<(), threei::RetVal> {
+// This is generic, so an instance with any payload type can be made with
+// FdTables::<MyPayload>::default()
+impl<P: Clone + Send + Sync> Default for FdTables<P> {
+    fn default() -> Self {
+        Self {
+            fdtable: DashMap::new(),
+            fdcount: DashMap::new(),
+            rowusers: DashMap::new(),
+            nextrowid: AtomicU64::new(0),
+            fdlimits: DashMap::new(),
+            sharedtables: DashMap::new(),
+            nexttablekey: AtomicU64::new(u64::MAX),
+            closehandlertable: Mutex::new(HashMap::new()),
+            nexthandlertoken: AtomicU64::new(0),
+            defaultclosehandlers: Mutex::new(None),
+            strictclosehandlers: AtomicBool::new(false),
+            openhandlertable: Mutex::new(Arc::new(HashMap::new())),
+            epolltable: Mutex::new(EPollTable {
+                highestneverusedentry:0,
+                thisepolltable:HashMap::new(),
+            }),
+            unknowncagepolicy: Mutex::new(UnknownCagePolicy::Panic),
+            getfdhooks: Mutex::new(GetfdHooks::default()),
+            totalfds: AtomicU64::new(0),
+            totalfdlimit: AtomicU64::new(TOTAL_FD_MAX),
+        }
+    }
+}
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+impl FdTables {
 
-    // return EBADFD, if the fd is missing...
-    if FDTABLE.get(&cageid).unwrap()[virtualfd as usize].is_none() {
-        return Err(threei::Errno::EBADFD as u64);
+    /// Creates a new set of fdtables, which does not contain any cages.  The
+    /// per-fd payload is a `u64`.  Use `FdTables::<P>::default()` for some
+    /// other payload type `P`.
+    #[must_use] // must use the return value if you call it.
+    pub fn new() -> Self {
+        Self::default()
     }
-
-    // Set optionalinfo or return EBADFD, if that's missing...
-    FDTABLE.get_mut(&cageid).unwrap()[virtualfd as usize].as_mut().unwrap().perfdinfo = perfdinfo;
-    Ok(())
 }
 
-// Helper function used for fork...  Copies an fdtable for another process
-#[doc = include_str!("../docs/copy_fdtable_for_cage.md")]
-pub fn copy_fdtable_for_cage(srccageid: u64, newcageid: u64) -> Result<(), threei::Errno> {
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    assert!(FDTABLE.contains_key(&srccageid),"Unknown cageid in fdtable access");
-    assert!(!FDTABLE.contains_key(&newcageid),"Known cageid in fdtable access");
+    /// See [`init_empty_cage`].
+    ///
+    /// # Panics
+    ///   See [`init_empty_cage`].
+    pub fn init_empty_cage(&self, cageid: u64) {
 
-    // Insert a copy and ensure it didn't exist...
-    // I've checked this should be a copy, not a ref to the same thing.  
-    let hmcopy = *FDTABLE.get(&srccageid).unwrap();
+        assert!(!self._is_known_cage(cageid),"Known cageid in fdtable access");
 
-    // Increment copied items
-    for entry in FDTABLE.get(&srccageid).unwrap().iter() {
-        if entry.is_some() {
-            _increment_fdcount(entry.unwrap());
-        }
+        let tablecageid = self._new_table_key(cageid);
+        self.fdtable.insert(tablecageid,self._new_row(tablecageid));
+        self._set_table_key(cageid, tablecageid);
     }
 
-    assert!(FDTABLE.insert(newcageid, hmcopy).is_none());
-    
-    // I'm not going to bother to check the number of fds used overall yet...
-    //    Err(threei::Errno::EMFILE as u64),
-    Ok(())
-}
+    /// See [`translate_virtual_fd`].
+    ///
+    /// # Panics
+    ///   See [`translate_virtual_fd`].
+    ///
+    /// # Errors
+    ///   See [`translate_virtual_fd`].
+    pub fn translate_virtual_fd(&self, cageid: u64, virtualfd: u64) -> Result<FDTableEntry<P>, FdTableError> {
+
+        // They should not be able to pass a new cage I don't know.  I should
+        // always have a table for each cage because each new cage is added at fork
+        // time
+        let cageid = self._check_cageid(cageid)?;
+
+        // Below condition checks if the virtualfd is out of bounds and if yes it throws an error
+        // Note that this assumes that all virtualfd numbers returned < FD_PER_PROCESS_MAX
+        if virtualfd >= FD_PER_PROCESS_MAX {
+            return Err(FdTableError::FdNotOpen);
+        }
 
-// This is mostly used in handling exit, etc.  Returns the HashMap
-// for the cage.
-#[doc = include_str!("../docs/remove_cage_from_fdtable.md")]
-pub fn remove_cage_from_fdtable(cageid: u64) {
+        return match &self.fdtable.get(&cageid).unwrap()[virtualfd as usize] {
+            Some(tableentry) => Ok(tableentry.clone()),
+            None => Err(FdTableError::FdNotOpen),
+        };
+    }
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
 
+    // Iterating sequentially through the fds was slow when a cage had a lot
+    // open.  Instead, the row has a bitmap of the used fds, which finds the
+    // lowest unused one in constant time (and so keeps the POSIX semantics).
+    /// See [`get_unused_virtual_fd`].
+    ///
+    /// # Panics
+    ///   See [`get_unused_virtual_fd`].
+    ///
+    /// # Errors
+    ///   See [`get_unused_virtual_fd`].
+    pub fn get_unused_virtual_fd(
+        &self,
+        cageid: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<u64, FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+        // Set up the entry so it has the right info...
+        // Note, a HashMap stores its data on the heap!  No need to box it...
+        // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
+        let myentry = FDTableEntry {
+            fdkind,
+            underfd,
+            should_cloexec,
+            perfdinfo,
+        };
 
-    // remove the item first and then we clean up and call their close
-    // handlers.
-    let myfdrow = FDTABLE.remove(&cageid).unwrap().1;
+        // Get this before I lock the row, since it's in a different map...
+        let softlimit = self._soft_fd_limit(cageid);
 
-    // Take only the Some items in here (clippy suggested)
-    for entry in myfdrow.into_iter().flatten() {
-        _decrement_fdcount(entry);
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+        let virtualfd = self._insert_lowest_unused_fd(&mut self._row_mut(cageid), 0, softlimit, myentry)?;
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        Ok(virtualfd)
     }
 
-}
+    // This is for F_DUPFD, which wants the lowest unused fd that is at least
+    // minfd.  The only difference from above is that a minfd past the limit
+    // is an error of its own...
+    /// See [`get_unused_virtual_fd_from`].
+    ///
+    /// # Panics
+    ///   See [`get_unused_virtual_fd_from`].
+    ///
+    /// # Errors
+    ///   See [`get_unused_virtual_fd_from`].
+    pub fn get_unused_virtual_fd_from(
+        &self,
+        cageid: u64,
+        minfd: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<u64, FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        let softlimit = self._soft_fd_limit(cageid);
+
+        if minfd >= softlimit {
+            return Err(FdTableError::MinFdTooLarge);
+        }
 
-// This removes all fds with the should_cloexec flag set.  They are returned
-// in a new hashmap...
-#[doc = include_str!("../docs/empty_fds_for_exec.md")]
-pub fn empty_fds_for_exec(cageid: u64) {
+        let myentry = FDTableEntry {
+            fdkind,
+            underfd,
+            should_cloexec,
+            perfdinfo,
+        };
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+        let virtualfd = self._insert_lowest_unused_fd(&mut self._row_mut(cageid), minfd, softlimit, myentry)?;
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        Ok(virtualfd)
+    }
 
-    let mut myfdrow = FDTABLE.get_mut(&cageid).unwrap();
-    // I need to call all the close handlers at the end.  So I need to 
-    // get vector of them to do the operation on...
-    let mut closevec = Vec::new();
+    // Puts myentry in the lowest unused fd in [minfd, softlimit) of this
+    // cage's row and returns that fd.
+    fn _insert_lowest_unused_fd(&self, myfdrow:&mut FdRow<P>, minfd:u64, softlimit:u64, myentry:FDTableEntry<P>) -> Result<u64, FdTableError> {
+        // The bitmap gives me the lowest unused fd.  Only ones below the soft
+        // limit can be handed out.
+        let Some(fdcandidate) = myfdrow.lowest_free_between(minfd, softlimit) else {
+            return Err(FdTableError::TableFull);
+        };
 
-    for item in 0..FD_PER_PROCESS_MAX as usize {
-        if myfdrow[item].is_some() && myfdrow[item].unwrap().should_cloexec {
-            // handle this in a moment...
-            closevec.push(myfdrow[item].unwrap());
+        self._reserve_total_fds(1)?;
+        self._increment_fdcount(myfdrow.rowid, fdcandidate, &myentry);
+        myfdrow.insert(fdcandidate, myentry);
+        Ok(fdcandidate)
+    }
 
-            // Always zero out the row before calling their handler
-            myfdrow[item] = None;
-        }
+    // The dup calls copy the entry while holding the lock, so another thread
+    // can't close (or replace) the old fd partway through.  The copy never
+    // inherits close-on-exec, unless dup3 asks for it.
+    /// See [`dup_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`dup_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`dup_virtualfd`].
+    pub fn dup_virtualfd(&self, cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
+
+        // Get this before I lock the row, since it's in a different map...
+        let softlimit = self._soft_fd_limit(cageid);
+
+        let mut opened = self._pending_opens();
+        let mut myfdrow = self._row_mut(cageid);
+
+        let Some(oldentry) = myfdrow.get(oldvirtualfd) else {
+            return Err(FdTableError::BadFd);
+        };
+        let mut myentry = oldentry.clone();
+        myentry.should_cloexec = false;
+
+        opened.add(&myentry);
+        let virtualfd = self._insert_lowest_unused_fd(&mut myfdrow, 0, softlimit, myentry)?;
+        drop(myfdrow);
+        self._call_open_handlers(opened, OpenReason::Dup);
+        Ok(virtualfd)
     }
 
-    // Need to drop the lock, before calling the handlers.
-    drop(myfdrow);
+    /// See [`dup2_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`dup2_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`dup2_virtualfd`].
+    pub fn dup2_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64) -> Result<u64, FdTableError> {
+        self._dup_onto(cageid, oldvirtualfd, newvirtualfd, false)
+    }
 
-    // Now, we can call the close handlers!
-    for entry in closevec {
-        _decrement_fdcount(entry);
+    /// See [`dup3_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`dup3_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`dup3_virtualfd`].
+    pub fn dup3_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64, should_cloexec: bool) -> Result<u64, FdTableError> {
+        // Unlike dup2, this is an error instead of doing nothing.
+        if oldvirtualfd == newvirtualfd {
+            return Err(FdTableError::DupToSameFd);
+        }
+        self._dup_onto(cageid, oldvirtualfd, newvirtualfd, should_cloexec)
     }
 
-}
+    // Copies oldvirtualfd's entry to newvirtualfd, closing whatever was there.
+    // This is like get_specific_virtual_fd, except the entry comes from the
+    // table.
+    fn _dup_onto(&self, cageid:u64, oldvirtualfd:u64, newvirtualfd:u64, should_cloexec:bool) -> Result<u64, FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
 
-// Returns the HashMap returns a copy of the fdtable for a cage.  Useful 
-// helper function for a caller that needs to examine the table.  Likely could
-// be more efficient by letting the caller borrow this...
-#[doc = include_str!("../docs/return_fdtable_copy.md")]
-#[must_use] // must use the return value if you call it.
-pub fn return_fdtable_copy(cageid: u64) -> HashMap<u64, FDTableEntry> {
+        // Get this before I lock the row, since it's in a different map...
+        let softlimit = self._soft_fd_limit(cageid);
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+        let mut opened = self._pending_opens();
+        let mut myfdrow = self._row_mut(cageid);
 
-    let mut myhashmap = HashMap::new();
+        let Some(oldentry) = myfdrow.get(oldvirtualfd) else {
+            return Err(FdTableError::BadFd);
+        };
 
-    let myfdrow = FDTABLE.get(&cageid).unwrap();
-    for item in 0..FD_PER_PROCESS_MAX as usize {
-        if myfdrow[item].is_some() {
-            myhashmap.insert(item as u64,myfdrow[item].unwrap());
+        // dup2(fd, fd) does nothing if fd is open.
+        if oldvirtualfd == newvirtualfd {
+            return Ok(newvirtualfd);
         }
-    }
-    myhashmap
-}
 
+        if newvirtualfd >= softlimit {
+            return Err(FdTableError::FdOutOfRange);
+        }
 
+        let mut myentry = oldentry.clone();
+        myentry.should_cloexec = should_cloexec;
 
-/******************* CLOSE SPECIFIC FUNCTIONALITY *******************/
+        // Replacing an open fd doesn't change the total number open, but
+        // _decrement_fdcount takes one off for the old entry below...
+        if myfdrow[newvirtualfd as usize].is_some() {
+            self.totalfds.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self._reserve_total_fds(1)?;
+        }
 
-// These indicate what functions should be called upon a virtualfd closing.
-// The handler which is called depends on number of (fdkind,underfd) tuples
-// that are used across *all instances managed by this library including in
-// other cages*.
-struct CloseHandlers {
-    // Called when close is called, but at least one (fdkind,underfd)
-    // reference still remains.  Called with (fdkind,underfd,count)
-    intermediate: fn(FDTableEntry,u64),
-    // Called when close is called, but at least one (fdkind,underfd)
-    // reference still remains. Called with (fdkind,underfd,0)
-    last: fn(FDTableEntry,u64),
-}
+        // Before the insert, so if newvirtualfd already had the same
+        // (fdkind,underfd), closing it calls the intermediate handler.
+        let rowid = myfdrow.rowid;
+        self._increment_fdcount(rowid, newvirtualfd, &myentry);
+        opened.add(&myentry);
+        let myoptionentry = myfdrow.insert(newvirtualfd, myentry);
+        drop(myfdrow);
+
+        self._call_open_handlers(opened, OpenReason::Dup);
+        // Close whatever was at newvirtualfd (after dropping the lock, since
+        // the close handlers may call back into this library).
+        if let Some(entry) = myoptionentry {
+            self._report_unhandled_closes(self._decrement_fdcount(rowid, newvirtualfd, entry));
+        }
 
+        Ok(newvirtualfd)
+    }
 
-lazy_static! {
-    // This holds the user registered handlers they want to have called when
-    // a close occurs.  I did this rather than return messy data structures
-    // from the close, exec, and exit handlers because it seemed cleaner...
-    #[derive(Debug)]
-    static ref CLOSEHANDLERTABLE: Mutex<HashMap<u32,CloseHandlers>> = {
-        Mutex::new(HashMap::new())
-    };
-}
+    // This is used for things like dup2, which need a specific fd...
+    // If the requested_virtualfd is used, I close it...
+    /// See [`get_specific_virtual_fd`].
+    ///
+    /// # Panics
+    ///   See [`get_specific_virtual_fd`].
+    ///
+    /// # Errors
+    ///   See [`get_specific_virtual_fd`].
+    pub fn get_specific_virtual_fd(
+        &self,
+        cageid: u64,
+        requested_virtualfd: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<(), FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        // If you ask for a FD number that is too large, I'm going to reject it.
+        // Like dup2, this is anything at or above the cage's soft limit.  (The
+        // soft limit is never above FD_PER_PROCESS_MAX, which is also the
+        // size of the table.)
+        if requested_virtualfd >= self._soft_fd_limit(cageid) {
+            return Err(FdTableError::FdOutOfRange);
+        }
 
+        // Set up the entry so it has the right info...
+        // Note, a HashMap stores its data on the heap!  No need to box it...
+        // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
+        let myentry = FDTableEntry {
+            fdkind,
+            underfd,
+            should_cloexec,
+            perfdinfo,
+        };
 
-#[doc = include_str!("../docs/close_virtualfd.md")]
-pub fn close_virtualfd(cageid:u64, virtfd:u64) -> Result<(),threei::RetVal> {
+        let mut opened = self._pending_opens();
+        let mut myfdrow = self._row_mut(cageid);
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+        // Replacing an open fd doesn't change the total number open, but
+        // _decrement_fdcount takes one off for the old entry below...
+        if myfdrow[requested_virtualfd as usize].is_some() {
+            self.totalfds.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self._reserve_total_fds(1)?;
+        }
 
-    // derefing this so I don't hold a lock and deadlock close handlers
-    let mut myfdrow = *FDTABLE.get_mut(&cageid).unwrap();
+        // This is before the FDTABLE action, so if I decrement the same fd, it
+        // calls the intermediate handler instead of the last one.
+        let rowid = myfdrow.rowid;
+        self._increment_fdcount(rowid, requested_virtualfd, &myentry);
+        opened.add(&myentry);
+        // always add the new entry.  I'm doing this first, before I close
+        // the old one because I need to ensure I've cleaned up state correctly
+        // before calling the close handlers...
+        let myoptionentry = myfdrow.insert(requested_virtualfd, myentry);
+        drop(myfdrow);
+
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        // Update the fdcount / close the old entry, if existed
+        if let Some(entry) = myoptionentry {
+            self._report_unhandled_closes(self._decrement_fdcount(rowid, requested_virtualfd, entry));
+        }
 
+        Ok(())
+    }
 
-    if myfdrow[virtfd as usize].is_some() {
-        let entry = myfdrow[virtfd as usize];
+    // The copies are made, and take references of their own, while I hold
+    // the sender's row.  So nothing the sender does after that can close them
+    // before they are in the receiver's row.  (I can't hold both rows, since
+    // they may be in the same shard of the DashMap.)
+    /// See [`transfer_virtualfds`].
+    ///
+    /// # Panics
+    ///   See [`transfer_virtualfds`].
+    ///
+    /// # Errors
+    ///   See [`transfer_virtualfds`].
+    pub fn transfer_virtualfds(&self, srccageid: u64, srcvirtualfds: &[u64], dstcageid: u64, should_cloexec: bool) -> Result<Vec<u64>, FdTableError> {
+        let srccageid = self._check_cageid(srccageid)?;
+        let dstcageid = self._check_cageid(dstcageid)?;
+
+        let mut opened = self._pending_opens();
+        let mut entries = Vec::with_capacity(srcvirtualfds.len());
+        {
+            let srcrow = self.fdtable.get(&srccageid).unwrap();
+            for &virtualfd in srcvirtualfds {
+                let Some(entry) = srcrow.get(virtualfd) else {
+                    return Err(FdTableError::BadFd);
+                };
+                let mut entry = entry.clone();
+                entry.should_cloexec = should_cloexec;
+                entries.push(entry);
+            }
+            for entry in &entries {
+                self.fdcount.get_mut(&(entry.fdkind, entry.underfd)).unwrap().inflight += 1;
+            }
+        }
 
-        // Zero out this entry before calling the close handler...
-        myfdrow[virtfd as usize] = None;
+        let result = self._insert_lowest_unused_fds(dstcageid, &entries);
+        if result.is_ok() {
+            for entry in &entries {
+                opened.add(entry);
+            }
+        }
 
-        // Re-insert the modified myfdrow since I've been modifying a copy
-        FDTABLE.insert(cageid, myfdrow.clone());
-        
-        // always _decrement last as it may call the user handler...
-        _decrement_fdcount(entry.unwrap());
-        return Ok(());
+        // If they were inserted, those fds hold them open now.  If not, and
+        // the sender closed them meanwhile, this is the last close.
+        let mut unhandled = Vec::new();
+        for entry in entries {
+            let newcount = {
+                let mut description = self.fdcount.get_mut(&(entry.fdkind, entry.underfd)).unwrap();
+                description.inflight -= 1;
+                self._reference_count(&description)
+            };
+            if newcount == 0 {
+                unhandled.extend(self._call_close_handler(entry, 0));
+            }
+        }
+        self._call_open_handlers(opened, OpenReason::Transfer);
+        self._report_unhandled_closes(unhandled);
+        result
     }
-    Err(threei::Errno::EBADFD as u64)
-}
 
+    // Puts the entries in the lowest unused fds of the cage's row, in order,
+    // and returns those fds.  If there aren't enough below the soft limit,
+    // none of them are put in.
+    fn _insert_lowest_unused_fds(&self, cageid:u64, entries:&[FDTableEntry<P>]) -> Result<Vec<u64>, FdTableError> {
+        // Get this before I lock the row, since it's in a different map...
+        let softlimit = self._soft_fd_limit(cageid);
 
-// Register a series of helpers to be called for close.  Can be called
-// multiple times to override the older helpers.
-#[doc = include_str!("../docs/register_close_handlers.md")]
-pub fn register_close_handlers(fdkind:u32, intermediate: fn(FDTableEntry,u64), last: fn(FDTableEntry,u64)) {
-    // Unlock the table and set the handlers...
-    let mut closehandlertable = CLOSEHANDLERTABLE.lock().unwrap();
-    let closehandler = CloseHandlers {
-        intermediate,
-        last,
-    };
-    // overwrite whatever is in there...
-    closehandlertable.insert(fdkind,closehandler);
-}
+        let mut myfdrow = self._row_mut(cageid);
 
+        let mut newvirtualfds = Vec::with_capacity(entries.len());
+        let mut minfd = 0;
+        for _ in entries {
+            let Some(virtualfd) = myfdrow.lowest_free_between(minfd, softlimit) else {
+                return Err(FdTableError::TableFull);
+            };
+            newvirtualfds.push(virtualfd);
+            minfd = virtualfd + 1;
+        }
+        self._reserve_total_fds(entries.len() as u64)?;
 
-// Helpers to track the count of times each (fdkind,underfd) is used
-#[doc(hidden)]
-fn _decrement_fdcount(entry:FDTableEntry) {
+        for (&virtualfd, entry) in newvirtualfds.iter().zip(entries) {
+            self._increment_fdcount(myfdrow.rowid, virtualfd, entry);
+            myfdrow.insert(virtualfd, entry.clone());
+        }
+        Ok(newvirtualfds)
+    }
 
-    let mytuple = (entry.fdkind, entry.underfd);
+    // The policy and audit functions are called without any of my locks
+    // held, so they can call back into the fdtables (to look up a cage's fds,
+    // for example).
+    /// See [`getfd_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`getfd_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`getfd_virtualfd`].
+    pub fn getfd_virtualfd(&self, requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+        let result = self._getfd_virtualfd(requestercageid, targetcageid, targetvirtualfd);
+        let audit = Arc::clone(&self.getfdhooks.lock().unwrap().audit);
+        audit(requestercageid, targetcageid, targetvirtualfd, result);
+        result
+    }
 
-    let newcount:u64 = FDCOUNT.get(&mytuple).unwrap().value() - 1;
+    fn _getfd_virtualfd(&self, requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+        self._check_cageid(requestercageid)?;
+        self._check_cageid(targetcageid)?;
 
-    let intermediatech;
-    let lastch;
-    // Doing this to release the lock so I can call it recursively...
-    let closehandlers = CLOSEHANDLERTABLE.lock().unwrap();
-    if let Some(closehandlerentry) = closehandlers.get(&entry.fdkind) {
-        intermediatech =  closehandlerentry.intermediate;
-        lastch = closehandlerentry.last;
-    }
-    else {
-        // TODO: If at any future point, I wanted to add a "default" handler
-        // for all fdkind values, I would add it here...
-        intermediatech = NULL_FUNC;
-        lastch = NULL_FUNC;
+        // Like pidfd_getfd, the policy is checked before the fd, so a cage
+        // which isn't allowed can't find out which fds are open.
+        let policy = Arc::clone(&self.getfdhooks.lock().unwrap().policy);
+        if !policy(requestercageid, targetcageid, targetvirtualfd) {
+            return Err(FdTableError::PermissionDenied);
+        }
+        // pidfd_getfd always sets close on exec on the new fd.
+        Ok(self.transfer_virtualfds(targetcageid, &[targetvirtualfd], requestercageid, true)?[0])
     }
-    // release the lock...
-    drop(closehandlers);
 
-    if newcount > 0 {
-        // Update before calling their close handler in case they do operations
-        // inside the close handler which create / close fds...
-        FDCOUNT.insert(mytuple,newcount);
-        (intermediatech)(entry,newcount);
+    // We're just setting a flag here, so this should be pretty straightforward.
+    /// See [`set_cloexec`].
+    ///
+    /// # Panics
+    ///   See [`set_cloexec`].
+    ///
+    /// # Errors
+    ///   See [`set_cloexec`].
+    pub fn set_cloexec(&self, cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), FdTableError> {
+        self.update_virtualfd_entry(cageid, virtualfd, |entry| entry.should_cloexec = is_cloexec)
     }
-    else{
-        // Remove before calling their close handler in case they do operations
-        // inside the close handler which create / close fds...
-        FDCOUNT.remove(&mytuple);
-        (lastch)(entry,0);
+
+    // We're setting an opaque value here. This should be pretty straightforward.
+    /// See [`set_perfdinfo`].
+    ///
+    /// # Panics
+    ///   See [`set_perfdinfo`].
+    ///
+    /// # Errors
+    ///   See [`set_perfdinfo`].
+    pub fn set_perfdinfo(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        perfdinfo: P,
+    ) -> Result<(), FdTableError> {
+        self.update_virtualfd_entry(cageid, virtualfd, |entry| entry.perfdinfo = perfdinfo)
     }
-}
 
-// Helpers to track the count of times each (fdkind,underfd) is used
-#[doc(hidden)]
-fn _increment_fdcount(entry:FDTableEntry) {
+    // func runs while I hold the row's lock, so nothing else can see or
+    // change the entry partway through.
+    /// See [`update_virtualfd_entry`].
+    ///
+    /// # Panics
+    ///   See [`update_virtualfd_entry`].
+    ///
+    /// # Errors
+    ///   See [`update_virtualfd_entry`].
+    pub fn update_virtualfd_entry<R>(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        func: impl FnOnce(&mut FDTableEntry<P>) -> R,
+    ) -> Result<R, FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        let mut myfdrow = self._row_mut(cageid);
+        let rowid = myfdrow.rowid;
+
+        // return EBADFD, if the fd is missing...
+        let Some(entry) = myfdrow.get_mut(virtualfd) else {
+            return Err(FdTableError::FdNotOpen);
+        };
 
-    let mytuple = (entry.fdkind, entry.underfd);
+        // I need the old entry for the close handler, if func changes what
+        // it refers to.
+        let oldentry = entry.clone();
+        let retval = func(entry);
+
+        if (entry.fdkind, entry.underfd) == (oldentry.fdkind, oldentry.underfd) {
+            return Ok(retval);
+        }
 
-    // Get a mutable reference to the entry so we can update it.
-    if let Some(mut count) = FDCOUNT.get_mut(&mytuple) {
-        *count += 1;
-    } else {
-        FDCOUNT.insert(mytuple, 1);
+        // This is like replacing the fd in get_specific_virtual_fd.  The new
+        // (fdkind,underfd) gets a reference and the old one loses one.  The
+        // number of open fds doesn't change, but _decrement_fdcount takes one
+        // off for the old entry below...
+        self.totalfds.fetch_add(1, Ordering::Relaxed);
+        self._increment_fdcount(rowid, virtualfd, entry);
+        // Only taken here, since most updates (like set_cloexec) don't add a
+        // reference.
+        let mut opened = self._pending_opens();
+        opened.add(entry);
+        drop(myfdrow);
+
+        // After dropping the lock, since the handlers may call back into
+        // this library.
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        self._report_unhandled_closes(self._decrement_fdcount(rowid, virtualfd, oldentry));
+
+        Ok(retval)
     }
-}
 
+    /// See [`compare_and_swap_virtualfd_entry`].
+    ///
+    /// # Panics
+    ///   See [`compare_and_swap_virtualfd_entry`].
+    ///
+    /// # Errors
+    ///   See [`compare_and_swap_virtualfd_entry`].
+    pub fn compare_and_swap_virtualfd_entry(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        expected: &FDTableEntry<P>,
+        new: FDTableEntry<P>,
+    ) -> Result<bool, FdTableError>
+    where
+        P: PartialEq,
+    {
+        self.update_virtualfd_entry(cageid, virtualfd, |entry| {
+            if entry != expected {
+                return false;
+            }
+            *entry = new;
+            true
+        })
+    }
 
+    /// See [`get_status_flags`].
+    ///
+    /// # Panics
+    ///   See [`get_status_flags`].
+    ///
+    /// # Errors
+    ///   See [`get_status_flags`].
+    pub fn get_status_flags(&self, cageid: u64, virtualfd: u64) -> Result<i32, FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.statusflags)
+    }
 
-/***************   Code for handling select() ****************/
+    /// See [`set_status_flags`].
+    ///
+    /// # Panics
+    ///   See [`set_status_flags`].
+    ///
+    /// # Errors
+    ///   See [`set_status_flags`].
+    pub fn set_status_flags(&self, cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.statusflags = statusflags)
+    }
 
-use libc::fd_set;
-use std::collections::HashSet;
-use std::cmp;
-use std::mem;
+    /// See [`get_offset`].
+    ///
+    /// # Panics
+    ///   See [`get_offset`].
+    ///
+    /// # Errors
+    ///   See [`get_offset`].
+    pub fn get_offset(&self, cageid: u64, virtualfd: u64) -> Result<u64, FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.offset)
+    }
 
-// Helper to get an empty fd_set.  Helper function to isolate unsafe code, 
-// etc.
-#[doc(hidden)]
-#[must_use] // must use the return value if you call it.
-pub fn _init_fd_set() -> fd_set {
-    let raw_fd_set:fd_set;
-    unsafe {
-        let mut this_fd_set = mem::MaybeUninit::<libc::fd_set>::uninit();
-        libc::FD_ZERO(this_fd_set.as_mut_ptr());
-        raw_fd_set = this_fd_set.assume_init();
+    /// See [`set_offset`].
+    ///
+    /// # Panics
+    ///   See [`set_offset`].
+    ///
+    /// # Errors
+    ///   See [`set_offset`].
+    pub fn set_offset(&self, cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.offset = offset)
     }
-    raw_fd_set
-}
 
-#[doc(hidden)]
-pub fn _fd_set(fd:u64, thisfdset:&mut fd_set) {
-    unsafe{libc::FD_SET(fd as i32,thisfdset)}
-}
+    // Calls func on the description virtualfd refers to.
+    fn _with_description<R>(&self, cageid:u64, virtualfd:u64, func:impl FnOnce(&mut FileDescription) -> R) -> Result<R,FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
 
-#[doc(hidden)]
-#[must_use] // must use the return value if you call it.
-pub fn _fd_isset(fd:u64, thisfdset:&fd_set) -> bool {
-    unsafe{libc::FD_ISSET(fd as i32,thisfdset)}
-}
+        // I hold the row while I use the description, so the fd can't be
+        // closed (and the description dropped) partway through.
+        let myfdrow = self.fdtable.get(&cageid).unwrap();
+        let Some(entry) = myfdrow.get(virtualfd) else {
+            return Err(FdTableError::FdNotOpen);
+        };
 
+        let mut description = self.fdcount.get_mut(&(entry.fdkind, entry.underfd)).unwrap();
+        Ok(func(&mut description))
+    }
 
+    /// See [`get_fd_limit`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_limit`].
+    ///
+    /// # Errors
+    ///   See [`get_fd_limit`].
+    pub fn get_fd_limit(&self, cageid: u64) -> Result<(u64, u64), FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
+        Ok(self.fdlimits.get(&cageid).map_or((FD_PER_PROCESS_MAX,FD_PER_PROCESS_MAX), |limits| *limits))
+    }
 
-// This is a helper that just does a single type (r/w/e) and returns:
-//    bithashmap: HashMap<fdkind, (nfds, fd_set)>
-//    unhandledhashmap: HashMap<fdkind, HashSet<FDTableEntry>>
-//    mappingtable: HashMap<FDTableEntry, virt_fd>
-// 
-// With this we trivially build the whole function...
+    /// See [`set_fd_limit`].
+    ///
+    /// # Panics
+    ///   See [`set_fd_limit`].
+    ///
+    /// # Errors
+    ///   See [`set_fd_limit`].
+    pub fn set_fd_limit(&self, cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
+
+        // Like setrlimit, the soft limit can't be above the hard limit...
+        if soft > hard {
+            return Err(FdTableError::InvalidLimit);
+        }
+        // ... and the hard limit can't be above the size of the table.
+        if hard > FD_PER_PROCESS_MAX {
+            return Err(FdTableError::LimitTooLarge);
+        }
 
-// helper to call before calling select beneath you.  Translates your virtfds 
-// into a bitmask you may use for select.
-// See: https://man7.org/linux/man-pages/man2/select.2.html for details / 
-// corner cases about the arguments.
-//
+        // Note that I leave any fds at or above the new soft limit alone.
+        // Linux does the same.  They stay open, but can't be handed out again.
+        self.fdlimits.insert(cageid, (soft,hard));
+        Ok(())
+    }
 
-// I hate doing this, but don't know how to make this interface better...
-#[allow(clippy::type_complexity)]
-#[allow(clippy::implicit_hasher)]
-#[doc = include_str!("../docs/get_bitmask_for_select.md")]
-pub fn get_bitmask_for_select(cageid:u64, nfds:u64, bits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry>>, HashMap<(u32,u64),u64>),threei::RetVal> {
-    
-    if nfds >= FD_PER_PROCESS_MAX {
-        return Err(threei::Errno::EINVAL as u64);
-    }
-
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
-
-    // The three things I will return...
-    let mut retbittable:HashMap<u32,(u64,fd_set)> = HashMap::new();
-    let mut retunparsedtable:HashMap<u32,HashSet<FDTableEntry>> = HashMap::new();
-    let mut mappingtable:HashMap<(u32,u64),u64> = HashMap::new();
-
-    // If we were asked to do this on nothing, return empty mappings...
-    if bits.is_none() {
-        return Ok((retbittable, retunparsedtable, mappingtable));
-    }
-
-    let infdset = bits.unwrap();
-
-    // dashmaps are lockless, but usually I would grab a lock on the fdtable
-    // here...  
-    let binding = FDTABLE.get(&cageid).unwrap();
-    let myfdrow = *binding.value();
-
-    // Clippy is somehow missing how the virtualfd is being used throughout
-    // here.  It's not just a range value
-    #[allow(clippy::needless_range_loop)]
-    // iterate through the set bits...
-    for bit in 0..nfds as usize {
-        let pos = bit as u64;
-        if _fd_isset(pos,&infdset) {
-            if let Some(entry) = myfdrow[bit] {
-                
-                // I like to do the shorter case first rather than having 
-                // it later.
-                #[allow(clippy::if_not_else)]
-                // Which return set do I go in?
-                if !fdkinds.contains(&entry.fdkind) {
-                    // Is unparsed...  Clippy's suggestion to insert if missing
-                    retunparsedtable.entry(entry.fdkind).or_default();
-                    retunparsedtable.get_mut(&entry.fdkind).unwrap().insert(entry);
-                    // and update the mappingtable to have the bit from the
-                    // original fd...
-                    mappingtable.insert((entry.fdkind,entry.underfd),pos);
+    /// See [`set_total_fd_limit`].
+    pub fn set_total_fd_limit(&self, limit: u64) {
+        // If this is below the number already open, nothing is closed.  New
+        // fds just fail until enough are closed.
+        self.totalfdlimit.store(limit, Ordering::Relaxed);
+    }
+
+    /// See [`get_total_fd_usage`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_total_fd_usage(&self) -> (u64, u64) {
+        (self.totalfds.load(Ordering::Relaxed), self.totalfdlimit.load(Ordering::Relaxed))
+    }
+
+    // Helper function used for fork...  Copies an fdtable for another process
+    /// See [`copy_fdtable_for_cage`].
+    ///
+    /// # Panics
+    ///   See [`copy_fdtable_for_cage`].
+    ///
+    /// # Errors
+    ///   See [`copy_fdtable_for_cage`].
+    pub fn copy_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+
+        let srccageid = self._check_cageid(srccageid)?;
+        assert!(!self._is_known_cage(newcageid),"Known cageid in fdtable access");
+        let newtablecageid = self._new_table_key(newcageid);
+
+        let mut opened = self._pending_opens();
+        // The child uses the parent's row until one of them changes it, so
+        // nothing is copied here.  Once the child is one of the row's users,
+        // every reference to the row counts for it too.
+        let myfdrow = {
+            let srcrow = self.fdtable.get(&srccageid).unwrap();
+
+            // Every copied fd counts against the total limit...
+            self._reserve_total_fds(srcrow.count())?;
+
+            // While I hold the parent's row, so the parent can't move to
+            // another one first.
+            self.rowusers.get_mut(&srcrow.rowid).unwrap().push(newtablecageid);
+            if opened.wanted() {
+                for entry in srcrow.iter().flatten() {
+                    opened.add(entry);
                 }
-                else {
+            }
+            Arc::clone(&srcrow)
+        };
 
-                    let startingnfds;
-                    let mut startingfdset;
+        assert!(self.fdtable.insert(newtablecageid, myfdrow).is_none());
+        self._set_table_key(newcageid, newtablecageid);
 
-                    // Either initialize it or use what exists
-                    if retbittable.contains_key(&entry.fdkind) {
-                        (startingnfds, startingfdset) = *retbittable.get(&entry.fdkind).unwrap();
-                    }
-                    else{
-                        startingnfds = 1;
-                        // I don't init this above because a fd_set is a large
-                        // data structure and would be costly. 
-                        startingfdset = _init_fd_set();
-                    }
+        // The child inherits the limits too.  I copy these out first, so I'm
+        // not holding a reference into fdlimits when I insert into it.
+        let limits = self.fdlimits.get(&srccageid).map(|limits| *limits);
+        if let Some(limits) = limits {
+            self.fdlimits.insert(newtablecageid, limits);
+        }
+
+        self._call_open_handlers(opened, OpenReason::Fork);
+        Ok(())
+    }
 
-                    // Update the table and the nfds
-                    _fd_set(entry.underfd,&mut startingfdset);
-                    let newnfds = cmp::max(startingnfds, entry.underfd+1);
+    // Helper function used for clone(CLONE_FILES), which is how threads are
+    // made...  The new cage uses the same table rather than a copy of it.
+    /// See [`share_fdtable_for_cage`].
+    ///
+    /// # Panics
+    ///   See [`share_fdtable_for_cage`].
+    ///
+    /// # Errors
+    ///   See [`share_fdtable_for_cage`].
+    pub fn share_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+
+        let srccageid = self._check_cageid(srccageid)?;
+        assert!(!self._is_known_cage(newcageid),"Known cageid in fdtable access");
+
+        // The fds are still in one table, so their reference counts (and the
+        // total number open) don't change.
+        self._set_table_key(newcageid, srccageid);
+        Ok(())
+    }
 
-                    // and update the mappingtable to have the bit from the
-                    // original fd...
-                    mappingtable.insert((entry.fdkind,entry.underfd),pos);
+    /// See [`unshare_fdtable_for_cage`].
+    ///
+    /// # Panics
+    ///   See [`unshare_fdtable_for_cage`].
+    ///
+    /// # Errors
+    ///   See [`unshare_fdtable_for_cage`].
+    pub fn unshare_fdtable_for_cage(&self, cageid: u64) -> Result<(), FdTableError> {
+        let tablecageid = self._check_cageid(cageid)?;
+        let mut opened = self._pending_opens();
+        self._unshare(cageid, tablecageid, false, &mut opened);
+        self._call_open_handlers(opened, OpenReason::Fork);
+        Ok(())
+    }
 
-                    // insert the item
-                    retbittable.insert(entry.fdkind,(newnfds,startingfdset));
-                }
+    // Gives cageid a table which no other cage uses.  tablecageid is where
+    // its table is now (from _check_cageid).  Returns where it is after, which
+    // is a new key if the table was copied.  The other cages keep using the
+    // table where it is, since they may have already looked it up.  For exec,
+    // the copy leaves out the close-on-exec fds.  The references the copy
+    // adds go in opened.
+    fn _unshare(&self, cageid:u64, tablecageid:u64, forexec:bool, opened:&mut PendingOpens<P>) -> u64 {
+        if self._table_users(tablecageid).iter().all(|&user| user == cageid) {
+            return tablecageid;
+        }
+        let newtablecageid = self._new_table_key(cageid);
+        self._copy_shared_table(tablecageid, newtablecageid, forexec, opened);
+        // Its calls go to the shared table until this, so it doesn't matter
+        // that the copy is in the fdtable first.
+        self._set_table_key(cageid, newtablecageid);
+        newtablecageid
+    }
+
+    // The cages which use the table stored under tablecageid, in order.
+    fn _table_users(&self, tablecageid:u64) -> Vec<u64> {
+        let mut users: Vec<u64> = self.sharedtables.iter()
+            .filter(|item| *item.value() == Some(tablecageid))
+            .map(|item| *item.key())
+            .collect();
+        // The cage with that cageid uses it, unless it is in sharedtables.
+        if !self.sharedtables.contains_key(&tablecageid) {
+            users.push(tablecageid);
+        }
+        users.sort_unstable();
+        users
+    }
+
+    // Puts a copy of the table stored under tablecageid (and its limits) in
+    // the fdtable under newtablecageid.  Unlike fork, this isn't held to the
+    // total limit, since nothing new is opened (and exec can't fail).
+    fn _copy_shared_table(&self, tablecageid:u64, newtablecageid:u64, forexec:bool, opened:&mut PendingOpens<P>) {
+        let myfdrow = {
+            let tablerow = self.fdtable.get(&tablecageid).unwrap();
+            let myfdrow = if forexec {
+                // The close-on-exec fds are left out, rather than copied and
+                // closed.
+                let rowcopy = self._copy_row(newtablecageid, &tablerow, |entry| !entry.should_cloexec);
+                self.totalfds.fetch_add(rowcopy.count(), Ordering::Relaxed);
+                rowcopy
             }
             else {
-                return Err(threei::Errno::EBADF as u64);
+                // Like fork, the new cage uses the row until it changes it.
+                self.totalfds.fetch_add(tablerow.count(), Ordering::Relaxed);
+                self.rowusers.get_mut(&tablerow.rowid).unwrap().push(newtablecageid);
+                Arc::clone(&tablerow)
+            };
+            if opened.wanted() {
+                for entry in myfdrow.iter().flatten() {
+                    opened.add(entry);
+                }
             }
+            myfdrow
+        };
+
+        self.fdtable.insert(newtablecageid, myfdrow);
+
+        let limits = self.fdlimits.get(&tablecageid).map(|limits| *limits);
+        if let Some(limits) = limits {
+            self.fdlimits.insert(newtablecageid, limits);
         }
     }
-    Ok((retbittable, retunparsedtable, mappingtable))
-    
-}
 
+    // This is mostly used in handling exit, etc.  Returns the HashMap
+    // for the cage.
+    /// See [`remove_cage_from_fdtable`].
+    ///
+    /// # Panics
+    ///   See [`remove_cage_from_fdtable`].
+    pub fn remove_cage_from_fdtable(&self, cageid: u64) {
+
+        // With the Error policy, an unknown cage has no fds to remove...
+        let Ok(tablecageid) = self._check_cageid(cageid) else {
+            return;
+        };
 
-#[allow(clippy::type_complexity)]
-#[allow(clippy::implicit_hasher)]
-#[doc = include_str!("../docs/prepare_bitmasks_for_select.md")]
-pub fn prepare_bitmasks_for_select(cageid:u64, nfds:u64, rbits:Option<fd_set>, wbits:Option<fd_set>, ebits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry>>;3], HashMap<(u32,u64),u64>),threei::RetVal> {
-    // This is a pretty simple function.  Calls get_bitmask_for_select 
-    // repeatedly and combines the results...
-    // [HashSet<(u64,u64)>;3]
+        // The cage stops using its table.  If its cageid is the key of a
+        // table, it stays in sharedtables (as None) while the table is there,
+        // so it is unknown now.
+        if self.fdtable.contains_key(&cageid) {
+            self.sharedtables.insert(cageid, None);
+        }
+        else {
+            self.sharedtables.remove(&cageid);
+        }
 
-    // return the error, if need be
-    let rresult = get_bitmask_for_select(cageid, nfds, rbits, fdkinds)?;
-    let wresult = get_bitmask_for_select(cageid, nfds, wbits, fdkinds)?;
-    let eresult = get_bitmask_for_select(cageid, nfds, ebits, fdkinds)?;
+        // If other cages use the table, its fds are still open for them, and
+        // it stays where it is.
+        if !self._table_users(tablecageid).is_empty() {
+            return;
+        }
 
-    let mut mappingtable = rresult.2;
-    mappingtable.extend(wresult.2);
-    mappingtable.extend(eresult.2);
+        // remove the item first and then we clean up and call their close
+        // handlers.  (If the last two cages exit at once, both may get here,
+        // but only one removes it.)
+        let Some((_, myfdrow)) = self.fdtable.remove(&tablecageid) else {
+            return;
+        };
+        self.fdlimits.remove(&tablecageid);
+        // The key was only kept from being a cage for this table.  (A cage
+        // with that cageid may have got a table since, under another key.)
+        self.sharedtables.remove_if(&tablecageid, |_, tablekey| tablekey.is_none());
 
-    Ok(([rresult.0,wresult.0,eresult.0],[rresult.1,wresult.1,eresult.1],mappingtable))
+        // If a forked cage still uses the row, its fds are still open, so
+        // this just takes this cage's references away.
+        self._leave_row(tablecageid, &myfdrow, |_| false);
 
-}
+    }
 
+    // This removes all fds with the should_cloexec flag set.  They are returned
+    // in a new hashmap...
+    /// See [`empty_fds_for_exec`].
+    ///
+    /// # Panics
+    ///   See [`empty_fds_for_exec`].
+    pub fn empty_fds_for_exec(&self, cageid: u64) {
+
+        // With the Error policy, an unknown cage has no fds to close...
+        let Ok(tablecageid) = self._check_cageid(cageid) else {
+            return;
+        };
 
-// helper to call after calling select beneath you.  returns the fd_set you 
-// need for your return from a select call and the number of unique flags
-// set...
+        // Like Linux, exec gives the cage a table of its own first.  If it
+        // used a table other cages use, its copy just leaves out the
+        // close-on-exec fds.  Those are still open for the other cages, so
+        // there is nothing to close.
+        let mut opened = self._pending_opens();
+        let newtablecageid = self._unshare(cageid, tablecageid, true, &mut opened);
+        self._call_open_handlers(opened, OpenReason::Fork);
+        if newtablecageid != tablecageid {
+            return;
+        }
 
-// I given them the hashmap, so don't need flexibility in what they return...
-#[allow(clippy::implicit_hasher)]
-#[must_use] // must use the return value if you call it.
-#[doc = include_str!("../docs/get_one_virtual_bitmask_from_select_result.md")]
-pub fn get_one_virtual_bitmask_from_select_result(fdkind:u32, nfds:u64, bits:Option<fd_set>, unprocessedset:HashSet<u64>, startingbits:Option<fd_set>,mappingtable:&HashMap<(u32,u64),u64>) -> (u64, Option<fd_set>) {
+        // Right after a fork, the row is usually still shared with the
+        // parent.  Then, rather than copy it and close the close-on-exec fds
+        // in the copy, the copy leaves them out.
+        {
+            let mut myfdrow = self.fdtable.get_mut(&tablecageid).unwrap();
+            if Arc::get_mut(&mut myfdrow).is_none() {
+                let keep = |entry: &FDTableEntry<P>| !entry.should_cloexec;
+                let rowcopy = self._copy_row(tablecageid, &myfdrow, keep);
+                let oldrow = std::mem::replace(&mut *myfdrow, rowcopy);
+                drop(myfdrow);
+                self._leave_row(tablecageid, &oldrow, keep);
+                return;
+            }
+        }
 
-    // Note, I don't need the cage_id here because I have the mappingtable...
+        let mut myfdrow = self._row_mut(tablecageid);
+        let rowid = myfdrow.rowid;
+        // I need to call all the close handlers at the end.  So I need to
+        // get vector of them to do the operation on...
+        let mut closevec = Vec::new();
+
+        for item in 0..FD_PER_PROCESS_MAX as usize {
+            if myfdrow[item].as_ref().is_some_and(|entry| entry.should_cloexec) {
+                // handle this in a moment...  Always zero out the row before
+                // calling their handler
+                closevec.push((item as u64, myfdrow.take(item as u64).unwrap()));
+            }
+        }
 
-    assert!(nfds < FD_PER_PROCESS_MAX,"This shouldn't be possible because we shouldn't have returned this previously");
+        // Need to drop the lock, before calling the handlers.
+        drop(myfdrow);
 
-    let mut flagsset = 0;
+        // Now, we can call the close handlers!
+        let mut unhandled = Vec::new();
+        for (virtualfd, entry) in closevec {
+            unhandled.extend(self._decrement_fdcount(rowid, virtualfd, entry));
+        }
+        self._report_unhandled_closes(unhandled);
 
-    if bits.is_none() && unprocessedset.is_empty() {
-        return (flagsset,None);
     }
 
-    // I probably should pass a reference to startingbits to avoid copying the 
-    // bit structure...
-    let mut retbits = match startingbits {
-        Some(val) => val,
-        None => _init_fd_set(),
-    };
+    // Returns the HashMap returns a copy of the fdtable for a cage.  Useful
+    // helper function for a caller that needs to examine the table.  Likely could
+    // be more efficient by letting the caller borrow this...
+    /// See [`return_fdtable_copy`].
+    ///
+    /// # Panics
+    ///   See [`return_fdtable_copy`].
+    #[must_use] // must use the return value if you call it.
+    pub fn return_fdtable_copy(&self, cageid: u64) -> HashMap<u64, FDTableEntry<P>> {
+
+        // With the Error policy, an unknown cage has no fds...
+        let Ok(cageid) = self._check_cageid(cageid) else {
+            return HashMap::new();
+        };
 
-    if let Some(inset) = bits {
-        for bit in 0..nfds as usize {
-            let pos = bit as u64;
-            if _fd_isset(pos,&inset)&& !_fd_isset(*mappingtable.get(&(fdkind,pos)).unwrap(),&retbits) {
-                flagsset+=1;
-                _fd_set(*mappingtable.get(&(fdkind,pos)).unwrap(),&mut retbits);
+        let mut myhashmap = HashMap::new();
+
+        let myfdrow = self.fdtable.get(&cageid).unwrap();
+        for item in 0..FD_PER_PROCESS_MAX as usize {
+            if let Some(entry) = &myfdrow[item] {
+                myhashmap.insert(item as u64,entry.clone());
             }
         }
+        myhashmap
     }
-    for virtfd in unprocessedset {
-        if !_fd_isset(virtfd,&retbits) {
-            flagsset+=1;
-            _fd_set(virtfd,&mut retbits);
-        }
+
+    // func is called while I hold the lock on the cage's table, so it sees a
+    // consistent table and nothing is copied.
+    /// See [`for_each_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`for_each_virtualfd`].
+    pub fn for_each_virtualfd(&self, cageid: u64, func: impl FnMut(u64, &FDTableEntry<P>)) {
+        self.for_each_virtualfd_in_range(cageid, 0, u64::MAX, None, func);
     }
 
-    (flagsset,Some(retbits))
+    /// See [`for_each_virtualfd_in_range`].
+    ///
+    /// # Panics
+    ///   See [`for_each_virtualfd_in_range`].
+    pub fn for_each_virtualfd_in_range(
+        &self,
+        cageid: u64,
+        first: u64,
+        last: u64,
+        fdkind: Option<u32>,
+        mut func: impl FnMut(u64, &FDTableEntry<P>),
+    ) {
+        // With the Error policy, an unknown cage has no fds...
+        let Ok(cageid) = self._check_cageid(cageid) else {
+            return;
+        };
 
+        let myfdrow = self.fdtable.get(&cageid).unwrap();
+
+        // Nothing is open past the end of the table, so I stop there (last
+        // is often !0).
+        for virtualfd in first..=cmp::min(last, FD_PER_PROCESS_MAX - 1) {
+            if let Some(entry) = myfdrow.get(virtualfd) {
+                if fdkind.is_none_or(|kind| kind == entry.fdkind) {
+                    func(virtualfd, entry);
+                }
+            }
+        }
+    }
 }
 
 
 
-/********************** POLL SPECIFIC FUNCTIONS **********************/
+/******************* QUERY FUNCTIONS *******************/
 
-// helper to call before calling poll beneath you.  replaces the fds in 
-// the poll struct with virtual versions and returns the items you need
-// to check yourself...
-#[allow(clippy::implicit_hasher)]
-#[allow(clippy::type_complexity)]
-#[doc = include_str!("../docs/convert_virtualfds_for_poll.md")]
-#[must_use] // must use the return value if you call it.
-pub fn convert_virtualfds_for_poll(cageid:u64, virtualfds:HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry)>>, HashMap<(u32,u64),u64>) {
+// These only read the tables.  They are for things like lsof, or finding out
+// who still has the other end of a pipe open.
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    let thefdrow = *FDTABLE.get(&cageid).unwrap();
-    let mut mappingtable:HashMap<(u32,u64),u64> = HashMap::new();
-    let mut rethashmap:HashMap<u32,HashSet<(u64,FDTableEntry)>> = HashMap::new();
+    /// See [`get_cageids`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_cageids(&self) -> Vec<u64> {
+        // A key in sharedtables isn't its own cage's table.
+        let mut cageids: Vec<u64> = self.fdtable.iter().map(|row| *row.key())
+            .filter(|tablecageid| !self.sharedtables.contains_key(tablecageid))
+            .chain(self.sharedtables.iter().filter(|item| item.value().is_some()).map(|item| *item.key()))
+            .collect();
+        cageids.sort_unstable();
+        // A cage which is unsharing its table is briefly in both.
+        cageids.dedup();
+        cageids
+    }
 
-    
-    // BUG?: I'm ignoring the fact that virtualfds can show up multiple times.
-    // I'm not sure this actually matters, but I didn't think hard about it.
-    for virtfd in virtualfds {
-        if let Some(entry) = thefdrow[virtfd as usize] {
-            // Insert an empty HashSet, if needed
-            rethashmap.entry(entry.fdkind).or_default();
-            mappingtable.entry((entry.fdkind,entry.underfd)).or_default();
+    /// See [`get_fd_count`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_count`].
+    ///
+    /// # Errors
+    ///   See [`get_fd_count`].
+    pub fn get_fd_count(&self, cageid: u64) -> Result<u64, FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
+        // The row's bitmap knows, so I don't need to look at the entries.
+        Ok(self.fdtable.get(&cageid).unwrap().count())
+    }
 
-            rethashmap.get_mut(&entry.fdkind).unwrap().insert((virtfd,entry));
-            mappingtable.insert((entry.fdkind,entry.underfd), virtfd);
+    /// See [`get_fd_references`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_fd_references(&self, fdkind: u32, underfd: u64) -> Vec<(u64, u64)> {
+        let places = self.fdcount.get(&(fdkind, underfd)).map_or_else(HashMap::new, |description| description.references.clone());
+        // Every table which uses a row has the fds which are in it.
+        let mut references = Vec::new();
+        for (rowid, virtualfds) in places {
+            if let Some(users) = self.rowusers.get(&rowid) {
+                for &cageid in users.iter() {
+                    references.extend(virtualfds.iter().map(|&virtualfd| (cageid, virtualfd)));
+                }
+            }
         }
-        else {
-            let myentry = FDTableEntry {
-                fdkind:FDT_INVALID_FD,
-                underfd:virtfd,
-                should_cloexec:false,
-                perfdinfo:u64::from(FDT_INVALID_FD),
-            };
+        // Those are under the keys of the tables, so they go to the cages
+        // which use each table.
+        let mut shared = Vec::new();
+        for item in &self.sharedtables {
+            if let Some(tablecageid) = *item.value() {
+                shared.extend(references.iter()
+                    .filter(|(cageid, _)| *cageid == tablecageid)
+                    .map(|&(_, virtualfd)| (*item.key(), virtualfd)));
+            }
+        }
+        references.retain(|(cageid, _)| !self.sharedtables.contains_key(cageid));
+        references.extend(shared);
+        references.sort_unstable();
+        // A replaced fd is briefly in here twice (see FileDescription).
+        references.dedup();
+        references
+    }
 
-            // Insert an empty HashSet, if needed
-            rethashmap.entry(FDT_INVALID_FD).or_default();
-            mappingtable.entry((FDT_INVALID_FD,virtfd)).or_default();
+    /// See [`get_fd_reference_count`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_fd_reference_count(&self, fdkind: u32, underfd: u64) -> u64 {
+        self.fdcount.get(&(fdkind, underfd)).map_or(0, |description| self._reference_count(&description))
+    }
 
-            rethashmap.get_mut(&FDT_INVALID_FD).unwrap().insert((virtfd,myentry));
-            // Add this because they need to handle it if POLLNVAL is set.
-            // An exception should not be raised!!!
+    /// See [`get_fd_reference_counts`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_fd_reference_counts(&self, fdkind: u32) -> HashMap<u64, u64> {
+        self.fdcount.iter()
+            .filter(|item| item.key().0 == fdkind)
+            .map(|item| (item.key().1, self._reference_count(item.value())))
+            .collect()
+    }
 
-            // I will add this to the mapping table, because I do think they
-            // may want to raise an exception, etc. based upon this and signal
-            // back.  I am setting the underfd to be the virtfd, so I can 
-            // reverse this process, if multiple entries like this occur.
-            mappingtable.insert((FDT_INVALID_FD,virtfd), virtfd);
-        }
+    /// See [`get_virtualfds_of_kind`].
+    ///
+    /// # Panics
+    ///   See [`get_virtualfds_of_kind`].
+    ///
+    /// # Errors
+    ///   See [`get_virtualfds_of_kind`].
+    pub fn get_virtualfds_of_kind(&self, cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError> {
+        let cageid = self._check_cageid(cageid)?;
+        let mut virtualfds = Vec::new();
+        self.for_each_virtualfd_in_range(cageid, 0, u64::MAX, Some(fdkind), |virtualfd, _| virtualfds.push(virtualfd));
+        Ok(virtualfds)
     }
+}
+
+
 
-    (rethashmap, mappingtable)
+/******************* CROSS-CAGE GETFD POLICY *******************/
+
+// The functions set with set_getfd_policy and set_getfd_audit.  They are
+// taken out (as Arcs) and called after the lock is dropped.
+struct GetfdHooks {
+    policy: GetfdPolicy,
+    audit: GetfdAudit,
 }
 
+impl Default for GetfdHooks {
+    fn default() -> Self {
+        Self {
+            policy: Arc::new(GETFD_SAME_CAGE_ONLY),
+            audit: Arc::new(GETFD_NULL_AUDIT),
+        }
+    }
+}
 
+// The closures can't be printed, so this doesn't show anything.
+impl std::fmt::Debug for GetfdHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GetfdHooks").finish_non_exhaustive()
+    }
+}
 
-// helper to call after calling poll.  replaces the fds in the vector
-// with virtual ones...
-#[doc = include_str!("../docs/convert_poll_result_back_to_virtual.md")]
-// I give them the hashmap, so don't need flexibility in what they return...
-#[allow(clippy::implicit_hasher)]
-#[must_use] // must use the return value if you call it.
-pub fn convert_poll_result_back_to_virtual(fdkind:u32,underfd:u64, mappingtable:&HashMap<(u32,u64),u64>) -> Option<u64> {
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    // I don't care what cage was used, and don't need to lock anything...
-    // I have the mappingtable!
-    
-    // Should this even be a function?
-    mappingtable.get(&(fdkind,underfd)).copied()
+    /// See [`set_getfd_policy`].
+    ///
+    /// # Panics
+    ///   See [`set_getfd_policy`].
+    pub fn set_getfd_policy(&self, policy:GetfdPolicy) {
+        self.getfdhooks.lock().unwrap().policy = policy;
+    }
+
+    /// See [`set_getfd_audit`].
+    ///
+    /// # Panics
+    ///   See [`set_getfd_audit`].
+    pub fn set_getfd_audit(&self, audit:GetfdAudit) {
+        self.getfdhooks.lock().unwrap().audit = audit;
+    }
 }
 
 
 
-/********************** EPOLL SPECIFIC FUNCTIONS **********************/
+/******************* UNKNOWN CAGE HANDLING *******************/
 
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-// Supporting epollfds is done by a fdkind which is not set by the user.  
-// There are a few complexities here:
-// 1) an epollfd gets a virtual file descriptor
-// 2) a epollfd can point to any number of other fds of different kinds
-// 3) an epollfd can point to epollfds, which can point to other epollfds, etc.
-//    and possibly cause a loop to occur (which is an error)
-// 
-// My thinking is this is handled as similarly to poll as possible.  We push
-// off the problem of understanding what the event types are to the implementer
-// of the library.
-//
-// In my view, epoll_wait() is quite simple to support.  One basically just 
-// keeps a list of virtual fds for this epollfd and their corresponding event 
-// types, which they may need to poll themselves.  After this, they handle the
-// call.
-//
-// epoll_ctl is complex, but really has the same fundamental problem as 
-// epoll_create: the epollfd.
-//
-// I'll create a new fdkind for epoll.  When epoll_create is called, the 
-// caller can decide which fdkinds need to be passed down to the underlying 
-// epoll_create call(s).  Similarly, when epoll_ctl is called, one either 
-// handles the call internally or uses the underfd for the fdkind...
-//
-// Interestingly, this actually would be just as easy to build on top of the
-// fdtables library as into it.  
-//
-// Each epollfd will have some virtual fds associated with it.  Each of those
-// will have an event mask.  So I'll have a mutex around an EPollTable struct.
-// This contains the next available entry and an epollhashmap<virtfd, event>.  
-// I use a hashmap here to better support removing and modifying items.
+    /// See [`set_unknown_cage_policy`].
+    ///
+    /// # Panics
+    ///   See [`set_unknown_cage_policy`].
+    pub fn set_unknown_cage_policy(&self, policy:UnknownCagePolicy) {
+        *self.unknowncagepolicy.lock().unwrap() = policy;
+    }
 
-// Note, I'm defining a bunch of symbols myself because libc doesn't import
-// them on systems that don't support epoll and I want to be able to build
-// the code anywhere.  See commonconstants.rs for more info.
+    /// See [`unknown_cage_policy`].
+    ///
+    /// # Panics
+    ///   See [`unknown_cage_policy`].
+    #[must_use] // must use the return value if you call it.
+    pub fn unknown_cage_policy(&self) -> UnknownCagePolicy {
+        *self.unknowncagepolicy.lock().unwrap()
+    }
 
+    // Checks that the cage exists, doing whatever the unknown cage policy
+    // says if it doesn't.  Returns the key the cage's table is stored under,
+    // which is the cageid unless it is in sharedtables.  That one is in the
+    // fdtable until the cage is removed, so callers use it in place of cageid.
+    fn _check_cageid(&self, cageid:u64) -> Result<u64,FdTableError> {
+        match self.sharedtables.get(&cageid).map(|tablecageid| *tablecageid) {
+            Some(Some(tablecageid)) => return Ok(tablecageid),
+            // Only a table other cages use is left under this cageid.
+            Some(None) => {},
+            None => {
+                if self.fdtable.contains_key(&cageid) {
+                    return Ok(cageid);
+                }
+            },
+        }
+        let mut tablecageid = cageid;
+        self._handle_unknown_cageid(cageid, |newcageid| {
+            tablecageid = self._new_table_key(newcageid);
+            self.fdtable.entry(tablecageid).or_insert_with(|| self._new_row(tablecageid));
+            self._set_table_key(newcageid, tablecageid);
+        })?;
+        Ok(tablecageid)
+    }
 
-// Okay, so the basic structure is like this:
-// 1) epoll_create_helper sets up an epollfd.  You will need to have a unique 
-// underfd for each fdkind below you, if you want to call down.   
-// 2) my API should track all of the fdkinds where there isn't an underlying
-// epollfd
-// 3) epoll_ctl / epoll_wait will work on whichever is appropriate
-//
-// A hashmap of:
-//  HashMap<fdkind,underepollfd> for tracking how to call down.
-//  HashMap<fdkind,HashMap<virtfd,epoll_event>> seems to make the most sense for the other
-//      descriptors.
+    // Is this a cage with a table?  (_check_cageid without the policy.)
+    fn _is_known_cage(&self, cageid:u64) -> bool {
+        match self.sharedtables.get(&cageid).map(|tablecageid| *tablecageid) {
+            Some(tablecageid) => tablecageid.is_some(),
+            None => self.fdtable.contains_key(&cageid),
+        }
+    }
 
+    // The key to store a new table for cageid under.  That's its cageid,
+    // unless a table other cages use is still there.  Then it is a new key,
+    // which is in sharedtables as None until the table is, so that cageid
+    // stays unknown.
+    fn _new_table_key(&self, cageid:u64) -> u64 {
+        if !self.fdtable.contains_key(&cageid) {
+            return cageid;
+        }
+        loop {
+            let tablecageid = self.nexttablekey.fetch_sub(1, Ordering::Relaxed);
+            if self.fdtable.contains_key(&tablecageid) {
+                continue;
+            }
+            // A cage may already have this cageid...
+            if let dashmap::mapref::entry::Entry::Vacant(entry) = self.sharedtables.entry(tablecageid) {
+                if !self.fdtable.contains_key(&tablecageid) {
+                    entry.insert(None);
+                    return tablecageid;
+                }
+            }
+        }
+    }
 
-// a structure that exists for each epoll descriptor to track the underfd(s)
-// and parts the user will handle
-#[derive(Clone, Debug, Default)]
-struct EPollDescriptorInfo {
-    // I didn't combine thewe two hashmaps into one because they are used
-    // separately and the resulting value type would be too messy...
+    // Makes cageid use the table stored under tablecageid (which is in the
+    // fdtable already).
+    fn _set_table_key(&self, cageid:u64, tablecageid:u64) {
+        if tablecageid == cageid {
+            self.sharedtables.remove(&cageid);
+        }
+        else {
+            self.sharedtables.insert(cageid, Some(tablecageid));
+        }
+    }
 
-    underfdhashmap: HashMap<u32,u64>, // The underfd for a specific fdkind.
-                                      // Used only when an epoll call will
-                                      // call down beneath it.
-    userhandledhashmap: HashMap<u32,HashMap<u64,epoll_event>>,
-                                      // This has all of the things the user
-                                      // will virtualize and handle.  The key
-                                      // is the fdkind.  
+    // The soft limit for a cage.  Cages which never set a limit have the
+    // default one.
+    fn _soft_fd_limit(&self, cageid:u64) -> u64 {
+        self.fdlimits.get(&cageid).map_or(FD_PER_PROCESS_MAX, |limits| limits.0)
+    }
+
+    // Does what the policy says for a cage which isn't in the fdtable.
+    // createfunc adds an empty table for the cage.
+    fn _handle_unknown_cageid(&self, cageid:u64, createfunc:impl FnOnce(u64)) -> Result<(),FdTableError> {
+        // Copy this out first, so I don't poison the lock by panicking while
+        // holding it...
+        let policy = *self.unknowncagepolicy.lock().unwrap();
+        match policy {
+            UnknownCagePolicy::Panic => panic!("Unknown cageid in fdtable access"),
+            UnknownCagePolicy::Error => Err(FdTableError::UnknownCage),
+            UnknownCagePolicy::AutoCreate => {
+                createfunc(cageid);
+                Ok(())
+            },
+        }
+    }
 }
 
-// TODO: I don't clean up this table yet.  I probably should when the last 
-// reference to a fd is closed, but this bookkeeping seems excessive at this
-// time...
-#[derive(Clone, Debug)]
-struct EPollTable {
-    highestneverusedentry: u64, // Never resets (even after close).  Used to
-                                // let us quickly get an unused entry
-    thisepolltable: HashMap<u64,EPollDescriptorInfo>, 
+/******************* OPEN SPECIFIC FUNCTIONALITY *******************/
+
+// A handler to call when a reference to an (fdkind,underfd) is added.
+struct OpenHandlers<P> {
+    // Identifies it, so remove_open_handler can find it.
+    token: OpenHandlerToken,
+    // Called with (entry,count,reason)
+    handler: OpenHandler<P>,
 }
 
-lazy_static! {
+// The closure can't be printed, so this only shows the token.
+impl<P> std::fmt::Debug for OpenHandlers<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenHandlers").field("token", &self.token).finish_non_exhaustive()
+    }
+}
 
-    #[derive(Debug)]
-    static ref EPOLLTABLE: Mutex<EPollTable> = {
-        let newetable = HashMap::new();
-        let m = EPollTable {
-            highestneverusedentry:0, 
-            thisepolltable:newetable,
-        };
-        Mutex::new(m)
-    };
+// Each fdkind's open handlers, in the order they were added.
+type OpenHandlerTable<P> = Arc<HashMap<u32,Vec<Arc<OpenHandlers<P>>>>>;
+
+// The references an operation added, for the open handlers.  It is filled
+// in while the operation holds its locks, and the handlers are called once
+// it drops them.  Only entries whose fdkind has handlers are kept, so this
+// costs nothing much if there aren't any.
+struct PendingOpens<P> {
+    openhandlers: OpenHandlerTable<P>,
+    entries: Vec<FDTableEntry<P>>,
 }
 
-fn _get_epoll_entrynum_or_error(cageid:u64, epfd:u64) -> Result<u64,threei::RetVal> {
-    // Is the epfd ok? 
-    match FDTABLE.get(&cageid).unwrap()[epfd as usize] {
-        None => {
-            Err(threei::Errno::EBADF as u64)
-        },
-        Some(tableentry) => { 
-            // You must call this on an epoll fd
-            if tableentry.fdkind == FDT_KINDEPOLL {
-                Ok(tableentry.underfd)
-            }
-            else {
-                Err(threei::Errno::EINVAL as u64)
+impl<P: Clone> PendingOpens<P> {
+    // Whether any fdkind has open handlers.  Lets a caller skip looking at
+    // every fd of a table it copied.
+    fn wanted(&self) -> bool {
+        !self.openhandlers.is_empty()
+    }
+
+    fn add(&mut self, entry:&FDTableEntry<P>) {
+        if self.openhandlers.contains_key(&entry.fdkind) {
+            self.entries.push(entry.clone());
+        }
+    }
+}
+
+impl<P: Clone + Send + Sync> FdTables<P> {
+
+    // The table is copied on write, so a call which is already running the
+    // old one doesn't see the change.
+    /// See [`add_open_handler`].
+    ///
+    /// # Panics
+    ///   See [`add_open_handler`].
+    #[must_use] // must use the return value if you call it.
+    pub fn add_open_handler(&self, fdkind:u32, handler: OpenHandler<P>) -> OpenHandlerToken {
+        let token = OpenHandlerToken(self.nexthandlertoken.fetch_add(1, Ordering::Relaxed));
+        let openhandler = OpenHandlers {
+            token,
+            handler,
+        };
+        let mut openhandlertable = self.openhandlertable.lock().unwrap();
+        Arc::make_mut(&mut openhandlertable).entry(fdkind).or_default().push(Arc::new(openhandler));
+        token
+    }
+
+    /// See [`remove_open_handler`].
+    ///
+    /// # Panics
+    ///   See [`remove_open_handler`].
+    #[must_use] // must use the return value if you call it.
+    pub fn remove_open_handler(&self, token:OpenHandlerToken) -> bool {
+        let mut openhandlertable = self.openhandlertable.lock().unwrap();
+        let Some(&fdkind) = openhandlertable.iter().find(|(_, openhandlers)| openhandlers.iter().any(|openhandler| openhandler.token == token)).map(|(fdkind, _)| fdkind) else {
+            return false;
+        };
+        let openhandlertable = Arc::make_mut(&mut openhandlertable);
+        let openhandlers = openhandlertable.get_mut(&fdkind).unwrap();
+        openhandlers.retain(|openhandler| openhandler.token != token);
+        // Leave no empty lists, so the fdkind has no handlers again.
+        if openhandlers.is_empty() {
+            openhandlertable.remove(&fdkind);
+        }
+        true
+    }
+
+    // This only holds the openhandlertable lock for a moment, so it may be
+    // called while holding others.  Pass the result to _call_open_handlers
+    // once they are all dropped.
+    fn _pending_opens(&self) -> PendingOpens<P> {
+        PendingOpens {
+            openhandlers: Arc::clone(&self.openhandlertable.lock().unwrap()),
+            entries: Vec::new(),
+        }
+    }
+
+    // Calls the open handlers for the references the operation added.  The
+    // count is looked up now, so it includes every reference the operation
+    // added (and nothing it is about to close).
+    fn _call_open_handlers(&self, opened:PendingOpens<P>, reason:OpenReason) {
+        for entry in opened.entries {
+            let count = self.get_fd_reference_count(entry.fdkind, entry.underfd);
+            for openhandler in &opened.openhandlers[&entry.fdkind] {
+                (openhandler.handler)(&entry, count, reason);
             }
-        },
+        }
     }
 }
 
 
-#[doc = include_str!("../docs/epoll_create_empty.md")]
-pub fn epoll_create_empty(cageid:u64, should_cloexec:bool) -> Result<u64,threei::RetVal> {
 
-    let mut ept = EPOLLTABLE.lock().unwrap();
+/******************* CLOSE SPECIFIC FUNCTIONALITY *******************/
 
-    // return the same errno (EMFile), if we get one 
-    let newepollfd = get_unused_virtual_fd(cageid, FDT_KINDEPOLL, ept.highestneverusedentry, should_cloexec, 0)?;
+// These indicate what functions should be called upon a virtualfd closing.
+// The handler which is called depends on number of (fdkind,underfd) tuples
+// that are used across *all instances managed by this library including in
+// other cages*.
+struct CloseHandlers<P> {
+    // Identifies these, so remove_close_handlers can find them.
+    token: CloseHandlerToken,
+    // Called when close is called, but at least one (fdkind,underfd)
+    // reference still remains.  Called with (entry,count)
+    intermediate: CloseHandler<P>,
+    // Called when the last (fdkind,underfd) reference is closed.  Called
+    // with (entry,0)
+    last: CloseHandler<P>,
+}
 
-    let newentrynum = ept.highestneverusedentry;
-    ept.highestneverusedentry+=1;
-    
-    // Create a new entry with empty values
-    ept.thisepolltable.entry(newentrynum).or_default();
-    Ok(newepollfd)
+// The closures can't be printed, so this only shows the token.
+impl<P> std::fmt::Debug for CloseHandlers<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloseHandlers").field("token", &self.token).finish_non_exhaustive()
+    }
+}
 
+impl<P> CloseHandlers<P> {
+    fn call(&self, entry:FDTableEntry<P>, count:u64) {
+        if count > 0 {
+            (self.intermediate)(entry,count);
+        }
+        else {
+            (self.last)(entry,0);
+        }
+    }
 }
 
-#[doc = include_str!("../docs/epoll_add_underfd.md")]
-pub fn epoll_add_underfd(cageid:u64, virtepollfd:u64, fdkind:u32, underfd:u64) -> Result<(),threei::RetVal> {
+// An fdkind's handlers, in the order they were added.
+type CloseHandlerList<P> = Arc<Vec<Arc<CloseHandlers<P>>>>;
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
 
-    let mut ept = EPOLLTABLE.lock().unwrap();
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    // get this or error out...
-    let epentrynum =  _get_epoll_entrynum_or_error(cageid, virtepollfd)?;
+    /// See [`close_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`close_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`close_virtualfd`].
+    pub fn close_virtualfd(&self, cageid:u64, virtfd:u64) -> Result<(),FdTableError> {
 
-    let myhm = &mut ept.thisepolltable.get_mut(&epentrynum).unwrap().underfdhashmap;
+        let cageid = self._check_cageid(cageid)?;
 
-    assert!(!myhm.contains_key(&fdkind),"Adding duplicate underfd to epollfd");
-        
-    myhm.insert(fdkind,underfd);
+        // Nothing past the end of the row can be open.
+        if virtfd >= FD_PER_PROCESS_MAX {
+            return Err(FdTableError::FdNotOpen);
+        }
 
-    Ok(())
+        // Zero out this entry in place, while holding the row's lock.  (I
+        // used to modify a copy of the row and re-insert it, which lost
+        // anything another thread did to the row in between.)
+        let (rowid, thisoption) = {
+            let mut myfdrow = self._row_mut(cageid);
+            (myfdrow.rowid, myfdrow.take(virtfd))
+        };
+        // The row's lock was dropped at the end of that block, so the close
+        // handler is free to call back into this library.
 
-}
+        match thisoption {
+            Some(entry) => {
+                self._report_unhandled_closes(self._decrement_fdcount(rowid, virtfd, entry));
+                Ok(())
+            }
+            None => Err(FdTableError::FdNotOpen),
+        }
+    }
 
 
-#[doc = include_str!("../docs/epoll_get_underfd_hashmap.md")]
-pub fn epoll_get_underfd_hashmap(cageid:u64, virtepollfd:u64) -> Result<HashMap<u32,u64>,threei::RetVal> {
+    // Like empty_fds_for_exec, this takes everything out under one lock and
+    // calls the close handlers after, so they see a consistent table.
+    /// See [`close_virtualfd_range`].
+    ///
+    /// # Panics
+    ///   See [`close_virtualfd_range`].
+    ///
+    /// # Errors
+    ///   See [`close_virtualfd_range`].
+    pub fn close_virtualfd_range(&self, cageid:u64, first:u64, last:u64, flags:u32) -> Result<(),FdTableError> {
+        if flags & !(CLOSE_RANGE_UNSHARE | CLOSE_RANGE_CLOEXEC) != 0 {
+            return Err(FdTableError::InvalidFlags);
+        }
+        if first > last {
+            return Err(FdTableError::InvalidRange);
+        }
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+        // Like Linux, the fds are closed in the cage's own copy of the table.
+        if flags & CLOSE_RANGE_UNSHARE != 0 {
+            self.unshare_fdtable_for_cage(cageid)?;
+        }
 
-    let ept = EPOLLTABLE.lock().unwrap();
+        let cageid = self._check_cageid(cageid)?;
 
-    // get this or error out...
-    let epentrynum =  _get_epoll_entrynum_or_error(cageid, virtepollfd)?;
+        let mut myfdrow = self._row_mut(cageid);
+        let rowid = myfdrow.rowid;
 
-    Ok(ept.thisepolltable.get(&epentrynum).unwrap().underfdhashmap.clone())
+        // Nothing is open past the end of the table, so I stop there (last is
+        // often !0).
+        let fdrange = first..=cmp::min(last, FD_PER_PROCESS_MAX - 1);
 
-}
+        if flags & CLOSE_RANGE_CLOEXEC != 0 {
+            for virtualfd in fdrange {
+                if let Some(entry) = myfdrow.get_mut(virtualfd) {
+                    entry.should_cloexec = true;
+                }
+            }
+            return Ok(());
+        }
+
+        // In fd order, so the handlers are called in that order too.
+        let mut closevec = Vec::new();
+        for virtualfd in fdrange {
+            if let Some(entry) = myfdrow.take(virtualfd) {
+                closevec.push((virtualfd, entry));
+            }
+        }
 
+        // Need to drop the lock, before calling the handlers.
+        drop(myfdrow);
 
+        let mut unhandled = Vec::new();
+        for (virtualfd, entry) in closevec {
+            unhandled.extend(self._decrement_fdcount(rowid, virtualfd, entry));
+        }
+        self._report_unhandled_closes(unhandled);
 
-#[doc = include_str!("../docs/virtualize_epoll_ctl.md")]
-pub fn virtualize_epoll_ctl(cageid:u64, epfd:u64, op:i32, virtfd:u64, event:epoll_event) -> Result<(),threei::RetVal> {
+        Ok(())
+    }
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+    // Register a series of helpers to be called for close.  Can be called
+    // multiple times to override the older helpers.
+    /// See [`register_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`register_close_handlers`].
+    pub fn register_close_handlers(&self, fdkind:u32, intermediate: fn(FDTableEntry<P>,u64), last: fn(FDTableEntry<P>,u64)) where P: 'static {
+        let closehandler = CloseHandlers {
+            token: self._new_close_handler_token(),
+            intermediate: Box::new(intermediate),
+            last: Box::new(last),
+        };
+        // overwrite whatever is in there (including any added handlers)...
+        self.closehandlertable.lock().unwrap().insert(fdkind, Arc::new(vec![Arc::new(closehandler)]));
+    }
+
+    // The list is copied on write, so a close which is already running the
+    // old list doesn't see the change.
+    /// See [`add_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`add_close_handlers`].
+    #[must_use] // must use the return value if you call it.
+    pub fn add_close_handlers(&self, fdkind:u32, intermediate: CloseHandler<P>, last: CloseHandler<P>) -> CloseHandlerToken {
+        let token = self._new_close_handler_token();
+        let closehandler = CloseHandlers {
+            token,
+            intermediate,
+            last,
+        };
+        let mut closehandlertable = self.closehandlertable.lock().unwrap();
+        Arc::make_mut(closehandlertable.entry(fdkind).or_default()).push(Arc::new(closehandler));
+        token
+    }
 
-    if epfd == virtfd {
-        return Err(threei::Errno::EINVAL as u64);
+    /// See [`remove_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`remove_close_handlers`].
+    #[must_use] // must use the return value if you call it.
+    pub fn remove_close_handlers(&self, token:CloseHandlerToken) -> bool {
+        let mut closehandlertable = self.closehandlertable.lock().unwrap();
+        let Some((&fdkind, closehandlers)) = closehandlertable.iter_mut().find(|(_, closehandlers)| closehandlers.iter().any(|closehandler| closehandler.token == token)) else {
+            return false;
+        };
+        Arc::make_mut(closehandlers).retain(|closehandler| closehandler.token != token);
+        // Leave no empty lists, so the fdkind looks unregistered again.
+        if closehandlers.is_empty() {
+            closehandlertable.remove(&fdkind);
+        }
+        true
     }
 
-    // get this or error out...
-    let epentrynum =  _get_epoll_entrynum_or_error(cageid, epfd)?;
+    fn _new_close_handler_token(&self) -> CloseHandlerToken {
+        CloseHandlerToken(self.nexthandlertoken.fetch_add(1, Ordering::Relaxed))
+    }
 
-    // Okay, I know which table entry, now verify the virtfd...
+    /// See [`register_default_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`register_default_close_handlers`].
+    pub fn register_default_close_handlers(&self, intermediate: CloseHandler<P>, last: CloseHandler<P>) {
+        let closehandler = CloseHandlers {
+            token: self._new_close_handler_token(),
+            intermediate,
+            last,
+        };
+        *self.defaultclosehandlers.lock().unwrap() = Some(Arc::new(vec![Arc::new(closehandler)]));
+    }
 
+    /// See [`set_strict_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`set_strict_close_handlers`].
+    pub fn set_strict_close_handlers(&self, strict:bool) {
+        self.strictclosehandlers.store(strict, Ordering::Relaxed);
+    }
 
-    let virtfdkind:u32;
+    // The handlers for fdkind, or the default ones if it has none.  The
+    // reserved fdkinds (like epoll fds) are mine, so they never get the
+    // default handlers.
+    fn _close_handlers_for(&self, fdkind:u32) -> Option<CloseHandlerList<P>> {
+        let closehandlers = self.closehandlertable.lock().unwrap().get(&fdkind).cloned();
+        if closehandlers.is_some() || fdkind >= FDT_KINDMAX {
+            return closehandlers;
+        }
+        self.defaultclosehandlers.lock().unwrap().clone()
+    }
 
-    if let Some(tableentry) = FDTABLE.get(&cageid).unwrap()[virtfd as usize] {
-        // Right now, I don't support this, so error...
-        if tableentry.fdkind == FDT_KINDEPOLL {
-            // TODO: support EPOLLFDs...
-            return Err(threei::Errno::ENOSYS as u64);
+    // Calls each of the fdkind's handlers in order.  All but the last get a
+    // copy of the entry.
+    // Returns the fdkind if it had no handlers to call, for
+    // _report_unhandled_closes.
+    fn _run_close_handlers(closehandlers:Option<CloseHandlerList<P>>, entry:FDTableEntry<P>, count:u64) -> Option<u32> {
+        let Some(closehandlers) = closehandlers else {
+            // My own fdkinds, like epoll fds, don't need any.
+            return (entry.fdkind < FDT_KINDMAX).then_some(entry.fdkind);
+        };
+        if let Some((lastone, others)) = closehandlers.split_last() {
+            for closehandler in others {
+                closehandler.call(entry.clone(), count);
+            }
+            lastone.call(entry, count);
         }
-        virtfdkind = tableentry.fdkind;
+        None
     }
-    else {
-        // The virtual Fd doesn't exist -- error...
-        return Err(threei::Errno::EBADF as u64);
+
+    // In strict mode, panics if any of the closes had no handlers to call
+    // (fdkinds is what _decrement_fdcount returned for them).  A call which
+    // closes several fds does them all first, and then reports them at once,
+    // so the tables and counts are still consistent if the panic is caught.
+    fn _report_unhandled_closes(&self, fdkinds:impl IntoIterator<Item = u32>) {
+        let mut fdkinds: Vec<u32> = fdkinds.into_iter().collect();
+        if fdkinds.is_empty() || !self.strictclosehandlers.load(Ordering::Relaxed) {
+            return;
+        }
+        fdkinds.sort_unstable();
+        fdkinds.dedup();
+        panic!("closed fds of fdkinds {fdkinds:?}, which have no close handlers");
     }
 
-    let mut eptable = EPOLLTABLE.lock().unwrap();
-//    let userhm = eptable.thisepolltable.get_mut(&epentrynum).unwrap().userhandledhashmap.entry(virtfdkind).or_default();
-    let userhm = &mut eptable.thisepolltable.get_mut(&epentrynum).unwrap().userhandledhashmap;
 
-    match op {
-        EPOLL_CTL_ADD => {
-            let thisuserhm = userhm.entry(virtfdkind).or_default();
-            if thisuserhm.contains_key(&virtfd) {
-                return Err(threei::Errno::EEXIST as u64);
-            }
-            // BUG: Need to check for ELOOP here once I support EPOLLFDs
-            // referencing each other...
-
-            thisuserhm.insert(virtfd, event);
-        },
-        EPOLL_CTL_MOD => {
-            if !userhm.contains_key(&virtfdkind) {
-                return Err(threei::Errno::ENOENT as u64);
-            }
-            let thisuserhm: &mut HashMap<u64, epoll_event> = userhm.get_mut(&virtfdkind).unwrap();
-            if !thisuserhm.contains_key(&virtfd) {
-                return Err(threei::Errno::ENOENT as u64);
-            }
-            thisuserhm.insert(virtfd, event);
-        },
-        EPOLL_CTL_DEL => {
-            if !userhm.contains_key(&virtfdkind) {
-                return Err(threei::Errno::ENOENT as u64);
-            }
-            let thisuserhm: &mut HashMap<u64, epoll_event> = userhm.get_mut(&virtfdkind).unwrap();
-            if !thisuserhm.contains_key(&virtfd) {
-                return Err(threei::Errno::ENOENT as u64);
-            }
-            thisuserhm.remove(&virtfd);
-            // If this was the last entry, delete the key altogether...
-            if thisuserhm.is_empty() {
-                userhm.remove(&virtfdkind);
-            }
-        },
-        _ => {
-            return Err(threei::Errno::EINVAL as u64);
-        },
-    };
-    Ok(())
-}
+    // Helpers to track the count of times each (fdkind,underfd) is used.
+    // rowid and virtualfd are where the entry was.
+    // Returns the fdkind if it had no close handlers to call (see
+    // _report_unhandled_closes).
+    #[doc(hidden)]
+    fn _decrement_fdcount(&self, rowid:u64, virtualfd:u64, entry:FDTableEntry<P>) -> Option<u32> {
 
+        // This fd no longer counts against the total limit.
+        self.totalfds.fetch_sub(1, Ordering::Relaxed);
 
-#[doc = include_str!("../docs/get_virtual_epoll_wait_data.md")]
-pub fn get_virtual_epoll_wait_data(cageid:u64, epfd:u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,threei::RetVal> {
+        // Decrement while holding the entry's lock, so a concurrent
+        // increment or decrement of the same tuple isn't lost.
+        let newcount:u64 = {
+            let mut description = self.fdcount.get_mut(&(entry.fdkind, entry.underfd)).unwrap();
+            description.remove_reference(rowid, virtualfd);
+            self._reference_count(&description)
+        };
+
+        self._call_close_handler(entry, newcount)
+    }
+
+    // Calls the intermediate or last close handler for entry's fdkind, now
+    // that newcount fds refer to its (fdkind,underfd).  Returns the fdkind if
+    // it had none.
+    fn _call_close_handler(&self, entry:FDTableEntry<P>, newcount:u64) -> Option<u32> {
+        let mytuple = (entry.fdkind, entry.underfd);
+
+        // Take the list, and release the lock so I can call it recursively
+        // (the handlers may also add or remove handlers)...
+        let closehandlers = self._close_handlers_for(entry.fdkind);
+
+        if newcount > 0 {
+            Self::_run_close_handlers(closehandlers, entry, newcount)
+        }
+        else{
+            // Remove before calling their close handler in case they do operations
+            // inside the close handler which create / close fds...  Another
+            // thread may have opened the tuple again since I decremented, in
+            // which case the description must stay.
+            self.fdcount.remove_if(&mytuple, |_, description| self._reference_count(description) == 0);
+            Self::_run_close_handlers(closehandlers, entry, 0)
+        }
+    }
+
+    // Helpers to track the count of times each (fdkind,underfd) is used,
+    // and where.  rowid and virtualfd are where the entry is being added.
+    #[doc(hidden)]
+    fn _increment_fdcount(&self, rowid:u64, virtualfd:u64, entry:&FDTableEntry<P>) {
 
-    assert!(FDTABLE.contains_key(&cageid),"Unknown cageid in fdtable access");
+        let mytuple = (entry.fdkind, entry.underfd);
 
-    // get this or error out...
-    let epentrynum =  _get_epoll_entrynum_or_error(cageid, epfd)?;
+        // The first fd for this tuple makes a new description.
+        self.fdcount.entry(mytuple).or_default().references.entry(rowid).or_default().push(virtualfd);
+    }
+
+    // How many fds refer to this description.  Each reference counts once
+    // for every cage which uses its row.  Fds in flight count too.
+    fn _reference_count(&self, description:&FileDescription) -> u64 {
+        description.references.iter()
+            .map(|(rowid, virtualfds)| self.rowusers.get(rowid).map_or(0, |users| (users.len() * virtualfds.len()) as u64))
+            .sum::<u64>() + description.inflight
+    }
 
-    let eptable = EPOLLTABLE.lock().unwrap();
-    Ok(eptable.thisepolltable.get(&epentrynum).unwrap().userhandledhashmap.clone())
+    // Takes count fds from the total limit, or returns ENFILE (and takes
+    // nothing) if there aren't that many left.
+    fn _reserve_total_fds(&self, count:u64) -> Result<(),FdTableError> {
+        let limit = self.totalfdlimit.load(Ordering::Relaxed);
+        self.totalfds.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+            (total + count <= limit).then_some(total + count)
+        }).map(|_| ()).map_err(|_| FdTableError::SystemTableFull)
+    }
 }
 
 
 
-/********************** TESTING HELPER FUNCTION **********************/
+/******************* COPY ON WRITE ROWS *******************/
+
+impl<P: Clone + Send + Sync> FdTables<P> {
+
+    // A new, empty row which only cageid uses.
+    fn _new_row(&self, cageid:u64) -> Arc<FdRow<P>> {
+        let rowid = self.nextrowid.fetch_add(1, Ordering::Relaxed);
+        self.rowusers.insert(rowid, vec![cageid]);
+        Arc::new(FdRow::new(rowid))
+    }
+
+    // A new row which only cageid uses, with the fds in row that keep is true
+    // for.  They get references, but the caller decides if they are more
+    // open fds.
+    fn _copy_row(&self, cageid:u64, row:&FdRow<P>, keep:impl Fn(&FDTableEntry<P>) -> bool) -> Arc<FdRow<P>> {
+        let mut rowcopy = self._new_row(cageid);
+        let newrow = Arc::get_mut(&mut rowcopy).unwrap();
+        for (virtualfd, entry) in row.iter().enumerate() {
+            if let Some(entry) = entry.as_ref().filter(|entry| keep(entry)) {
+                self._increment_fdcount(newrow.rowid, virtualfd as u64, entry);
+                newrow.insert(virtualfd as u64, entry.clone());
+            }
+        }
+        rowcopy
+    }
+
+    // Locks cageid's row to change it.  If a forked cage still uses the row,
+    // cageid gets its own copy first, so the other cage doesn't see the
+    // change.  The copy has the same fds, so nothing is closed.
+    fn _row_mut(&self, cageid:u64) -> RowGuard<'_, P> {
+        let mut myfdrow = self.fdtable.get_mut(&cageid).unwrap();
+        if Arc::get_mut(&mut myfdrow).is_none() {
+            let rowcopy = self._copy_row(cageid, &myfdrow, |_| true);
+            let oldrow = std::mem::replace(&mut *myfdrow, rowcopy);
+            // This is fine while holding the lock, since it closes nothing.
+            self._leave_row(cageid, &oldrow, |_| true);
+        }
+        RowGuard(myfdrow)
+    }
+
+    // cageid no longer uses oldrow.  The caller already took it out of the
+    // fdtable, or gave the cage a copy of it with the fds keep is true for.
+    // The rest are closed for this cage, so unless keep is true for all of
+    // them, call this after dropping the cage's row lock.
+    fn _leave_row(&self, cageid:u64, oldrow:&FdRow<P>, keep:impl Fn(&FDTableEntry<P>) -> bool) {
+        let rowid = oldrow.rowid;
+        let lastuser = {
+            let mut users = self.rowusers.get_mut(&rowid).unwrap();
+            let index = users.iter().position(|&user| user == cageid).unwrap();
+            users.swap_remove(index);
+            users.is_empty()
+        };
+        // The references to the row go away with its last user.  (Nothing
+        // can start using it after that, since no cage has it.)
+        if lastuser {
+            self.rowusers.remove(&rowid);
+        }
+
+        // The cage's fds in the row all stopped counting just now, but they
+        // are closed one at a time.  So each close counts the ones after it.
+        let mut unclosed: HashMap<(u32,u64), u64> = HashMap::new();
+        for entry in oldrow.iter().flatten().filter(|entry| !keep(entry)) {
+            *unclosed.entry((entry.fdkind, entry.underfd)).or_default() += 1;
+        }
+
+        let mut unhandled = Vec::new();
+        for (virtualfd, entry) in oldrow.iter().enumerate() {
+            let Some(entry) = entry else {
+                continue;
+            };
+            let virtualfd = virtualfd as u64;
+            let mytuple = (entry.fdkind, entry.underfd);
+            if keep(entry) {
+                // It's in the cage's new row, so it's still open.
+                if lastuser {
+                    self.fdcount.get_mut(&mytuple).unwrap().remove_reference(rowid, virtualfd);
+                }
+                continue;
+            }
+
+            let stillopen = {
+                let count = unclosed.get_mut(&mytuple).unwrap();
+                *count -= 1;
+                *count
+            };
+            self.totalfds.fetch_sub(1, Ordering::Relaxed);
+            let newcount = {
+                let mut description = self.fdcount.get_mut(&mytuple).unwrap();
+                if lastuser {
+                    description.remove_reference(rowid, virtualfd);
+                }
+                self._reference_count(&description) + stillopen
+            };
+            unhandled.extend(self._call_close_handler(entry.clone(), newcount));
+        }
+        self._report_unhandled_closes(unhandled);
+    }
+}
+
+
+
+/***************   Code for handling select() ****************/
+
+use libc::fd_set;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::cmp;
+use std::mem;
+
+// Helper to get an empty fd_set.  Helper function to isolate unsafe code,
+// etc.
+#[doc(hidden)]
+#[must_use] // must use the return value if you call it.
+pub fn _init_fd_set() -> fd_set {
+    let raw_fd_set:fd_set;
+    unsafe {
+        let mut this_fd_set = mem::MaybeUninit::<libc::fd_set>::uninit();
+        libc::FD_ZERO(this_fd_set.as_mut_ptr());
+        raw_fd_set = this_fd_set.assume_init();
+    }
+    raw_fd_set
+}
+
+#[doc(hidden)]
+pub fn _fd_set(fd:u64, thisfdset:&mut fd_set) {
+    unsafe{libc::FD_SET(fd as i32,thisfdset)}
+}
+
+#[doc(hidden)]
+#[must_use] // must use the return value if you call it.
+pub fn _fd_isset(fd:u64, thisfdset:&fd_set) -> bool {
+    unsafe{libc::FD_ISSET(fd as i32,thisfdset)}
+}
+
+
+
+// This is a helper that just does a single type (r/w/e) and returns:
+//    bithashmap: HashMap<fdkind, (nfds, fd_set)>
+//    unhandledhashmap: HashMap<fdkind, HashSet<FDTableEntry<P>>>
+//    mappingtable: HashMap<FDTableEntry<P>, virt_fd>
+//
+// With this we trivially build the whole function...
+
+impl<P: Clone + Send + Sync + Eq + Hash> FdTables<P> {
+
+    // helper to call before calling select beneath you.  Translates your virtfds
+    // into a bitmask you may use for select.
+    // See: https://man7.org/linux/man-pages/man2/select.2.html for details /
+    // corner cases about the arguments.
+    //
+
+    // I hate doing this, but don't know how to make this interface better...
+    /// See [`get_bitmask_for_select`].
+    ///
+    /// # Panics
+    ///   See [`get_bitmask_for_select`].
+    ///
+    /// # Errors
+    ///   See [`get_bitmask_for_select`].
+    #[allow(clippy::type_complexity)]
+    #[allow(clippy::implicit_hasher)]
+    pub fn get_bitmask_for_select(&self, cageid:u64, nfds:u64, bits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry<P>>>, HashMap<(u32,u64),u64>),FdTableError> {
+
+        if nfds >= FD_PER_PROCESS_MAX {
+            return Err(FdTableError::NfdsTooLarge);
+        }
+
+        let cageid = self._check_cageid(cageid)?;
+
+        // The three things I will return...
+        let mut retbittable:HashMap<u32,(u64,fd_set)> = HashMap::new();
+        let mut retunparsedtable:HashMap<u32,HashSet<FDTableEntry<P>>> = HashMap::new();
+        let mut mappingtable:HashMap<(u32,u64),u64> = HashMap::new();
+
+        // If we were asked to do this on nothing, return empty mappings...
+        if bits.is_none() {
+            return Ok((retbittable, retunparsedtable, mappingtable));
+        }
+
+        let infdset = bits.unwrap();
+
+        // dashmaps are lockless, but usually I would grab a lock on the fdtable
+        // here...
+        let binding = self.fdtable.get(&cageid).unwrap();
+        let myfdrow = binding.value();
+
+        // Clippy is somehow missing how the virtualfd is being used throughout
+        // here.  It's not just a range value
+        #[allow(clippy::needless_range_loop)]
+        // iterate through the set bits...
+        for bit in 0..nfds as usize {
+            let pos = bit as u64;
+            if _fd_isset(pos,&infdset) {
+                if let Some(entry) = &myfdrow[bit] {
+
+                    // I like to do the shorter case first rather than having
+                    // it later.
+                    #[allow(clippy::if_not_else)]
+                    // Which return set do I go in?
+                    if !fdkinds.contains(&entry.fdkind) {
+                        // Is unparsed...  Clippy's suggestion to insert if missing
+                        retunparsedtable.entry(entry.fdkind).or_default();
+                        retunparsedtable.get_mut(&entry.fdkind).unwrap().insert(entry.clone());
+                        // and update the mappingtable to have the bit from the
+                        // original fd...
+                        mappingtable.insert((entry.fdkind,entry.underfd),pos);
+                    }
+                    else {
+
+                        let startingnfds;
+                        let mut startingfdset;
+
+                        // Either initialize it or use what exists
+                        if retbittable.contains_key(&entry.fdkind) {
+                            (startingnfds, startingfdset) = *retbittable.get(&entry.fdkind).unwrap();
+                        }
+                        else{
+                            startingnfds = 1;
+                            // I don't init this above because a fd_set is a large
+                            // data structure and would be costly.
+                            startingfdset = _init_fd_set();
+                        }
+
+                        // Update the table and the nfds
+                        _fd_set(entry.underfd,&mut startingfdset);
+                        let newnfds = cmp::max(startingnfds, entry.underfd+1);
+
+                        // and update the mappingtable to have the bit from the
+                        // original fd...
+                        mappingtable.insert((entry.fdkind,entry.underfd),pos);
+
+                        // insert the item
+                        retbittable.insert(entry.fdkind,(newnfds,startingfdset));
+                    }
+                }
+                else {
+                    return Err(FdTableError::BadFd);
+                }
+            }
+        }
+        Ok((retbittable, retunparsedtable, mappingtable))
+
+    }
+
+
+    /// See [`prepare_bitmasks_for_select`].
+    ///
+    /// # Panics
+    ///   See [`prepare_bitmasks_for_select`].
+    ///
+    /// # Errors
+    ///   See [`prepare_bitmasks_for_select`].
+    #[allow(clippy::type_complexity)]
+    #[allow(clippy::implicit_hasher)]
+    pub fn prepare_bitmasks_for_select(&self, cageid:u64, nfds:u64, rbits:Option<fd_set>, wbits:Option<fd_set>, ebits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry<P>>>;3], HashMap<(u32,u64),u64>),FdTableError> {
+        // This is a pretty simple function.  Calls get_bitmask_for_select
+        // repeatedly and combines the results...
+        // [HashSet<(u64,u64)>;3]
+
+        // return the error, if need be
+        let rresult = self.get_bitmask_for_select(cageid, nfds, rbits, fdkinds)?;
+        let wresult = self.get_bitmask_for_select(cageid, nfds, wbits, fdkinds)?;
+        let eresult = self.get_bitmask_for_select(cageid, nfds, ebits, fdkinds)?;
+
+        let mut mappingtable = rresult.2;
+        mappingtable.extend(wresult.2);
+        mappingtable.extend(eresult.2);
+
+        Ok(([rresult.0,wresult.0,eresult.0],[rresult.1,wresult.1,eresult.1],mappingtable))
+
+    }
+}
+
+
+// helper to call after calling select beneath you.  returns the fd_set you
+// need for your return from a select call and the number of unique flags
+// set...
+
+// I given them the hashmap, so don't need flexibility in what they return...
+#[allow(clippy::implicit_hasher)]
+#[must_use] // must use the return value if you call it.
+#[doc = include_str!("../docs/get_one_virtual_bitmask_from_select_result.md")]
+pub fn get_one_virtual_bitmask_from_select_result(fdkind:u32, nfds:u64, bits:Option<fd_set>, unprocessedset:HashSet<u64>, startingbits:Option<fd_set>,mappingtable:&HashMap<(u32,u64),u64>) -> (u64, Option<fd_set>) {
+
+    // Note, I don't need the cage_id here because I have the mappingtable...
+
+    assert!(nfds < FD_PER_PROCESS_MAX,"This shouldn't be possible because we shouldn't have returned this previously");
+
+    let mut flagsset = 0;
+
+    if bits.is_none() && unprocessedset.is_empty() {
+        return (flagsset,None);
+    }
+
+    // I probably should pass a reference to startingbits to avoid copying the
+    // bit structure...
+    let mut retbits = match startingbits {
+        Some(val) => val,
+        None => _init_fd_set(),
+    };
+
+    if let Some(inset) = bits {
+        for bit in 0..nfds as usize {
+            let pos = bit as u64;
+            if _fd_isset(pos,&inset)&& !_fd_isset(*mappingtable.get(&(fdkind,pos)).unwrap(),&retbits) {
+                flagsset+=1;
+                _fd_set(*mappingtable.get(&(fdkind,pos)).unwrap(),&mut retbits);
+            }
+        }
+    }
+    for virtfd in unprocessedset {
+        if !_fd_isset(virtfd,&retbits) {
+            flagsset+=1;
+            _fd_set(virtfd,&mut retbits);
+        }
+    }
+
+    (flagsset,Some(retbits))
+
+}
+
+
+
+/********************** POLL SPECIFIC FUNCTIONS **********************/
+
+impl<P: Clone + Send + Sync + Eq + Hash + Default> FdTables<P> {
+
+    // helper to call before calling poll beneath you.  replaces the fds in
+    // the poll struct with virtual versions and returns the items you need
+    // to check yourself...
+    /// See [`convert_virtualfds_for_poll`].
+    ///
+    /// # Panics
+    ///   See [`convert_virtualfds_for_poll`].
+    #[allow(clippy::implicit_hasher)]
+    #[allow(clippy::type_complexity)]
+    #[must_use] // must use the return value if you call it.
+    pub fn convert_virtualfds_for_poll(&self, cageid:u64, virtualfds:HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry<P>)>>, HashMap<(u32,u64),u64>) {
+
+        // With the Error policy, an unknown cage has no fds, so they all
+        // come back as invalid...
+        let thefdrow = self._check_cageid(cageid).ok().map(|cageid| Arc::clone(&self.fdtable.get(&cageid).unwrap()));
+        let mut mappingtable:HashMap<(u32,u64),u64> = HashMap::new();
+        let mut rethashmap:HashMap<u32,HashSet<(u64,FDTableEntry<P>)>> = HashMap::new();
+
+
+        // BUG?: I'm ignoring the fact that virtualfds can show up multiple times.
+        // I'm not sure this actually matters, but I didn't think hard about it.
+        for virtfd in virtualfds {
+            if let Some(entry) = thefdrow.as_ref().and_then(|row| row[virtfd as usize].as_ref()) {
+                // Insert an empty HashSet, if needed
+                rethashmap.entry(entry.fdkind).or_default();
+                mappingtable.entry((entry.fdkind,entry.underfd)).or_default();
+
+                rethashmap.get_mut(&entry.fdkind).unwrap().insert((virtfd,entry.clone()));
+                mappingtable.insert((entry.fdkind,entry.underfd), virtfd);
+            }
+            else {
+                let myentry = FDTableEntry {
+                    fdkind:FDT_INVALID_FD,
+                    underfd:virtfd,
+                    should_cloexec:false,
+                    perfdinfo:P::default(),
+                };
+
+                // Insert an empty HashSet, if needed
+                rethashmap.entry(FDT_INVALID_FD).or_default();
+                mappingtable.entry((FDT_INVALID_FD,virtfd)).or_default();
+
+                rethashmap.get_mut(&FDT_INVALID_FD).unwrap().insert((virtfd,myentry));
+                // Add this because they need to handle it if POLLNVAL is set.
+                // An exception should not be raised!!!
+
+                // I will add this to the mapping table, because I do think they
+                // may want to raise an exception, etc. based upon this and signal
+                // back.  I am setting the underfd to be the virtfd, so I can
+                // reverse this process, if multiple entries like this occur.
+                mappingtable.insert((FDT_INVALID_FD,virtfd), virtfd);
+            }
+        }
+
+        (rethashmap, mappingtable)
+    }
+}
+
+
+
+// helper to call after calling poll.  replaces the fds in the vector
+// with virtual ones...
+#[doc = include_str!("../docs/convert_poll_result_back_to_virtual.md")]
+// I give them the hashmap, so don't need flexibility in what they return...
+#[allow(clippy::implicit_hasher)]
+#[must_use] // must use the return value if you call it.
+pub fn convert_poll_result_back_to_virtual(fdkind:u32,underfd:u64, mappingtable:&HashMap<(u32,u64),u64>) -> Option<u64> {
+
+    // I don't care what cage was used, and don't need to lock anything...
+    // I have the mappingtable!
+
+    // Should this even be a function?
+    mappingtable.get(&(fdkind,underfd)).copied()
+}
+
+
+
+/********************** EPOLL SPECIFIC FUNCTIONS **********************/
+
+
+// Supporting epollfds is done by a fdkind which is not set by the user.
+// There are a few complexities here:
+// 1) an epollfd gets a virtual file descriptor
+// 2) a epollfd can point to any number of other fds of different kinds
+// 3) an epollfd can point to epollfds, which can point to other epollfds, etc.
+//    and possibly cause a loop to occur (which is an error)
+//
+// My thinking is this is handled as similarly to poll as possible.  We push
+// off the problem of understanding what the event types are to the implementer
+// of the library.
+//
+// In my view, epoll_wait() is quite simple to support.  One basically just
+// keeps a list of virtual fds for this epollfd and their corresponding event
+// types, which they may need to poll themselves.  After this, they handle the
+// call.
+//
+// epoll_ctl is complex, but really has the same fundamental problem as
+// epoll_create: the epollfd.
+//
+// I'll create a new fdkind for epoll.  When epoll_create is called, the
+// caller can decide which fdkinds need to be passed down to the underlying
+// epoll_create call(s).  Similarly, when epoll_ctl is called, one either
+// handles the call internally or uses the underfd for the fdkind...
+//
+// Interestingly, this actually would be just as easy to build on top of the
+// fdtables library as into it.
+//
+// Each epollfd will have some virtual fds associated with it.  Each of those
+// will have an event mask.  So I'll have a mutex around an EPollTable struct.
+// This contains the next available entry and an epollhashmap<virtfd, event>.
+// I use a hashmap here to better support removing and modifying items.
+
+// Note, I'm defining a bunch of symbols myself because libc doesn't import
+// them on systems that don't support epoll and I want to be able to build
+// the code anywhere.  See commonconstants.rs for more info.
+
+
+// Okay, so the basic structure is like this:
+// 1) epoll_create_helper sets up an epollfd.  You will need to have a unique
+// underfd for each fdkind below you, if you want to call down.
+// 2) my API should track all of the fdkinds where there isn't an underlying
+// epollfd
+// 3) epoll_ctl / epoll_wait will work on whichever is appropriate
+//
+// A hashmap of:
+//  HashMap<fdkind,underepollfd> for tracking how to call down.
+//  HashMap<fdkind,HashMap<virtfd,epoll_event>> seems to make the most sense for the other
+//      descriptors.
+
+
+// a structure that exists for each epoll descriptor to track the underfd(s)
+// and parts the user will handle
+#[derive(Clone, Debug, Default)]
+struct EPollDescriptorInfo {
+    // I didn't combine thewe two hashmaps into one because they are used
+    // separately and the resulting value type would be too messy...
+
+    underfdhashmap: HashMap<u32,u64>, // The underfd for a specific fdkind.
+                                      // Used only when an epoll call will
+                                      // call down beneath it.
+    userhandledhashmap: HashMap<u32,HashMap<u64,epoll_event>>,
+                                      // This has all of the things the user
+                                      // will virtualize and handle.  The key
+                                      // is the fdkind.
+}
+
+// TODO: I don't clean up this table yet.  I probably should when the last
+// reference to a fd is closed, but this bookkeeping seems excessive at this
+// time...
+#[derive(Clone, Debug)]
+struct EPollTable {
+    highestneverusedentry: u64, // Never resets (even after close).  Used to
+                                // let us quickly get an unused entry
+    thisepolltable: HashMap<u64,EPollDescriptorInfo>,
+}
+
+impl<P: Clone + Send + Sync> FdTables<P> {
+
+    fn _get_epoll_entrynum_or_error(&self, cageid:u64, epfd:u64) -> Result<u64,FdTableError> {
+        // Is the epfd ok?
+        match &self.fdtable.get(&cageid).unwrap()[epfd as usize] {
+            None => {
+                Err(FdTableError::BadFd)
+            },
+            Some(tableentry) => {
+                // You must call this on an epoll fd
+                if tableentry.fdkind == FDT_KINDEPOLL {
+                    Ok(tableentry.underfd)
+                }
+                else {
+                    Err(FdTableError::NotEpollFd)
+                }
+            },
+        }
+    }
+
+
+    /// See [`epoll_create_empty`].
+    ///
+    /// # Panics
+    ///   See [`epoll_create_empty`].
+    ///
+    /// # Errors
+    ///   See [`epoll_create_empty`].
+    pub fn epoll_create_empty(&self, cageid:u64, should_cloexec:bool) -> Result<u64,FdTableError>
+    where
+        P: Default,
+    {
+
+        // Set up the entry first, so it is there if an open handler uses the
+        // new epoll fd.  The lock is dropped before the fd is allocated, since
+        // that calls the open handlers (which may call the epoll functions).
+        let mut ept = self.epolltable.lock().unwrap();
+        let newentrynum = ept.highestneverusedentry;
+        ept.highestneverusedentry+=1;
+        ept.thisepolltable.entry(newentrynum).or_default();
+        drop(ept);
+
+        // return the same errno (EMFile), if we get one.  No fd refers to
+        // the entry then, so it just goes away.
+        let result = self.get_unused_virtual_fd(cageid, FDT_KINDEPOLL, newentrynum, should_cloexec, P::default());
+        if result.is_err() {
+            self.epolltable.lock().unwrap().thisepolltable.remove(&newentrynum);
+        }
+        result
+
+    }
+
+    /// See [`epoll_add_underfd`].
+    ///
+    /// # Panics
+    ///   See [`epoll_add_underfd`].
+    ///
+    /// # Errors
+    ///   See [`epoll_add_underfd`].
+    pub fn epoll_add_underfd(&self, cageid:u64, virtepollfd:u64, fdkind:u32, underfd:u64) -> Result<(),FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        let mut ept = self.epolltable.lock().unwrap();
+
+        // get this or error out...
+        let epentrynum =  self._get_epoll_entrynum_or_error(cageid, virtepollfd)?;
+
+        let myhm = &mut ept.thisepolltable.get_mut(&epentrynum).unwrap().underfdhashmap;
+
+        assert!(!myhm.contains_key(&fdkind),"Adding duplicate underfd to epollfd");
+
+        myhm.insert(fdkind,underfd);
+
+        Ok(())
+
+    }
+
+
+    /// See [`epoll_get_underfd_hashmap`].
+    ///
+    /// # Panics
+    ///   See [`epoll_get_underfd_hashmap`].
+    ///
+    /// # Errors
+    ///   See [`epoll_get_underfd_hashmap`].
+    pub fn epoll_get_underfd_hashmap(&self, cageid:u64, virtepollfd:u64) -> Result<HashMap<u32,u64>,FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        let ept = self.epolltable.lock().unwrap();
+
+        // get this or error out...
+        let epentrynum =  self._get_epoll_entrynum_or_error(cageid, virtepollfd)?;
+
+        Ok(ept.thisepolltable.get(&epentrynum).unwrap().underfdhashmap.clone())
+
+    }
+
+
+
+    /// See [`virtualize_epoll_ctl`].
+    ///
+    /// # Panics
+    ///   See [`virtualize_epoll_ctl`].
+    ///
+    /// # Errors
+    ///   See [`virtualize_epoll_ctl`].
+    pub fn virtualize_epoll_ctl(&self, cageid:u64, epfd:u64, op:i32, virtfd:u64, event:epoll_event) -> Result<(),FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        if epfd == virtfd {
+            return Err(FdTableError::EpollTargetIsSelf);
+        }
+
+        // get this or error out...
+        let epentrynum =  self._get_epoll_entrynum_or_error(cageid, epfd)?;
+
+        // Okay, I know which table entry, now verify the virtfd...
+
+
+        let virtfdkind:u32;
+
+        if let Some(tableentry) = &self.fdtable.get(&cageid).unwrap()[virtfd as usize] {
+            // Right now, I don't support this, so error...
+            if tableentry.fdkind == FDT_KINDEPOLL {
+                // TODO: support EPOLLFDs...
+                return Err(FdTableError::NestedEpollUnsupported);
+            }
+            virtfdkind = tableentry.fdkind;
+        }
+        else {
+            // The virtual Fd doesn't exist -- error...
+            return Err(FdTableError::BadFd);
+        }
+
+        let mut eptable = self.epolltable.lock().unwrap();
+//        let userhm = eptable.thisepolltable.get_mut(&epentrynum).unwrap().userhandledhashmap.entry(virtfdkind).or_default();
+        let userhm = &mut eptable.thisepolltable.get_mut(&epentrynum).unwrap().userhandledhashmap;
+
+        match op {
+            EPOLL_CTL_ADD => {
+                let thisuserhm = userhm.entry(virtfdkind).or_default();
+                if thisuserhm.contains_key(&virtfd) {
+                    return Err(FdTableError::EpollEntryExists);
+                }
+                // BUG: Need to check for ELOOP here once I support EPOLLFDs
+                // referencing each other...
+
+                thisuserhm.insert(virtfd, event);
+            },
+            EPOLL_CTL_MOD => {
+                if !userhm.contains_key(&virtfdkind) {
+                    return Err(FdTableError::EpollEntryMissing);
+                }
+                let thisuserhm: &mut HashMap<u64, epoll_event> = userhm.get_mut(&virtfdkind).unwrap();
+                if !thisuserhm.contains_key(&virtfd) {
+                    return Err(FdTableError::EpollEntryMissing);
+                }
+                thisuserhm.insert(virtfd, event);
+            },
+            EPOLL_CTL_DEL => {
+                if !userhm.contains_key(&virtfdkind) {
+                    return Err(FdTableError::EpollEntryMissing);
+                }
+                let thisuserhm: &mut HashMap<u64, epoll_event> = userhm.get_mut(&virtfdkind).unwrap();
+                if !thisuserhm.contains_key(&virtfd) {
+                    return Err(FdTableError::EpollEntryMissing);
+                }
+                thisuserhm.remove(&virtfd);
+                // If this was the last entry, delete the key altogether...
+                if thisuserhm.is_empty() {
+                    userhm.remove(&virtfdkind);
+                }
+            },
+            _ => {
+                return Err(FdTableError::InvalidEpollOp);
+            },
+        }
+        Ok(())
+    }
+
+
+    /// See [`get_virtual_epoll_wait_data`].
+    ///
+    /// # Panics
+    ///   See [`get_virtual_epoll_wait_data`].
+    ///
+    /// # Errors
+    ///   See [`get_virtual_epoll_wait_data`].
+    pub fn get_virtual_epoll_wait_data(&self, cageid:u64, epfd:u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,FdTableError> {
+
+        let cageid = self._check_cageid(cageid)?;
+
+        // get this or error out...
+        let epentrynum =  self._get_epoll_entrynum_or_error(cageid, epfd)?;
+
+        let eptable = self.epolltable.lock().unwrap();
+        Ok(eptable.thisepolltable.get(&epentrynum).unwrap().userhandledhashmap.clone())
+    }
+}
+
+
+
+/********************** GLOBAL INSTANCE FUNCTIONS **********************/
+
+// These are the functions most callers use.  Each one just calls the method
+// of the same name on the global FdTables instance.
+
+#[doc = include_str!("../docs/init_empty_cage.md")]
+pub fn init_empty_cage(cageid: u64) {
+    GLOBALFDTABLES.init_empty_cage(cageid);
+}
+
+#[doc = include_str!("../docs/translate_virtual_fd.md")]
+pub fn translate_virtual_fd(cageid: u64, virtualfd: u64) -> Result<FDTableEntry, FdTableError> {
+    GLOBALFDTABLES.translate_virtual_fd(cageid, virtualfd)
+}
+
+#[doc = include_str!("../docs/get_unused_virtual_fd.md")]
+pub fn get_unused_virtual_fd(
+    cageid: u64,
+    fdkind: u32,
+    underfd: u64,
+    should_cloexec: bool,
+    perfdinfo: u64,
+) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_unused_virtual_fd(cageid, fdkind, underfd, should_cloexec, perfdinfo)
+}
+
+#[doc = include_str!("../docs/get_unused_virtual_fd_from.md")]
+pub fn get_unused_virtual_fd_from(
+    cageid: u64,
+    minfd: u64,
+    fdkind: u32,
+    underfd: u64,
+    should_cloexec: bool,
+    perfdinfo: u64,
+) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_unused_virtual_fd_from(cageid, minfd, fdkind, underfd, should_cloexec, perfdinfo)
+}
+
+#[doc = include_str!("../docs/dup_virtualfd.md")]
+pub fn dup_virtualfd(cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.dup_virtualfd(cageid, oldvirtualfd)
+}
+
+#[doc = include_str!("../docs/dup2_virtualfd.md")]
+pub fn dup2_virtualfd(cageid: u64, oldvirtualfd: u64, newvirtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.dup2_virtualfd(cageid, oldvirtualfd, newvirtualfd)
+}
+
+#[doc = include_str!("../docs/dup3_virtualfd.md")]
+pub fn dup3_virtualfd(cageid: u64, oldvirtualfd: u64, newvirtualfd: u64, should_cloexec: bool) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.dup3_virtualfd(cageid, oldvirtualfd, newvirtualfd, should_cloexec)
+}
+
+#[doc = include_str!("../docs/get_specific_virtual_fd.md")]
+pub fn get_specific_virtual_fd(
+    cageid: u64,
+    requested_virtualfd: u64,
+    fdkind: u32,
+    underfd: u64,
+    should_cloexec: bool,
+    perfdinfo: u64,
+) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.get_specific_virtual_fd(cageid, requested_virtualfd, fdkind, underfd, should_cloexec, perfdinfo)
+}
+
+#[doc = include_str!("../docs/transfer_virtualfds.md")]
+pub fn transfer_virtualfds(srccageid: u64, srcvirtualfds: &[u64], dstcageid: u64, should_cloexec: bool) -> Result<Vec<u64>, FdTableError> {
+    GLOBALFDTABLES.transfer_virtualfds(srccageid, srcvirtualfds, dstcageid, should_cloexec)
+}
+
+#[doc = include_str!("../docs/getfd_virtualfd.md")]
+pub fn getfd_virtualfd(requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.getfd_virtualfd(requestercageid, targetcageid, targetvirtualfd)
+}
+
+#[doc = include_str!("../docs/set_cloexec.md")]
+pub fn set_cloexec(cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_cloexec(cageid, virtualfd, is_cloexec)
+}
+
+#[doc = include_str!("../docs/set_perfdinfo.md")]
+pub fn set_perfdinfo(cageid: u64, virtualfd: u64, perfdinfo: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_perfdinfo(cageid, virtualfd, perfdinfo)
+}
+
+#[doc = include_str!("../docs/update_virtualfd_entry.md")]
+pub fn update_virtualfd_entry<R>(cageid: u64, virtualfd: u64, func: impl FnOnce(&mut FDTableEntry) -> R) -> Result<R, FdTableError> {
+    GLOBALFDTABLES.update_virtualfd_entry(cageid, virtualfd, func)
+}
+
+#[doc = include_str!("../docs/compare_and_swap_virtualfd_entry.md")]
+pub fn compare_and_swap_virtualfd_entry(cageid: u64, virtualfd: u64, expected: &FDTableEntry, new: FDTableEntry) -> Result<bool, FdTableError> {
+    GLOBALFDTABLES.compare_and_swap_virtualfd_entry(cageid, virtualfd, expected, new)
+}
+
+#[doc = include_str!("../docs/get_status_flags.md")]
+pub fn get_status_flags(cageid: u64, virtualfd: u64) -> Result<i32, FdTableError> {
+    GLOBALFDTABLES.get_status_flags(cageid, virtualfd)
+}
+
+#[doc = include_str!("../docs/set_status_flags.md")]
+pub fn set_status_flags(cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_status_flags(cageid, virtualfd, statusflags)
+}
+
+#[doc = include_str!("../docs/get_offset.md")]
+pub fn get_offset(cageid: u64, virtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_offset(cageid, virtualfd)
+}
+
+#[doc = include_str!("../docs/set_offset.md")]
+pub fn set_offset(cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_offset(cageid, virtualfd, offset)
+}
+
+#[doc = include_str!("../docs/get_fd_limit.md")]
+pub fn get_fd_limit(cageid: u64) -> Result<(u64, u64), FdTableError> {
+    GLOBALFDTABLES.get_fd_limit(cageid)
+}
+
+#[doc = include_str!("../docs/set_fd_limit.md")]
+pub fn set_fd_limit(cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_fd_limit(cageid, soft, hard)
+}
+
+#[doc = include_str!("../docs/set_total_fd_limit.md")]
+pub fn set_total_fd_limit(limit: u64) {
+    GLOBALFDTABLES.set_total_fd_limit(limit);
+}
+
+#[doc = include_str!("../docs/get_total_fd_usage.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_total_fd_usage() -> (u64, u64) {
+    GLOBALFDTABLES.get_total_fd_usage()
+}
+
+#[doc = include_str!("../docs/copy_fdtable_for_cage.md")]
+pub fn copy_fdtable_for_cage(srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.copy_fdtable_for_cage(srccageid, newcageid)
+}
+
+#[doc = include_str!("../docs/share_fdtable_for_cage.md")]
+pub fn share_fdtable_for_cage(srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.share_fdtable_for_cage(srccageid, newcageid)
+}
+
+#[doc = include_str!("../docs/unshare_fdtable_for_cage.md")]
+pub fn unshare_fdtable_for_cage(cageid: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.unshare_fdtable_for_cage(cageid)
+}
+
+#[doc = include_str!("../docs/remove_cage_from_fdtable.md")]
+pub fn remove_cage_from_fdtable(cageid: u64) {
+    GLOBALFDTABLES.remove_cage_from_fdtable(cageid);
+}
+
+#[doc = include_str!("../docs/empty_fds_for_exec.md")]
+pub fn empty_fds_for_exec(cageid: u64) {
+    GLOBALFDTABLES.empty_fds_for_exec(cageid);
+}
+
+#[doc = include_str!("../docs/return_fdtable_copy.md")]
+#[must_use] // must use the return value if you call it.
+pub fn return_fdtable_copy(cageid: u64) -> HashMap<u64, FDTableEntry> {
+    GLOBALFDTABLES.return_fdtable_copy(cageid)
+}
+
+#[doc = include_str!("../docs/for_each_virtualfd.md")]
+pub fn for_each_virtualfd(cageid: u64, func: impl FnMut(u64, &FDTableEntry)) {
+    GLOBALFDTABLES.for_each_virtualfd(cageid, func);
+}
+
+#[doc = include_str!("../docs/for_each_virtualfd_in_range.md")]
+pub fn for_each_virtualfd_in_range(cageid: u64, first: u64, last: u64, fdkind: Option<u32>, func: impl FnMut(u64, &FDTableEntry)) {
+    GLOBALFDTABLES.for_each_virtualfd_in_range(cageid, first, last, fdkind, func);
+}
+
+#[doc = include_str!("../docs/get_cageids.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_cageids() -> Vec<u64> {
+    GLOBALFDTABLES.get_cageids()
+}
+
+#[doc = include_str!("../docs/get_fd_count.md")]
+pub fn get_fd_count(cageid: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_fd_count(cageid)
+}
+
+#[doc = include_str!("../docs/get_fd_references.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_fd_references(fdkind: u32, underfd: u64) -> Vec<(u64, u64)> {
+    GLOBALFDTABLES.get_fd_references(fdkind, underfd)
+}
+
+#[doc = include_str!("../docs/get_fd_reference_count.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_fd_reference_count(fdkind: u32, underfd: u64) -> u64 {
+    GLOBALFDTABLES.get_fd_reference_count(fdkind, underfd)
+}
+
+#[doc = include_str!("../docs/get_fd_reference_counts.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_fd_reference_counts(fdkind: u32) -> HashMap<u64, u64> {
+    GLOBALFDTABLES.get_fd_reference_counts(fdkind)
+}
+
+#[doc = include_str!("../docs/get_virtualfds_of_kind.md")]
+pub fn get_virtualfds_of_kind(cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError> {
+    GLOBALFDTABLES.get_virtualfds_of_kind(cageid, fdkind)
+}
+
+#[doc = include_str!("../docs/set_getfd_policy.md")]
+pub fn set_getfd_policy(policy:GetfdPolicy) {
+    GLOBALFDTABLES.set_getfd_policy(policy);
+}
+
+#[doc = include_str!("../docs/set_getfd_audit.md")]
+pub fn set_getfd_audit(audit:GetfdAudit) {
+    GLOBALFDTABLES.set_getfd_audit(audit);
+}
+
+#[doc = include_str!("../docs/set_unknown_cage_policy.md")]
+pub fn set_unknown_cage_policy(policy:UnknownCagePolicy) {
+    GLOBALFDTABLES.set_unknown_cage_policy(policy);
+}
+
+#[doc = include_str!("../docs/unknown_cage_policy.md")]
+#[must_use] // must use the return value if you call it.
+pub fn unknown_cage_policy() -> UnknownCagePolicy {
+    GLOBALFDTABLES.unknown_cage_policy()
+}
+
+#[doc = include_str!("../docs/close_virtualfd.md")]
+pub fn close_virtualfd(cageid:u64, virtfd:u64) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.close_virtualfd(cageid, virtfd)
+}
+
+#[doc = include_str!("../docs/close_virtualfd_range.md")]
+pub fn close_virtualfd_range(cageid:u64, first:u64, last:u64, flags:u32) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.close_virtualfd_range(cageid, first, last, flags)
+}
+
+#[doc = include_str!("../docs/register_close_handlers.md")]
+pub fn register_close_handlers(fdkind:u32, intermediate: fn(FDTableEntry,u64), last: fn(FDTableEntry,u64)) {
+    GLOBALFDTABLES.register_close_handlers(fdkind, intermediate, last);
+}
+
+#[doc = include_str!("../docs/add_close_handlers.md")]
+#[must_use] // must use the return value if you call it.
+pub fn add_close_handlers(fdkind:u32, intermediate: CloseHandler, last: CloseHandler) -> CloseHandlerToken {
+    GLOBALFDTABLES.add_close_handlers(fdkind, intermediate, last)
+}
+
+#[doc = include_str!("../docs/register_default_close_handlers.md")]
+pub fn register_default_close_handlers(intermediate: CloseHandler, last: CloseHandler) {
+    GLOBALFDTABLES.register_default_close_handlers(intermediate, last);
+}
+
+#[doc = include_str!("../docs/set_strict_close_handlers.md")]
+pub fn set_strict_close_handlers(strict:bool) {
+    GLOBALFDTABLES.set_strict_close_handlers(strict);
+}
+
+#[doc = include_str!("../docs/add_open_handler.md")]
+#[must_use] // must use the return value if you call it.
+pub fn add_open_handler(fdkind:u32, handler: OpenHandler) -> OpenHandlerToken {
+    GLOBALFDTABLES.add_open_handler(fdkind, handler)
+}
+
+#[doc = include_str!("../docs/remove_open_handler.md")]
+#[must_use] // must use the return value if you call it.
+pub fn remove_open_handler(token:OpenHandlerToken) -> bool {
+    GLOBALFDTABLES.remove_open_handler(token)
+}
+
+#[doc = include_str!("../docs/remove_close_handlers.md")]
+#[must_use] // must use the return value if you call it.
+pub fn remove_close_handlers(token:CloseHandlerToken) -> bool {
+    GLOBALFDTABLES.remove_close_handlers(token)
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::implicit_hasher)]
+#[doc = include_str!("../docs/get_bitmask_for_select.md")]
+pub fn get_bitmask_for_select(cageid:u64, nfds:u64, bits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry>>, HashMap<(u32,u64),u64>),FdTableError> {
+    GLOBALFDTABLES.get_bitmask_for_select(cageid, nfds, bits, fdkinds)
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::implicit_hasher)]
+#[doc = include_str!("../docs/prepare_bitmasks_for_select.md")]
+pub fn prepare_bitmasks_for_select(cageid:u64, nfds:u64, rbits:Option<fd_set>, wbits:Option<fd_set>, ebits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry>>;3], HashMap<(u32,u64),u64>),FdTableError> {
+    GLOBALFDTABLES.prepare_bitmasks_for_select(cageid, nfds, rbits, wbits, ebits, fdkinds)
+}
+
+#[allow(clippy::implicit_hasher)]
+#[allow(clippy::type_complexity)]
+#[doc = include_str!("../docs/convert_virtualfds_for_poll.md")]
+#[must_use] // must use the return value if you call it.
+pub fn convert_virtualfds_for_poll(cageid:u64, virtualfds:HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry)>>, HashMap<(u32,u64),u64>) {
+    GLOBALFDTABLES.convert_virtualfds_for_poll(cageid, virtualfds)
+}
+
+#[doc = include_str!("../docs/epoll_create_empty.md")]
+pub fn epoll_create_empty(cageid:u64, should_cloexec:bool) -> Result<u64,FdTableError> {
+    GLOBALFDTABLES.epoll_create_empty(cageid, should_cloexec)
+}
+
+#[doc = include_str!("../docs/epoll_add_underfd.md")]
+pub fn epoll_add_underfd(cageid:u64, virtepollfd:u64, fdkind:u32, underfd:u64) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.epoll_add_underfd(cageid, virtepollfd, fdkind, underfd)
+}
+
+#[doc = include_str!("../docs/epoll_get_underfd_hashmap.md")]
+pub fn epoll_get_underfd_hashmap(cageid:u64, virtepollfd:u64) -> Result<HashMap<u32,u64>,FdTableError> {
+    GLOBALFDTABLES.epoll_get_underfd_hashmap(cageid, virtepollfd)
+}
+
+#[doc = include_str!("../docs/virtualize_epoll_ctl.md")]
+pub fn virtualize_epoll_ctl(cageid:u64, epfd:u64, op:i32, virtfd:u64, event:epoll_event) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.virtualize_epoll_ctl(cageid, epfd, op, virtfd, event)
+}
+
+#[doc = include_str!("../docs/get_virtual_epoll_wait_data.md")]
+pub fn get_virtual_epoll_wait_data(cageid:u64, epfd:u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,FdTableError> {
+    GLOBALFDTABLES.get_virtual_epoll_wait_data(cageid, epfd)
+}
+
+
+
+/********************** BACKEND TRAIT IMPLEMENTATION **********************/
+
+impl<P: Clone + Send + Sync> FdTableBackend<P> for FdTables<P> {
+    const ALGONAME: &'static str = ALGONAME;
+
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn init_empty_cage(&self, cageid: u64) {
+        self.init_empty_cage(cageid);
+    }
+
+    fn translate_virtual_fd(&self, cageid: u64, virtualfd: u64) -> Result<FDTableEntry<P>, FdTableError> {
+        self.translate_virtual_fd(cageid, virtualfd)
+    }
+
+    fn get_unused_virtual_fd(&self, cageid: u64, fdkind: u32, underfd: u64, should_cloexec: bool, perfdinfo: P) -> Result<u64, FdTableError> {
+        self.get_unused_virtual_fd(cageid, fdkind, underfd, should_cloexec, perfdinfo)
+    }
+
+    fn get_unused_virtual_fd_from(&self, cageid: u64, minfd: u64, fdkind: u32, underfd: u64, should_cloexec: bool, perfdinfo: P) -> Result<u64, FdTableError> {
+        self.get_unused_virtual_fd_from(cageid, minfd, fdkind, underfd, should_cloexec, perfdinfo)
+    }
+
+    fn dup_virtualfd(&self, cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError> {
+        self.dup_virtualfd(cageid, oldvirtualfd)
+    }
+
+    fn dup2_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64) -> Result<u64, FdTableError> {
+        self.dup2_virtualfd(cageid, oldvirtualfd, newvirtualfd)
+    }
+
+    fn dup3_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64, should_cloexec: bool) -> Result<u64, FdTableError> {
+        self.dup3_virtualfd(cageid, oldvirtualfd, newvirtualfd, should_cloexec)
+    }
+
+    fn get_specific_virtual_fd(&self, cageid: u64, requested_virtualfd: u64, fdkind: u32, underfd: u64, should_cloexec: bool, perfdinfo: P) -> Result<(), FdTableError> {
+        self.get_specific_virtual_fd(cageid, requested_virtualfd, fdkind, underfd, should_cloexec, perfdinfo)
+    }
+
+    fn transfer_virtualfds(&self, srccageid: u64, srcvirtualfds: &[u64], dstcageid: u64, should_cloexec: bool) -> Result<Vec<u64>, FdTableError> {
+        self.transfer_virtualfds(srccageid, srcvirtualfds, dstcageid, should_cloexec)
+    }
+
+    fn getfd_virtualfd(&self, requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+        self.getfd_virtualfd(requestercageid, targetcageid, targetvirtualfd)
+    }
+
+    fn set_cloexec(&self, cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), FdTableError> {
+        self.set_cloexec(cageid, virtualfd, is_cloexec)
+    }
+
+    fn set_perfdinfo(&self, cageid: u64, virtualfd: u64, perfdinfo: P) -> Result<(), FdTableError> {
+        self.set_perfdinfo(cageid, virtualfd, perfdinfo)
+    }
+
+    fn update_virtualfd_entry<R>(&self, cageid: u64, virtualfd: u64, func: impl FnOnce(&mut FDTableEntry<P>) -> R) -> Result<R, FdTableError> {
+        self.update_virtualfd_entry(cageid, virtualfd, func)
+    }
+
+    fn compare_and_swap_virtualfd_entry(&self, cageid: u64, virtualfd: u64, expected: &FDTableEntry<P>, new: FDTableEntry<P>) -> Result<bool, FdTableError>
+    where
+        P: PartialEq,
+    {
+        self.compare_and_swap_virtualfd_entry(cageid, virtualfd, expected, new)
+    }
+
+    fn get_status_flags(&self, cageid: u64, virtualfd: u64) -> Result<i32, FdTableError> {
+        self.get_status_flags(cageid, virtualfd)
+    }
+
+    fn set_status_flags(&self, cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError> {
+        self.set_status_flags(cageid, virtualfd, statusflags)
+    }
+
+    fn get_offset(&self, cageid: u64, virtualfd: u64) -> Result<u64, FdTableError> {
+        self.get_offset(cageid, virtualfd)
+    }
+
+    fn set_offset(&self, cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError> {
+        self.set_offset(cageid, virtualfd, offset)
+    }
+
+    fn get_fd_limit(&self, cageid: u64) -> Result<(u64, u64), FdTableError> {
+        self.get_fd_limit(cageid)
+    }
+
+    fn set_fd_limit(&self, cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError> {
+        self.set_fd_limit(cageid, soft, hard)
+    }
+
+    fn set_total_fd_limit(&self, limit: u64) {
+        self.set_total_fd_limit(limit);
+    }
+
+    fn get_total_fd_usage(&self) -> (u64, u64) {
+        self.get_total_fd_usage()
+    }
+
+    fn copy_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+        self.copy_fdtable_for_cage(srccageid, newcageid)
+    }
+
+    fn share_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+        self.share_fdtable_for_cage(srccageid, newcageid)
+    }
+
+    fn unshare_fdtable_for_cage(&self, cageid: u64) -> Result<(), FdTableError> {
+        self.unshare_fdtable_for_cage(cageid)
+    }
+
+    fn remove_cage_from_fdtable(&self, cageid: u64) {
+        self.remove_cage_from_fdtable(cageid);
+    }
+
+    fn empty_fds_for_exec(&self, cageid: u64) {
+        self.empty_fds_for_exec(cageid);
+    }
+
+    fn return_fdtable_copy(&self, cageid: u64) -> HashMap<u64, FDTableEntry<P>> {
+        self.return_fdtable_copy(cageid)
+    }
+
+    fn for_each_virtualfd(&self, cageid: u64, func: impl FnMut(u64, &FDTableEntry<P>)) {
+        self.for_each_virtualfd(cageid, func);
+    }
+
+    fn for_each_virtualfd_in_range(&self, cageid: u64, first: u64, last: u64, fdkind: Option<u32>, func: impl FnMut(u64, &FDTableEntry<P>)) {
+        self.for_each_virtualfd_in_range(cageid, first, last, fdkind, func);
+    }
+
+    fn get_cageids(&self) -> Vec<u64> {
+        self.get_cageids()
+    }
+
+    fn get_fd_count(&self, cageid: u64) -> Result<u64, FdTableError> {
+        self.get_fd_count(cageid)
+    }
+
+    fn get_fd_references(&self, fdkind: u32, underfd: u64) -> Vec<(u64, u64)> {
+        self.get_fd_references(fdkind, underfd)
+    }
+
+    fn get_fd_reference_count(&self, fdkind: u32, underfd: u64) -> u64 {
+        self.get_fd_reference_count(fdkind, underfd)
+    }
+
+    fn get_fd_reference_counts(&self, fdkind: u32) -> HashMap<u64, u64> {
+        self.get_fd_reference_counts(fdkind)
+    }
+
+    fn get_virtualfds_of_kind(&self, cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError> {
+        self.get_virtualfds_of_kind(cageid, fdkind)
+    }
+
+    fn set_getfd_policy(&self, policy: GetfdPolicy) {
+        self.set_getfd_policy(policy);
+    }
+
+    fn set_getfd_audit(&self, audit: GetfdAudit) {
+        self.set_getfd_audit(audit);
+    }
+
+    fn set_unknown_cage_policy(&self, policy: UnknownCagePolicy) {
+        self.set_unknown_cage_policy(policy);
+    }
+
+    fn unknown_cage_policy(&self) -> UnknownCagePolicy {
+        self.unknown_cage_policy()
+    }
+
+    fn close_virtualfd(&self, cageid: u64, virtfd: u64) -> Result<(), FdTableError> {
+        self.close_virtualfd(cageid, virtfd)
+    }
+
+    fn close_virtualfd_range(&self, cageid: u64, first: u64, last: u64, flags: u32) -> Result<(), FdTableError> {
+        self.close_virtualfd_range(cageid, first, last, flags)
+    }
+
+    fn register_close_handlers(&self, fdkind: u32, intermediate: fn(FDTableEntry<P>, u64), last: fn(FDTableEntry<P>, u64)) where P: 'static {
+        self.register_close_handlers(fdkind, intermediate, last);
+    }
+
+    fn add_close_handlers(&self, fdkind: u32, intermediate: CloseHandler<P>, last: CloseHandler<P>) -> CloseHandlerToken {
+        self.add_close_handlers(fdkind, intermediate, last)
+    }
+
+    fn remove_close_handlers(&self, token: CloseHandlerToken) -> bool {
+        self.remove_close_handlers(token)
+    }
+
+    fn register_default_close_handlers(&self, intermediate: CloseHandler<P>, last: CloseHandler<P>) {
+        self.register_default_close_handlers(intermediate, last);
+    }
+
+    fn set_strict_close_handlers(&self, strict: bool) {
+        self.set_strict_close_handlers(strict);
+    }
+
+    fn add_open_handler(&self, fdkind: u32, handler: OpenHandler<P>) -> OpenHandlerToken {
+        self.add_open_handler(fdkind, handler)
+    }
+
+    fn remove_open_handler(&self, token: OpenHandlerToken) -> bool {
+        self.remove_open_handler(token)
+    }
+
+    fn get_bitmask_for_select(&self, cageid: u64, nfds: u64, bits: Option<fd_set>, fdkinds: &HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry<P>>>, HashMap<(u32,u64),u64>),FdTableError>
+    where
+        P: Eq + Hash,
+    {
+        self.get_bitmask_for_select(cageid, nfds, bits, fdkinds)
+    }
+
+    fn prepare_bitmasks_for_select(&self, cageid: u64, nfds: u64, rbits: Option<fd_set>, wbits: Option<fd_set>, ebits: Option<fd_set>, fdkinds: &HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry<P>>>;3], HashMap<(u32,u64),u64>),FdTableError>
+    where
+        P: Eq + Hash,
+    {
+        self.prepare_bitmasks_for_select(cageid, nfds, rbits, wbits, ebits, fdkinds)
+    }
+
+    fn convert_virtualfds_for_poll(&self, cageid: u64, virtualfds: HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry<P>)>>, HashMap<(u32,u64),u64>)
+    where
+        P: Eq + Hash + Default,
+    {
+        self.convert_virtualfds_for_poll(cageid, virtualfds)
+    }
+
+    fn epoll_create_empty(&self, cageid: u64, should_cloexec: bool) -> Result<u64, FdTableError>
+    where
+        P: Default,
+    {
+        self.epoll_create_empty(cageid, should_cloexec)
+    }
+
+    fn epoll_add_underfd(&self, cageid: u64, virtepollfd: u64, fdkind: u32, underfd: u64) -> Result<(), FdTableError> {
+        self.epoll_add_underfd(cageid, virtepollfd, fdkind, underfd)
+    }
+
+    fn epoll_get_underfd_hashmap(&self, cageid: u64, virtepollfd: u64) -> Result<HashMap<u32, u64>, FdTableError> {
+        self.epoll_get_underfd_hashmap(cageid, virtepollfd)
+    }
+
+    fn virtualize_epoll_ctl(&self, cageid: u64, epfd: u64, op: i32, virtfd: u64, event: epoll_event) -> Result<(), FdTableError> {
+        self.virtualize_epoll_ctl(cageid, epfd, op, virtfd, event)
+    }
+
+    fn get_virtual_epoll_wait_data(&self, cageid: u64, epfd: u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,FdTableError> {
+        self.get_virtual_epoll_wait_data(cageid, epfd)
+    }
+
+    fn refresh(&self) {
+        self.refresh();
+    }
+}
+
+
+/********************** TESTING HELPER FUNCTION **********************/
+
+impl<P: Clone + Send + Sync> FdTables<P> {
+    #[doc(hidden)]
+    // Helper to initialize / empty out state so we can test with a clean system...
+    // This is only used in tests, thus is hidden...
+    pub fn refresh(&self) {
+        self.fdtable.clear();
+        self.fdcount.clear();
+        self.rowusers.clear();
+        self.fdtable.insert(threei::TESTING_CAGEID,self._new_row(threei::TESTING_CAGEID));
+        let mut closehandlers = self.closehandlertable.lock().unwrap_or_else(|e| {
+            self.closehandlertable.clear_poison();
+            e.into_inner()
+        });
+        closehandlers.clear();
+        *self.defaultclosehandlers.lock().unwrap_or_else(|e| {
+            self.defaultclosehandlers.clear_poison();
+            e.into_inner()
+        }) = None;
+        self.strictclosehandlers.store(false, Ordering::Relaxed);
+        *self.openhandlertable.lock().unwrap_or_else(|e| {
+            self.openhandlertable.clear_poison();
+            e.into_inner()
+        }) = Arc::new(HashMap::new());
+        self.fdlimits.clear();
+        self.sharedtables.clear();
+        self.nexttablekey.store(u64::MAX, Ordering::Relaxed);
+        let mut policy = self.unknowncagepolicy.lock().unwrap_or_else(|e| {
+            self.unknowncagepolicy.clear_poison();
+            e.into_inner()
+        });
+        *policy = UnknownCagePolicy::Panic;
+        *self.getfdhooks.lock().unwrap_or_else(|e| {
+            self.getfdhooks.clear_poison();
+            e.into_inner()
+        }) = GetfdHooks::default();
+        self.totalfds.store(0, Ordering::Relaxed);
+        self.totalfdlimit.store(TOTAL_FD_MAX, Ordering::Relaxed);
+        // Note, it doesn't seem that Dashmaps can be poisoned...
+    }
+}
 
 #[doc(hidden)]
 // Helper to initialize / empty out state so we can test with a clean system...
 // This is only used in tests, thus is hidden...
 pub fn refresh() {
-    FDTABLE.clear();
-    FDTABLE.insert(threei::TESTING_CAGEID,[Option::None;FD_PER_PROCESS_MAX as usize]);
-    let mut closehandlers = CLOSEHANDLERTABLE.lock().unwrap_or_else(|e| {
-        CLOSEHANDLERTABLE.clear_poison();
-        e.into_inner()
-    });
-    closehandlers.clear();
-    // Note, it doesn't seem that Dashmaps can be poisoned...
+    GLOBALFDTABLES.refresh();
 }
@@ -0,0 +1,85 @@
+//! A bitmap of which virtual fds a cage has in use.
+//!
+//! This lets an implementation find the lowest unused fd without scanning its
+//! table.  There are two levels: one bit per fd, and a summary word with a
+//! bit set for each word of fds that is completely full.  Finding the lowest
+//! free fd is then two `trailing_ones` calls, no matter how many fds are open.
+
+use crate::commonconstants::FD_PER_PROCESS_MAX;
+
+// The number of u64 words needed for one bit per fd.
+const WORDS: usize = FD_PER_PROCESS_MAX.div_ceil(64) as usize;
+
+// The summary is a single u64, so this only works up to 4096 fds per cage.
+// If FD_PER_PROCESS_MAX grows past that, this needs another level.
+const _: () = assert!(WORDS <= 64);
+
+#[derive(Clone, Debug)]
+pub(crate) struct FdBitmap {
+    // Bit (fd % 64) of words[fd / 64] is set if fd is in use.
+    words: [u64; WORDS],
+    // Bit i is set if words[i] is all ones (so has no free fds).
+    full: u64,
+}
+
+impl FdBitmap {
+    /// An empty bitmap (all fds are free).
+    pub(crate) fn new() -> Self {
+        let mut bitmap = FdBitmap {
+            words: [0; WORDS],
+            full: 0,
+        };
+        // If FD_PER_PROCESS_MAX isn't a multiple of 64, mark the bits past it
+        // as used, so they are never handed out.
+        for fd in FD_PER_PROCESS_MAX..(WORDS as u64 * 64) {
+            bitmap.set(fd);
+        }
+        bitmap
+    }
+
+    /// Mark an fd as in use.
+    pub(crate) fn set(&mut self, fd: u64) {
+        let word = (fd / 64) as usize;
+        self.words[word] |= 1 << (fd % 64);
+        if self.words[word] == u64::MAX {
+            self.full |= 1 << word;
+        }
+    }
+
+    /// Mark an fd as free.
+    pub(crate) fn clear(&mut self, fd: u64) {
+        let word = (fd / 64) as usize;
+        self.words[word] &= !(1 << (fd % 64));
+        self.full &= !(1 << word);
+    }
+
+    /// The number of fds in use.
+    pub(crate) fn count(&self) -> u64 {
+        let used: u32 = self.words.iter().map(|word| word.count_ones()).sum();
+        // Don't count the bits past FD_PER_PROCESS_MAX (see new).
+        u64::from(used) - (WORDS as u64 * 64 - FD_PER_PROCESS_MAX)
+    }
+
+    /// The lowest free fd which is at least minfd, if it is below limit.
+    pub(crate) fn lowest_free_between(&self, minfd: u64, limit: u64) -> Option<u64> {
+        if minfd >= limit || minfd >= WORDS as u64 * 64 {
+            return None;
+        }
+        let startword = (minfd / 64) as usize;
+        // First look in minfd's own word, pretending the fds below minfd are
+        // used...
+        let startbits = self.words[startword] | ((1 << (minfd % 64)) - 1);
+        let fd = if startbits == u64::MAX {
+            // ... and if it's full, the first word after it which isn't.
+            let skip = if startword == 63 { u64::MAX } else { (2 << startword) - 1 };
+            let word = (self.full | skip).trailing_ones() as usize;
+            if word >= WORDS {
+                return None;
+            }
+            word as u64 * 64 + u64::from(self.words[word].trailing_ones())
+        } else {
+            startword as u64 * 64 + u64::from(startbits.trailing_ones())
+        };
+        (fd < limit).then_some(fd)
+    }
+}
@@ -0,0 +1,488 @@
+//! The interface that every fdtable implementation provides.
+//!
+//! Each algorithm (`dashmaparrayglobal`, `dashmapvecglobal`, etc.) has an
+//! `FdTables` struct with the same set of methods, plus free functions that
+//! call those methods on a global instance.  This trait captures that API so
+//! that a caller (or a benchmark / test) can be written once and run against
+//! any of the implementations.  The items re-exported at the top of the crate
+//! are the ones from the implementation picked by the `backend-*` Cargo
+//! features.  See the crate level docs for details.
+//!
+//! The documentation for each operation lives with the free function of the
+//! same name, so look there for the panics, errors, and examples.
+
+use crate::fdtableerror::FdTableError;
+
+use crate::commonconstants::{
+    epoll_event, CloseHandler, CloseHandlerToken, FDTableEntry, GetfdAudit, GetfdPolicy,
+    OpenHandler, OpenHandlerToken, UnknownCagePolicy,
+};
+
+use libc::fd_set;
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Operations that every fdtable implementation supports.
+///
+/// Each method behaves exactly like the free function with the same name in
+/// the implementation's module, except that it uses `self` instead of the
+/// global instance.  `P` is the type of the per-fd payload (see
+/// [`FDTableEntry::perfdinfo`]).  The select and poll helpers put entries in
+/// a `HashSet`, so they also need `P: Eq + Hash`, and poll needs `Default` to
+/// fill in entries for invalid fds
+/// (as does [`epoll_create_empty`](crate::epoll_create_empty), for the epoll fd's
+/// entry).
+pub trait FdTableBackend<P: Clone + Send + Sync = u64>: Send + Sync {
+    /// Algorithm name.  Used in benchmarking output.
+    const ALGONAME: &'static str;
+
+    /// Creates a new, independent set of fdtables with no cages in it.
+    #[must_use]
+    fn new() -> Self
+    where
+        Self: Sized;
+
+    /// See [`init_empty_cage`](crate::init_empty_cage).
+    fn init_empty_cage(&self, cageid: u64);
+
+    /// See [`translate_virtual_fd`](crate::translate_virtual_fd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn translate_virtual_fd(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+    ) -> Result<FDTableEntry<P>, FdTableError>;
+
+    /// See [`get_unused_virtual_fd`](crate::get_unused_virtual_fd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_unused_virtual_fd(
+        &self,
+        cageid: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<u64, FdTableError>;
+
+    /// See [`get_unused_virtual_fd_from`](crate::get_unused_virtual_fd_from).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_unused_virtual_fd_from(
+        &self,
+        cageid: u64,
+        minfd: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<u64, FdTableError>;
+
+    /// See [`dup_virtualfd`](crate::dup_virtualfd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn dup_virtualfd(&self, cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError>;
+
+    /// See [`dup2_virtualfd`](crate::dup2_virtualfd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn dup2_virtualfd(
+        &self,
+        cageid: u64,
+        oldvirtualfd: u64,
+        newvirtualfd: u64,
+    ) -> Result<u64, FdTableError>;
+
+    /// See [`dup3_virtualfd`](crate::dup3_virtualfd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn dup3_virtualfd(
+        &self,
+        cageid: u64,
+        oldvirtualfd: u64,
+        newvirtualfd: u64,
+        should_cloexec: bool,
+    ) -> Result<u64, FdTableError>;
+
+    /// See [`get_specific_virtual_fd`](crate::get_specific_virtual_fd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_specific_virtual_fd(
+        &self,
+        cageid: u64,
+        requested_virtualfd: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<(), FdTableError>;
+
+    /// See [`transfer_virtualfds`](crate::transfer_virtualfds).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn transfer_virtualfds(
+        &self,
+        srccageid: u64,
+        srcvirtualfds: &[u64],
+        dstcageid: u64,
+        should_cloexec: bool,
+    ) -> Result<Vec<u64>, FdTableError>;
+
+    /// See [`getfd_virtualfd`](crate::getfd_virtualfd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn getfd_virtualfd(
+        &self,
+        requestercageid: u64,
+        targetcageid: u64,
+        targetvirtualfd: u64,
+    ) -> Result<u64, FdTableError>;
+
+    /// See [`set_cloexec`](crate::set_cloexec).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn set_cloexec(&self, cageid: u64, virtualfd: u64, is_cloexec: bool)
+        -> Result<(), FdTableError>;
+
+    /// See [`set_perfdinfo`](crate::set_perfdinfo).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn set_perfdinfo(&self, cageid: u64, virtualfd: u64, perfdinfo: P)
+        -> Result<(), FdTableError>;
+
+    /// See [`update_virtualfd_entry`](crate::update_virtualfd_entry).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn update_virtualfd_entry<R>(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        func: impl FnOnce(&mut FDTableEntry<P>) -> R,
+    ) -> Result<R, FdTableError>;
+
+    /// See [`compare_and_swap_virtualfd_entry`](crate::compare_and_swap_virtualfd_entry).
+    /// The payload is compared too, so this needs `P: PartialEq`.
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn compare_and_swap_virtualfd_entry(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        expected: &FDTableEntry<P>,
+        new: FDTableEntry<P>,
+    ) -> Result<bool, FdTableError>
+    where
+        P: PartialEq;
+
+    /// See [`get_status_flags`](crate::get_status_flags).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_status_flags(&self, cageid: u64, virtualfd: u64) -> Result<i32, FdTableError>;
+
+    /// See [`set_status_flags`](crate::set_status_flags).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn set_status_flags(&self, cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError>;
+
+    /// See [`get_offset`](crate::get_offset).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_offset(&self, cageid: u64, virtualfd: u64) -> Result<u64, FdTableError>;
+
+    /// See [`set_offset`](crate::set_offset).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn set_offset(&self, cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError>;
+
+    /// See [`get_fd_limit`](crate::get_fd_limit).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_fd_limit(&self, cageid: u64) -> Result<(u64, u64), FdTableError>;
+
+    /// See [`set_fd_limit`](crate::set_fd_limit).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn set_fd_limit(&self, cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError>;
+
+    /// See [`set_total_fd_limit`](crate::set_total_fd_limit).
+    fn set_total_fd_limit(&self, limit: u64);
+
+    /// See [`get_total_fd_usage`](crate::get_total_fd_usage).
+    #[must_use]
+    fn get_total_fd_usage(&self) -> (u64, u64);
+
+    /// See [`copy_fdtable_for_cage`](crate::copy_fdtable_for_cage).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn copy_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError>;
+
+    /// See [`share_fdtable_for_cage`](crate::share_fdtable_for_cage).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn share_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError>;
+
+    /// See [`unshare_fdtable_for_cage`](crate::unshare_fdtable_for_cage).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn unshare_fdtable_for_cage(&self, cageid: u64) -> Result<(), FdTableError>;
+
+    /// See [`remove_cage_from_fdtable`](crate::remove_cage_from_fdtable).
+    fn remove_cage_from_fdtable(&self, cageid: u64);
+
+    /// See [`empty_fds_for_exec`](crate::empty_fds_for_exec).
+    fn empty_fds_for_exec(&self, cageid: u64);
+
+    /// See [`return_fdtable_copy`](crate::return_fdtable_copy).
+    #[must_use]
+    fn return_fdtable_copy(&self, cageid: u64) -> HashMap<u64, FDTableEntry<P>>;
+
+    /// See [`for_each_virtualfd`](crate::for_each_virtualfd).
+    fn for_each_virtualfd(&self, cageid: u64, func: impl FnMut(u64, &FDTableEntry<P>));
+
+    /// See [`for_each_virtualfd_in_range`](crate::for_each_virtualfd_in_range).
+    fn for_each_virtualfd_in_range(
+        &self,
+        cageid: u64,
+        first: u64,
+        last: u64,
+        fdkind: Option<u32>,
+        func: impl FnMut(u64, &FDTableEntry<P>),
+    );
+
+    /// See [`get_cageids`](crate::get_cageids).
+    #[must_use]
+    fn get_cageids(&self) -> Vec<u64>;
+
+    /// See [`get_fd_count`](crate::get_fd_count).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_fd_count(&self, cageid: u64) -> Result<u64, FdTableError>;
+
+    /// See [`get_fd_references`](crate::get_fd_references).
+    #[must_use]
+    fn get_fd_references(&self, fdkind: u32, underfd: u64) -> Vec<(u64, u64)>;
+
+    /// See [`get_fd_reference_count`](crate::get_fd_reference_count).
+    #[must_use]
+    fn get_fd_reference_count(&self, fdkind: u32, underfd: u64) -> u64;
+
+    /// See [`get_fd_reference_counts`](crate::get_fd_reference_counts).
+    #[must_use]
+    fn get_fd_reference_counts(&self, fdkind: u32) -> HashMap<u64, u64>;
+
+    /// See [`get_virtualfds_of_kind`](crate::get_virtualfds_of_kind).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn get_virtualfds_of_kind(&self, cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError>;
+
+    /// See [`set_getfd_policy`](crate::set_getfd_policy).
+    fn set_getfd_policy(&self, policy: GetfdPolicy);
+
+    /// See [`set_getfd_audit`](crate::set_getfd_audit).
+    fn set_getfd_audit(&self, audit: GetfdAudit);
+
+    /// See [`set_unknown_cage_policy`](crate::set_unknown_cage_policy).
+    fn set_unknown_cage_policy(&self, policy: UnknownCagePolicy);
+
+    /// See [`unknown_cage_policy`](crate::unknown_cage_policy).
+    #[must_use]
+    fn unknown_cage_policy(&self) -> UnknownCagePolicy;
+
+    /// See [`close_virtualfd`](crate::close_virtualfd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn close_virtualfd(&self, cageid: u64, virtfd: u64) -> Result<(), FdTableError>;
+
+    /// See [`close_virtualfd_range`](crate::close_virtualfd_range).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn close_virtualfd_range(
+        &self,
+        cageid: u64,
+        first: u64,
+        last: u64,
+        flags: u32,
+    ) -> Result<(), FdTableError>;
+
+    /// See [`register_close_handlers`](crate::register_close_handlers).
+    fn register_close_handlers(
+        &self,
+        fdkind: u32,
+        intermediate: fn(FDTableEntry<P>, u64),
+        last: fn(FDTableEntry<P>, u64),
+    ) where
+        P: 'static;
+
+    /// See [`add_close_handlers`](crate::add_close_handlers).
+    #[must_use]
+    fn add_close_handlers(
+        &self,
+        fdkind: u32,
+        intermediate: CloseHandler<P>,
+        last: CloseHandler<P>,
+    ) -> CloseHandlerToken;
+
+    /// See [`remove_close_handlers`](crate::remove_close_handlers).
+    #[must_use]
+    fn remove_close_handlers(&self, token: CloseHandlerToken) -> bool;
+
+    /// See [`register_default_close_handlers`](crate::register_default_close_handlers).
+    fn register_default_close_handlers(&self, intermediate: CloseHandler<P>, last: CloseHandler<P>);
+
+    /// See [`set_strict_close_handlers`](crate::set_strict_close_handlers).
+    fn set_strict_close_handlers(&self, strict: bool);
+
+    /// See [`add_open_handler`](crate::add_open_handler).
+    #[must_use]
+    fn add_open_handler(&self, fdkind: u32, handler: OpenHandler<P>) -> OpenHandlerToken;
+
+    /// See [`remove_open_handler`](crate::remove_open_handler).
+    #[must_use]
+    fn remove_open_handler(&self, token: OpenHandlerToken) -> bool;
+
+    /// See [`get_bitmask_for_select`](crate::get_bitmask_for_select).
+    ///
+    /// # Errors
+    ///   See the free function.
+    #[allow(clippy::type_complexity)]
+    fn get_bitmask_for_select(
+        &self,
+        cageid: u64,
+        nfds: u64,
+        bits: Option<fd_set>,
+        fdkinds: &HashSet<u32>,
+    ) -> Result<
+        (
+            HashMap<u32, (u64, fd_set)>,
+            HashMap<u32, HashSet<FDTableEntry<P>>>,
+            HashMap<(u32, u64), u64>,
+        ),
+        FdTableError,
+    >
+    where
+        P: Eq + Hash;
+
+    /// See [`prepare_bitmasks_for_select`](crate::prepare_bitmasks_for_select).
+    ///
+    /// # Errors
+    ///   See the free function.
+    #[allow(clippy::type_complexity)]
+    fn prepare_bitmasks_for_select(
+        &self,
+        cageid: u64,
+        nfds: u64,
+        rbits: Option<fd_set>,
+        wbits: Option<fd_set>,
+        ebits: Option<fd_set>,
+        fdkinds: &HashSet<u32>,
+    ) -> Result<
+        (
+            [HashMap<u32, (u64, fd_set)>; 3],
+            [HashMap<u32, HashSet<FDTableEntry<P>>>; 3],
+            HashMap<(u32, u64), u64>,
+        ),
+        FdTableError,
+    >
+    where
+        P: Eq + Hash;
+
+    /// See [`convert_virtualfds_for_poll`](crate::convert_virtualfds_for_poll).
+    #[allow(clippy::type_complexity)]
+    #[must_use]
+    fn convert_virtualfds_for_poll(
+        &self,
+        cageid: u64,
+        virtualfds: HashSet<u64>,
+    ) -> (
+        HashMap<u32, HashSet<(u64, FDTableEntry<P>)>>,
+        HashMap<(u32, u64), u64>,
+    )
+    where
+        P: Eq + Hash + Default;
+
+    /// See [`epoll_create_empty`](crate::epoll_create_empty).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn epoll_create_empty(&self, cageid: u64, should_cloexec: bool) -> Result<u64, FdTableError>
+    where
+        P: Default;
+
+    /// See [`epoll_add_underfd`](crate::epoll_add_underfd).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn epoll_add_underfd(
+        &self,
+        cageid: u64,
+        virtepollfd: u64,
+        fdkind: u32,
+        underfd: u64,
+    ) -> Result<(), FdTableError>;
+
+    /// See [`epoll_get_underfd_hashmap`](crate::epoll_get_underfd_hashmap).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn epoll_get_underfd_hashmap(
+        &self,
+        cageid: u64,
+        virtepollfd: u64,
+    ) -> Result<HashMap<u32, u64>, FdTableError>;
+
+    /// See [`virtualize_epoll_ctl`](crate::virtualize_epoll_ctl).
+    ///
+    /// # Errors
+    ///   See the free function.
+    fn virtualize_epoll_ctl(
+        &self,
+        cageid: u64,
+        epfd: u64,
+        op: i32,
+        virtfd: u64,
+        event: epoll_event,
+    ) -> Result<(), FdTableError>;
+
+    /// See [`get_virtual_epoll_wait_data`](crate::get_virtual_epoll_wait_data).
+    ///
+    /// # Errors
+    ///   See the free function.
+    #[allow(clippy::type_complexity)]
+    fn get_virtual_epoll_wait_data(
+        &self,
+        cageid: u64,
+        epfd: u64,
+    ) -> Result<HashMap<u32, HashMap<u64, epoll_event>>, FdTableError>;
+
+    #[doc(hidden)]
+    // Testing helper.  Empties out all state.  See refresh() in each module.
+    fn refresh(&self);
+}
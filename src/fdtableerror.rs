@@ -0,0 +1,141 @@
+//! The error type returned by the fdtables API.
+//!
+//! Each variant is one way a call can fail.  A grate usually just wants to
+//! hand an errno back to the cage, so every variant converts into a
+//! [`threei::Errno`] and into the raw [`threei::RetVal`] with `From` / `into()`.
+
+use crate::threei;
+
+use std::fmt;
+
+/// Why an fdtables call failed.
+///
+/// The errno each variant maps to is listed with it.  Note that several
+/// variants share an errno, since the syscalls themselves don't distinguish
+/// between those cases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive] // I want to be able to add more failure cases later...
+pub enum FdTableError {
+    /// The cageid isn't in the fdtable.  Only returned if the
+    /// `UnknownCagePolicy` is `Error`.  (`ESRCH`)
+    UnknownCage,
+    /// The virtual fd isn't open in this cage.  (`EBADFD`)
+    FdNotOpen,
+    /// The requested virtual fd is larger than the per-process limit.
+    /// (`EBADF`)
+    FdOutOfRange,
+    /// An fd passed to select, epoll, or dup isn't open in this cage.
+    /// (`EBADF`)
+    BadFd,
+    /// The cage has no free virtual fds left below its soft limit.
+    /// (`EMFILE`)
+    TableFull,
+    /// The first fd passed to `close_virtualfd_range` is after the last one.
+    /// (`EINVAL`)
+    InvalidRange,
+    /// A flags argument has a bit set which isn't supported.  (`EINVAL`)
+    InvalidFlags,
+    /// `dup3_virtualfd` was asked to copy an fd onto itself.  (`EINVAL`)
+    DupToSameFd,
+    /// The minimum fd passed to `get_unused_virtual_fd_from` is at or above
+    /// the cage's soft limit.  (`EINVAL`)
+    MinFdTooLarge,
+    /// Too many fds are open across all cages.  See `set_total_fd_limit`.
+    /// (`ENFILE`)
+    SystemTableFull,
+    /// The soft fd limit passed to `set_fd_limit` is above the hard limit.
+    /// (`EINVAL`)
+    InvalidLimit,
+    /// The hard fd limit passed to `set_fd_limit` is above
+    /// `FD_PER_PROCESS_MAX`.  (`EPERM`)
+    LimitTooLarge,
+    /// The `nfds` passed to select is at or above the per-process limit.
+    /// (`EINVAL`)
+    NfdsTooLarge,
+    /// The fd passed as an epoll fd isn't one.  (`EINVAL`)
+    NotEpollFd,
+    /// An epoll fd was asked to watch itself.  (`EINVAL`)
+    EpollTargetIsSelf,
+    /// The `op` passed to `virtualize_epoll_ctl` is not `EPOLL_CTL_ADD`,
+    /// `EPOLL_CTL_MOD`, or `EPOLL_CTL_DEL`.  (`EINVAL`)
+    InvalidEpollOp,
+    /// `EPOLL_CTL_ADD` for an fd the epoll fd already watches.  (`EEXIST`)
+    EpollEntryExists,
+    /// `EPOLL_CTL_MOD` / `EPOLL_CTL_DEL` for an fd the epoll fd doesn't
+    /// watch.  (`ENOENT`)
+    EpollEntryMissing,
+    /// Adding an epoll fd to another epoll fd is not supported yet.
+    /// (`ENOSYS`)
+    NestedEpollUnsupported,
+    /// Adding an epoll fd would create a cycle of epoll fds.  (`ELOOP`)
+    /// Not returned until nested epoll fds are supported.
+    LoopDetected,
+    /// The getfd policy doesn't let this cage copy the other cage's fds.
+    /// See `set_getfd_policy`.  (`EPERM`)
+    PermissionDenied,
+}
+
+impl From<FdTableError> for threei::Errno {
+    fn from(err: FdTableError) -> Self {
+        match err {
+            FdTableError::UnknownCage => threei::Errno::ESRCH,
+            FdTableError::FdNotOpen => threei::Errno::EBADFD,
+            FdTableError::FdOutOfRange | FdTableError::BadFd => threei::Errno::EBADF,
+            FdTableError::TableFull => threei::Errno::EMFILE,
+            FdTableError::SystemTableFull => threei::Errno::ENFILE,
+            FdTableError::LimitTooLarge | FdTableError::PermissionDenied => threei::Errno::EPERM,
+            FdTableError::InvalidRange
+            | FdTableError::InvalidFlags
+            | FdTableError::DupToSameFd
+            | FdTableError::MinFdTooLarge
+            | FdTableError::InvalidLimit
+            | FdTableError::NfdsTooLarge
+            | FdTableError::NotEpollFd
+            | FdTableError::EpollTargetIsSelf
+            | FdTableError::InvalidEpollOp => threei::Errno::EINVAL,
+            FdTableError::EpollEntryExists => threei::Errno::EEXIST,
+            FdTableError::EpollEntryMissing => threei::Errno::ENOENT,
+            FdTableError::NestedEpollUnsupported => threei::Errno::ENOSYS,
+            FdTableError::LoopDetected => threei::Errno::ELOOP,
+        }
+    }
+}
+
+// RetVal is just a u64, so this is what lets `?` / `into()` produce the raw
+// value a syscall returns.
+impl From<FdTableError> for threei::RetVal {
+    fn from(err: FdTableError) -> Self {
+        threei::Errno::from(err) as threei::RetVal
+    }
+}
+
+impl fmt::Display for FdTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FdTableError::UnknownCage => "unknown cageid",
+            FdTableError::FdNotOpen => "virtual fd is not open",
+            FdTableError::FdOutOfRange => "virtual fd is out of range",
+            FdTableError::BadFd => "bad file descriptor",
+            FdTableError::TableFull => "too many open files in cage",
+            FdTableError::InvalidRange => "first fd is after the last fd",
+            FdTableError::InvalidFlags => "unsupported flags",
+            FdTableError::DupToSameFd => "cannot dup3 an fd onto itself",
+            FdTableError::MinFdTooLarge => "minimum fd is at or above the cage's fd limit",
+            FdTableError::SystemTableFull => "too many open files in system",
+            FdTableError::InvalidLimit => "soft fd limit is above the hard limit",
+            FdTableError::LimitTooLarge => "hard fd limit exceeds the per-process fd limit",
+            FdTableError::NfdsTooLarge => "nfds exceeds the per-process fd limit",
+            FdTableError::NotEpollFd => "fd is not an epoll fd",
+            FdTableError::EpollTargetIsSelf => "epoll fd cannot watch itself",
+            FdTableError::InvalidEpollOp => "invalid epoll_ctl operation",
+            FdTableError::EpollEntryExists => "fd is already registered with this epoll fd",
+            FdTableError::EpollEntryMissing => "fd is not registered with this epoll fd",
+            FdTableError::NestedEpollUnsupported => "nested epoll fds are not supported",
+            FdTableError::LoopDetected => "epoll fds would form a loop",
+            FdTableError::PermissionDenied => "not permitted to copy this cage's fds",
+        };
+        write!(f, "{msg} ({:?})", threei::Errno::from(*self))
+    }
+}
+
+impl std::error::Error for FdTableError {}
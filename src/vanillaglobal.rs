@@ -1,13 +1,23 @@
+//! The `VanillaGlobal` fdtable implementation: a `Mutex` around a `HashMap` of cages, each holding a `HashMap` of entries.
+//!
+//! Use the functions re-exported at the top of the crate instead of this
+//! module directly, unless you need this specific algorithm.
+
 use crate::threei;
 
+use crate::fdtableerror::FdTableError;
+
+use crate::fdtablebackend::FdTableBackend;
+
 use lazy_static::lazy_static;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use std::collections::HashMap;
 
 // This is a basic fdtables library.  The purpose is to allow a cage to have
-// a set of virtual fds which is translated into real fds.
+// a set of virtual fds which is translated into underfds.
 
 
 // Get constants about the fd table sizes, etc.
@@ -21,8 +31,9 @@ pub const ALGONAME: &str = "VanillaGlobal";
 // when I would
 // add a new fd).
 //
-// BUG: I will ignore the total limit for now.  I would ideally do this on
-// every creation, close, fork, etc. but it's a PITA to track this.
+// The total limit is tracked with a counter of all open fds.  Anything which
+// adds fds (allocation, dup, fork) reserves them from the counter first, and
+// _decrement_fdcount gives them back when an fd goes away.
 
 // We will raise a panic anywhere we receive an unknown cageid.  This frankly
 // should not be possible and indicates some sort of internal error in our
@@ -31,8 +42,7 @@ pub const ALGONAME: &str = "VanillaGlobal";
 
 // In order to store this information, I'm going to use a HashMap which
 // has keys of (cageid:u64) and values that are another HashMap.  The second
-// HashMap has keys of (virtualfd:64) and values of (realfd:u64,
-// should_cloexec:bool, optionalinfo:u64).
+// HashMap has keys of (virtualfd:64) and values of FDTableEntry.
 //
 // To speed up lookups, I could have used arrays instead of HashMaps.  In
 // theory, that space is far too large, but likely each could be bounded to
@@ -42,952 +52,2895 @@ pub const ALGONAME: &str = "VanillaGlobal";
 // since they aren't always used together, but this seemed needlessly complex
 // (at least at first).
 //
+// Unlike the DashMap versions, everything here is behind a plain Mutex.  I'm
+// careful to drop the fdtable lock before calling the close handlers, since
+// the handlers may call back into this library.
+
+/// A set of fdtables, along with the close handlers and epoll state that go
+/// with them.
+///
+/// Every instance is completely independent of every other instance.  The
+/// free functions in this module (and at the top of the crate) use a global
+/// instance.
+#[derive(Debug)]
+pub struct FdTables<P = u64> {
+    fdtable: Mutex<HashMap<u64, HashMap<u64,FDTableEntry<P>>>>,
+    // This is needed for close and similar functionality.  I need track the
+    // number of times a (fdkind,underfd) is open.  This is across cages.  The
+    // value has where it is open (which is also the index for
+    // get_fd_references) and the open file description those fds share.  If
+    // the count reaches 0, the entry is removed.
+    fdcount: Mutex<HashMap<(u32,u64), FileDescription>>,
+    // The (soft, hard) limits on the number of fds for each cage, like
+    // RLIMIT_NOFILE.  A cage without an entry has the default limits
+    // (FD_PER_PROCESS_MAX for both), so this only has cages which called
+    // set_fd_limit (or inherited a limit from one that did).  If I need both
+    // locks, I always lock the fdtable first.
+    fdlimits: Mutex<HashMap<u64, (u64,u64)>>,
+    // Cages which share another cage's table (see share_fdtable_for_cage),
+    // mapped to the cage the table is stored under.  That cage is always in
+    // the fdtable and never in here.  The references to a shared table's fds
+    // (in fdcount) and its limits are under that cage too.  If I need both
+    // locks, I always lock the fdtable first.
+    sharedtables: Mutex<HashMap<u64,u64>>,
+    // This holds the user registered handlers they want to have called when
+    // a close occurs.  I did this rather than return messy data structures
+    // from the close, exec, and exit handlers because it seemed cleaner...
+    // Each fdkind's list can be added to (see add_close_handlers), and is
+    // copied on write so a close can run it without holding the lock.
+    closehandlertable: Mutex<HashMap<u32,CloseHandlerList<P>>>,
+    nexthandlertoken: AtomicU64,
+    // Run for an fdkind with no handlers of its own (see
+    // register_default_close_handlers), and whether to panic if there
+    // aren't any of those either.
+    defaultclosehandlers: Mutex<Option<CloseHandlerList<P>>>,
+    strictclosehandlers: AtomicBool,
+    // The handlers to call when a reference is added, by fdkind.  The whole
+    // table is copied on write, so an operation can take it (cheaply) before
+    // it starts and call the handlers once it has dropped its locks.
+    openhandlertable: Mutex<OpenHandlerTable<P>>,
+    epolltable: Mutex<EPollTable>,
+    // What to do if a call names a cage I don't know about.  This is only
+    // looked at when that happens, so it's not on the fast path.
+    unknowncagepolicy: Mutex<UnknownCagePolicy>,
+    // Who may copy another cage's fds with getfd_virtualfd, and what is told
+    // about each try.  Only looked at by getfd_virtualfd.
+    getfdhooks: Mutex<GetfdHooks>,
+    // The number of fds open in all cages, and the most that may be open at
+    // once (like file-nr and file-max in /proc/sys/fs).  These are atomics so
+    // they don't need another lock.
+    totalfds: AtomicU64,
+    totalfdlimit: AtomicU64,
+}
 
 // This lets me initialize the code as a global.
 lazy_static! {
 
     #[derive(Debug)]
-    static ref GLOBALFDTABLE: Mutex<HashMap<u64, HashMap<u64,FDTableEntry>>> = {
-        let mut m = HashMap::new();
+    static ref GLOBALFDTABLES: FdTables = {
+        let m = FdTables::new();
         // Insert a cage so that I have something to fork / test later, if need
         // be. Otherwise, I'm not sure how I get this started. I think this
         // should be invalid from a 3i standpoint, etc. Could this mask an
         // error in the future?
-        m.insert(threei::TESTING_CAGEID,HashMap::new());
-        Mutex::new(m)
+        m.fdtable.lock().unwrap().insert(threei::TESTING_CAGEID,HashMap::new());
+        m
     };
 }
 
-lazy_static! {
-    // This is needed for close and similar functionality.  I need track the
-    // number of times a realfd is open
-    #[derive(Debug)]
-    static ref GLOBALREALFDCOUNT: Mutex<HashMap<u64, u64>> = {
-        Mutex::new(HashMap::new())
-    };
+// The state shared by every fd that refers to one open file description.
+// Fds share a description exactly when they have the same (fdkind,underfd),
+// which is what dup and fork copy.  It is created by the first
+// fd that refers to it and dropped when the last one is closed.
+#[derive(Clone, Debug, Default)]
+struct FileDescription {
+    // Where the fds (in all cages) that refer to this are, as (cageid,
+    // virtualfd).  How many there are is the reference count.  This isn't a
+    // set since replacing an fd with the same (fdkind,underfd) adds the new
+    // reference before removing the old one, and they are at the same place.
+    references: Vec<(u64,u64)>,
+    // Status flags, like O_NONBLOCK and O_APPEND.  See set_status_flags.
+    statusflags: i32,
+    // The file offset.  See set_offset.
+    offset: u64,
+}
+
+impl FileDescription {
+    fn count(&self) -> u64 {
+        self.references.len() as u64
+    }
+
+    // Removes one of the references at this place.  The order of the
+    // references doesn't matter, so swap_remove is fine.
+    fn remove_reference(&mut self, cageid:u64, virtualfd:u64) {
+        let index = self.references.iter().position(|&place| place == (cageid, virtualfd)).unwrap();
+        self.references.swap_remove(index);
+    }
 
+    // Moves one of the references at this place to the same fd in another
+    // cage.  This is for when a shared table is handed to another cage.
+    fn move_reference(&mut self, cageid:u64, newcageid:u64, virtualfd:u64) {
+        let index = self.references.iter().position(|&place| place == (cageid, virtualfd)).unwrap();
+        self.references[index] = (newcageid, virtualfd);
+    }
 }
 
-// Internal helper to hold the close handlers...
-struct CloseHandlers {
-    intermediate_handler: fn(u64),
-    final_handler: fn(u64),
-    unreal_handler: fn(u64),
+// This is generic, so an instance with any payload type can be made with
+// FdTables::<MyPayload>::default()
+impl<P: Clone + Send + Sync> Default for FdTables<P> {
+    fn default() -> Self {
+        Self {
+            fdtable: Mutex::new(HashMap::new()),
+            fdcount: Mutex::new(HashMap::new()),
+            fdlimits: Mutex::new(HashMap::new()),
+            sharedtables: Mutex::new(HashMap::new()),
+            closehandlertable: Mutex::new(HashMap::new()),
+            nexthandlertoken: AtomicU64::new(0),
+            defaultclosehandlers: Mutex::new(None),
+            strictclosehandlers: AtomicBool::new(false),
+            openhandlertable: Mutex::new(Arc::new(HashMap::new())),
+            epolltable: Mutex::new(EPollTable {
+                highestneverusedentry:0,
+                thisepolltable:HashMap::new(),
+            }),
+            unknowncagepolicy: Mutex::new(UnknownCagePolicy::Panic),
+            getfdhooks: Mutex::new(GetfdHooks::default()),
+            totalfds: AtomicU64::new(0),
+            totalfdlimit: AtomicU64::new(TOTAL_FD_MAX),
+        }
+    }
 }
 
-lazy_static! {
-    // This holds the user registered handlers they want to have called when
-    // a close occurs.  I did this rather than return messy data structures
-    // from the close, exec, and exit handlers because it seemed cleaner...
-    #[derive(Debug)]
-    static ref CLOSEHANDLERTABLE: Mutex<CloseHandlers> = {
-        let c = CloseHandlers {
-            intermediate_handler:NULL_FUNC,
-            final_handler:NULL_FUNC,
-            unreal_handler:NULL_FUNC,
-        };
-        Mutex::new(c)
-    };
+impl FdTables {
+
+    /// Creates a new set of fdtables, which does not contain any cages.  The
+    /// per-fd payload is a `u64`.  Use `FdTables::<P>::default()` for some
+    /// other payload type `P`.
+    #[must_use] // must use the return value if you call it.
+    pub fn new() -> Self {
+        Self::default()
+    }
 }
 
-#[doc = include_str!("../docs/init_empty_cage.md")]
-pub fn init_empty_cage(cageid: u64) {
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+    /// See [`init_empty_cage`].
+    ///
+    /// # Panics
+    ///   See [`init_empty_cage`].
+    pub fn init_empty_cage(&self, cageid: u64) {
 
-    if fdtable.contains_key(&cageid) {
-        panic!("Known cageid in fdtable access");
-    }
+        let mut fdtable = self.fdtable.lock().unwrap();
 
-    fdtable.insert(cageid,HashMap::new());
-}
+        assert!(!fdtable.contains_key(&cageid) && !self.sharedtables.lock().unwrap().contains_key(&cageid),"Known cageid in fdtable access");
 
-#[doc = include_str!("../docs/translate_virtual_fd.md")]
-pub fn translate_virtual_fd(cageid: u64, virtualfd: u64) -> Result<u64, threei::RetVal> {
-    // Get the lock on the fdtable...  I'm not handling "poisoned locks" now
-    // where a thread holding the lock died...
-    let fdtable = GLOBALFDTABLE.lock().unwrap();
+        fdtable.insert(cageid,HashMap::new());
+    }
+
+    /// See [`translate_virtual_fd`].
+    ///
+    /// # Panics
+    ///   See [`translate_virtual_fd`].
+    ///
+    /// # Errors
+    ///   See [`translate_virtual_fd`].
+    pub fn translate_virtual_fd(&self, cageid: u64, virtualfd: u64) -> Result<FDTableEntry<P>, FdTableError> {
+        // Get the lock on the fdtable...  I'm not handling "poisoned locks" now
+        // where a thread holding the lock died...
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        // They should not be able to pass a new cage I don't know.  I should
+        // always have a table for each cage because each new cage is added at fork
+        // time
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        // Below condition checks if the virtualfd is out of bounds and if yes it throws an error
+        // Note that this assumes that all virtualfd numbers returned < FD_PER_PROCESS_MAX
+        if virtualfd >= FD_PER_PROCESS_MAX {
+            return Err(FdTableError::FdNotOpen);
+        }
 
-    // They should not be able to pass a new cage I don't know.  I should
-    // always have a table for each cage because each new cage is added at fork
-    // time
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+        match fdtable.get(&cageid).unwrap().get(&virtualfd) {
+            Some(tableentry) => Ok(tableentry.clone()),
+            None => Err(FdTableError::FdNotOpen),
+        }
     }
 
-    // Below condition checks if the virtualfd is out of bounds and if yes it throws an error
-    // Note that this assumes that all virtualfd numbers returned < FD_PER_PROCESS_MAX 
-    if virtualfd >= FD_PER_PROCESS_MAX {
-        return Err(threei::Errno::EBADFD as u64);
+    // This is fairly slow if I just iterate sequentially through numbers.
+    // However there are not that many to choose from.  I could pop from a list
+    // or a set as well...  Likely the best solution is to keep a count of the
+    // largest fd handed out and to just use this until you wrap.  This will be
+    // super fast for a normal cage and will be correct in the weird case.
+    // Right now, I'll just implement the slow path and will speed this up
+    // later, if needed.
+    /// See [`get_unused_virtual_fd`].
+    ///
+    /// # Panics
+    ///   See [`get_unused_virtual_fd`].
+    ///
+    /// # Errors
+    ///   See [`get_unused_virtual_fd`].
+    pub fn get_unused_virtual_fd(
+        &self,
+        cageid: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<u64, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        // Set up the entry so it has the right info...
+        // Note, a HashMap stores its data on the heap!  No need to box it...
+        // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
+        let myentry = FDTableEntry {
+            fdkind,
+            underfd,
+            should_cloexec,
+            perfdinfo,
+        };
+
+        let softlimit = self._soft_fd_limit(cageid);
+
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+        let virtualfd = self._insert_lowest_unused_fd(cageid, fdtable.get_mut(&cageid).unwrap(), 0, softlimit, myentry)?;
+        drop(fdtable);
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        Ok(virtualfd)
     }
 
-    return match fdtable.get(&cageid).unwrap().get(&virtualfd) {
-        Some(tableentry) => Ok(tableentry.realfd),
-        None => Err(threei::Errno::EBADFD as u64),
-    };
-}
+    // This is for F_DUPFD, which wants the lowest unused fd that is at least
+    // minfd.  The only difference from above is that a minfd past the limit
+    // is an error of its own...
+    /// See [`get_unused_virtual_fd_from`].
+    ///
+    /// # Panics
+    ///   See [`get_unused_virtual_fd_from`].
+    ///
+    /// # Errors
+    ///   See [`get_unused_virtual_fd_from`].
+    pub fn get_unused_virtual_fd_from(
+        &self,
+        cageid: u64,
+        minfd: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<u64, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        let softlimit = self._soft_fd_limit(cageid);
+
+        if minfd >= softlimit {
+            return Err(FdTableError::MinFdTooLarge);
+        }
 
-// This is fairly slow if I just iterate sequentially through numbers.
-// However there are not that many to choose from.  I could pop from a list
-// or a set as well...  Likely the best solution is to keep a count of the
-// largest fd handed out and to just use this until you wrap.  This will be
-// super fast for a normal cage and will be correct in the weird case.
-// Right now, I'll just implement the slow path and will speed this up
-// later, if needed.
-#[doc = include_str!("../docs/get_unused_virtual_fd.md")]
-pub fn get_unused_virtual_fd(
-    cageid: u64,
-    realfd: u64,
-    should_cloexec: bool,
-    optionalinfo: u64,
-) -> Result<u64, threei::RetVal> {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
-
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
-    }
-    // Set up the entry so it has the right info...
-    // Note, a HashMap stores its data on the heap!  No need to box it...
-    // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
-    let myentry = FDTableEntry {
-        realfd,
-        should_cloexec,
-        optionalinfo,
-    };
+        let myentry = FDTableEntry {
+            fdkind,
+            underfd,
+            should_cloexec,
+            perfdinfo,
+        };
 
-    let myfdmap = fdtable.get_mut(&cageid).unwrap();
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+        let virtualfd = self._insert_lowest_unused_fd(cageid, fdtable.get_mut(&cageid).unwrap(), minfd, softlimit, myentry)?;
+        drop(fdtable);
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        Ok(virtualfd)
+    }
 
-    // Check the fds in order.
-    for fdcandidate in 0..FD_PER_PROCESS_MAX {
-        // Get the entry if it's Vacant and assign it to e (so I can fill
-        // it in).
-        if let std::collections::hash_map::Entry::Vacant(e) = myfdmap.entry(fdcandidate) {
-            e.insert(myentry);
-            _increment_realfd(realfd);
-            return Ok(fdcandidate);
+    // Puts myentry in the lowest unused fd in [minfd, softlimit) of this
+    // cage's table and returns that fd.
+    fn _insert_lowest_unused_fd(&self, cageid:u64, myfdmap:&mut HashMap<u64,FDTableEntry<P>>, minfd:u64, softlimit:u64, myentry:FDTableEntry<P>) -> Result<u64, FdTableError> {
+        // Check the fds in order.  Only ones below the soft limit can be
+        // handed out.
+        for fdcandidate in minfd..softlimit {
+            // Get the entry if it's Vacant and assign it to e (so I can fill
+            // it in).
+            if let std::collections::hash_map::Entry::Vacant(e) = myfdmap.entry(fdcandidate) {
+                self._reserve_total_fds(1)?;
+                self._increment_fdcount(cageid, fdcandidate, &myentry);
+                e.insert(myentry);
+                return Ok(fdcandidate);
+            }
         }
+
+        // I must have checked all fds and failed to find one open.  Fail!
+        Err(FdTableError::TableFull)
     }
 
-    // I must have checked all fds and failed to find one open.  Fail!
-    Err(threei::Errno::EMFILE as u64)
-}
+    // The dup calls copy the entry while holding the lock, so another thread
+    // can't close (or replace) the old fd partway through.  The copy never
+    // inherits close-on-exec, unless dup3 asks for it.
+    /// See [`dup_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`dup_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`dup_virtualfd`].
+    pub fn dup_virtualfd(&self, cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
 
-// This is used for things like dup2, which need a specific fd...
-// If the requested_virtualfd is used, I close it...
-#[doc = include_str!("../docs/get_specific_virtual_fd.md")]
-pub fn get_specific_virtual_fd(
-    cageid: u64,
-    requested_virtualfd: u64,
-    realfd: u64,
-    should_cloexec: bool,
-    optionalinfo: u64,
-) -> Result<(), threei::RetVal> {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        let softlimit = self._soft_fd_limit(cageid);
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+        let myfdmap = fdtable.get_mut(&cageid).unwrap();
+
+        let Some(oldentry) = myfdmap.get(&oldvirtualfd) else {
+            return Err(FdTableError::BadFd);
+        };
+        let mut myentry = oldentry.clone();
+        myentry.should_cloexec = false;
+
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+        let virtualfd = self._insert_lowest_unused_fd(cageid, myfdmap, 0, softlimit, myentry)?;
+        drop(fdtable);
+        self._call_open_handlers(opened, OpenReason::Dup);
+        Ok(virtualfd)
     }
 
-    // If you ask for a FD number that is too large, I'm going to reject it.
-    // Note that, I need to use the FD_PER_PROCESS_MAX setting because this
-    // is also how I'm tracking how many values you have open.  If this
-    // changed, then these constants could be decoupled...
-    if requested_virtualfd > FD_PER_PROCESS_MAX {
-        return Err(threei::Errno::EBADF as u64);
+    /// See [`dup2_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`dup2_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`dup2_virtualfd`].
+    pub fn dup2_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64) -> Result<u64, FdTableError> {
+        self._dup_onto(cageid, oldvirtualfd, newvirtualfd, false)
     }
 
-    // Set up the entry so it has the right info...
-    // Note, a HashMap stores its data on the heap!  No need to box it...
-    // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
-    let myentry = FDTableEntry {
-        realfd,
-        should_cloexec,
-        optionalinfo,
-    };
+    /// See [`dup3_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`dup3_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`dup3_virtualfd`].
+    pub fn dup3_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64, should_cloexec: bool) -> Result<u64, FdTableError> {
+        // Unlike dup2, this is an error instead of doing nothing.
+        if oldvirtualfd == newvirtualfd {
+            return Err(FdTableError::DupToSameFd);
+        }
+        self._dup_onto(cageid, oldvirtualfd, newvirtualfd, should_cloexec)
+    }
+
+    // Copies oldvirtualfd's entry to newvirtualfd, closing whatever was there.
+    // This is like get_specific_virtual_fd, except the entry comes from the
+    // table.
+    fn _dup_onto(&self, cageid:u64, oldvirtualfd:u64, newvirtualfd:u64, should_cloexec:bool) -> Result<u64, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        let softlimit = self._soft_fd_limit(cageid);
 
-    // I moved this up so that if I decrement the same realfd, it calls
-    // the intermediate handler instead of the final one.
-    _increment_realfd(realfd);
+        let myfdmap = fdtable.get_mut(&cageid).unwrap();
 
-    // always add the new entry.  insert returns the old entry.
-    let myoptionentry = fdtable.get_mut(&cageid).unwrap().insert(requested_virtualfd,myentry);
-    drop(fdtable);
+        let Some(oldentry) = myfdmap.get(&oldvirtualfd) else {
+            return Err(FdTableError::BadFd);
+        };
 
-    // Close the old entry, if I need to...
-    if let Some(entry) = myoptionentry {
-        if entry.realfd != NO_REAL_FD {
-            _decrement_realfd(entry.realfd);
+        // dup2(fd, fd) does nothing if fd is open.
+        if oldvirtualfd == newvirtualfd {
+            return Ok(newvirtualfd);
         }
-        else {
-            // Let their code know this has been closed...
-            let unrealclosehandler = (*CLOSEHANDLERTABLE.lock().unwrap()).unreal_handler;
-            (unrealclosehandler)(entry.optionalinfo);
+
+        if newvirtualfd >= softlimit {
+            return Err(FdTableError::FdOutOfRange);
         }
-    }
 
-    Ok(())
-}
+        let mut myentry = oldentry.clone();
+        myentry.should_cloexec = should_cloexec;
 
-// We're just setting a flag here, so this should be pretty straightforward.
-#[doc = include_str!("../docs/set_cloexec.md")]
-pub fn set_cloexec(cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), threei::RetVal> {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+        // Replacing an open fd doesn't change the total number open, but
+        // _decrement_fdcount takes one off for the old entry below...
+        if myfdmap.contains_key(&newvirtualfd) {
+            self.totalfds.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self._reserve_total_fds(1)?;
+        }
+
+        // Before the insert, so if newvirtualfd already had the same
+        // (fdkind,underfd), closing it calls the intermediate handler.
+        self._increment_fdcount(cageid, newvirtualfd, &myentry);
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+        let myoptionentry = myfdmap.insert(newvirtualfd, myentry);
+        drop(fdtable);
+
+        self._call_open_handlers(opened, OpenReason::Dup);
+        // Close whatever was at newvirtualfd (after dropping the lock, since
+        // the close handlers may call back into this library).
+        if let Some(entry) = myoptionentry {
+            self._report_unhandled_closes(self._decrement_fdcount(cageid, newvirtualfd, entry));
+        }
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+        Ok(newvirtualfd)
     }
 
-    // Set the is_cloexec flag or return EBADFD, if that's missing...
-    return match fdtable.get_mut(&cageid).unwrap().get_mut(&virtualfd) {
-        Some(tableentry) => {
-            tableentry.should_cloexec = is_cloexec;
-            Ok(())
+    // This is used for things like dup2, which need a specific fd...
+    // If the requested_virtualfd is used, I close it...
+    /// See [`get_specific_virtual_fd`].
+    ///
+    /// # Panics
+    ///   See [`get_specific_virtual_fd`].
+    ///
+    /// # Errors
+    ///   See [`get_specific_virtual_fd`].
+    pub fn get_specific_virtual_fd(
+        &self,
+        cageid: u64,
+        requested_virtualfd: u64,
+        fdkind: u32,
+        underfd: u64,
+        should_cloexec: bool,
+        perfdinfo: P,
+    ) -> Result<(), FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        // If you ask for a FD number that is too large, I'm going to reject it.
+        // Like dup2, this is anything at or above the cage's soft limit.  (The
+        // soft limit is never above FD_PER_PROCESS_MAX, which is also the
+        // size of the table.)
+        if requested_virtualfd >= self._soft_fd_limit(cageid) {
+            return Err(FdTableError::FdOutOfRange);
         }
-        None => Err(threei::Errno::EBADFD as u64),
-    };
-}
 
-// Super easy, just return the optionalinfo field...
-#[doc = include_str!("../docs/get_optionalinfo.md")]
-pub fn get_optionalinfo(cageid: u64, virtualfd: u64) -> Result<u64, threei::RetVal> {
-    let fdtable = GLOBALFDTABLE.lock().unwrap();
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
-    }
+        // Set up the entry so it has the right info...
+        // Note, a HashMap stores its data on the heap!  No need to box it...
+        // https://doc.rust-lang.org/book/ch08-03-hash-maps.html#creating-a-new-hash-map
+        let myentry = FDTableEntry {
+            fdkind,
+            underfd,
+            should_cloexec,
+            perfdinfo,
+        };
 
-    return match fdtable.get(&cageid).unwrap().get(&virtualfd) {
-        Some(tableentry) => Ok(tableentry.optionalinfo),
-        None => Err(threei::Errno::EBADFD as u64),
-    };
-}
+        // Replacing an open fd doesn't change the total number open, but
+        // _decrement_fdcount takes one off for the old entry below...
+        if fdtable.get(&cageid).unwrap().contains_key(&requested_virtualfd) {
+            self.totalfds.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self._reserve_total_fds(1)?;
+        }
 
-// We're setting an opaque value here. This should be pretty straightforward.
-#[doc = include_str!("../docs/set_optionalinfo.md")]
-pub fn set_optionalinfo(
-    cageid: u64,
-    virtualfd: u64,
-    optionalinfo: u64,
-) -> Result<(), threei::RetVal> {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+        // I moved this up so that if I decrement the same (fdkind,underfd), it
+        // calls the intermediate handler instead of the last one.
+        self._increment_fdcount(cageid, requested_virtualfd, &myentry);
+        let mut opened = self._pending_opens();
+        opened.add(&myentry);
+
+        // always add the new entry.  insert returns the old entry.
+        let myoptionentry = fdtable.get_mut(&cageid).unwrap().insert(requested_virtualfd,myentry);
+        drop(fdtable);
+
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        // Close the old entry, if I need to...
+        if let Some(entry) = myoptionentry {
+            self._report_unhandled_closes(self._decrement_fdcount(cageid, requested_virtualfd, entry));
+        }
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+        Ok(())
     }
 
-    // Set optionalinfo or return EBADFD, if that's missing...
-    return match fdtable.get_mut(&cageid).unwrap().get_mut(&virtualfd) {
-        Some(tableentry) => {
-            tableentry.optionalinfo = optionalinfo;
-            Ok(())
+    // Everything is under the fdtable's lock, so the sender can't close the
+    // fds partway through, and the receiver gets all of them or none.
+    /// See [`transfer_virtualfds`].
+    ///
+    /// # Panics
+    ///   See [`transfer_virtualfds`].
+    ///
+    /// # Errors
+    ///   See [`transfer_virtualfds`].
+    pub fn transfer_virtualfds(&self, srccageid: u64, srcvirtualfds: &[u64], dstcageid: u64, should_cloexec: bool) -> Result<Vec<u64>, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let srccageid = self._check_cageid(&mut fdtable, srccageid)?;
+        let dstcageid = self._check_cageid(&mut fdtable, dstcageid)?;
+
+        let srcfdmap = fdtable.get(&srccageid).unwrap();
+        let mut entries = Vec::with_capacity(srcvirtualfds.len());
+        for virtualfd in srcvirtualfds {
+            let Some(entry) = srcfdmap.get(virtualfd) else {
+                return Err(FdTableError::BadFd);
+            };
+            let mut entry = entry.clone();
+            entry.should_cloexec = should_cloexec;
+            entries.push(entry);
         }
-        None => Err(threei::Errno::EBADFD as u64),
-    };
-}
 
-// Helper function used for fork...  Copies an fdtable for another process
-#[doc = include_str!("../docs/copy_fdtable_for_cage.md")]
-pub fn copy_fdtable_for_cage(srccageid: u64, newcageid: u64) -> Result<(), threei::Errno> {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+        let softlimit = self._soft_fd_limit(dstcageid);
+        let dstfdmap = fdtable.get_mut(&dstcageid).unwrap();
+        // The lowest unused fds, if there are enough of them.
+        let newvirtualfds: Vec<u64> = (0..softlimit)
+            .filter(|virtualfd| !dstfdmap.contains_key(virtualfd))
+            .take(entries.len())
+            .collect();
+        if newvirtualfds.len() < entries.len() {
+            return Err(FdTableError::TableFull);
+        }
+        self._reserve_total_fds(entries.len() as u64)?;
 
-    if !fdtable.contains_key(&srccageid) {
-        panic!("Unknown srccageid in fdtable access");
+        let mut opened = self._pending_opens();
+        for (&virtualfd, entry) in newvirtualfds.iter().zip(entries) {
+            self._increment_fdcount(dstcageid, virtualfd, &entry);
+            opened.add(&entry);
+            dstfdmap.insert(virtualfd, entry);
+        }
+        drop(fdtable);
+        self._call_open_handlers(opened, OpenReason::Transfer);
+        Ok(newvirtualfds)
     }
-    if fdtable.contains_key(&newcageid) {
-        panic!("Known newcageid in fdtable access");
+
+    // The policy and audit functions are called without any of my locks
+    // held, so they can call back into the fdtables (to look up a cage's fds,
+    // for example).
+    /// See [`getfd_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`getfd_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`getfd_virtualfd`].
+    pub fn getfd_virtualfd(&self, requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+        let result = self._getfd_virtualfd(requestercageid, targetcageid, targetvirtualfd);
+        let audit = Arc::clone(&self.getfdhooks.lock().unwrap().audit);
+        audit(requestercageid, targetcageid, targetvirtualfd, result);
+        result
     }
 
-    // Insert a copy and ensure it didn't exist...
-    let hmcopy = fdtable.get(&srccageid).unwrap().clone();
+    fn _getfd_virtualfd(&self, requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+        {
+            let mut fdtable = self.fdtable.lock().unwrap();
+            self._check_cageid(&mut fdtable, requestercageid)?;
+            self._check_cageid(&mut fdtable, targetcageid)?;
+        }
 
-    // increment the reference to items in the fdtable appropriately...
-    for v in fdtable.get(&srccageid).unwrap().values() {
-        if v.realfd != NO_REAL_FD {
-            _increment_realfd(v.realfd);
+        // Like pidfd_getfd, the policy is checked before the fd, so a cage
+        // which isn't allowed can't find out which fds are open.
+        let policy = Arc::clone(&self.getfdhooks.lock().unwrap().policy);
+        if !policy(requestercageid, targetcageid, targetvirtualfd) {
+            return Err(FdTableError::PermissionDenied);
         }
+        // pidfd_getfd always sets close on exec on the new fd.
+        Ok(self.transfer_virtualfds(targetcageid, &[targetvirtualfd], requestercageid, true)?[0])
     }
 
-    // insert the new table...
-    assert!(fdtable.insert(newcageid, hmcopy).is_none());
-    Ok(())
-    // I'm not going to bother to check the number of fds used overall yet...
-    //    Err(threei::Errno::EMFILE as u64),
-}
-
-// This is mostly used in handling exit, etc.  Returns the HashMap
-// for the cage.
-#[doc = include_str!("../docs/remove_cage_from_fdtable.md")]
-pub fn remove_cage_from_fdtable(cageid: u64) {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+    // We're just setting a flag here, so this should be pretty straightforward.
+    /// See [`set_cloexec`].
+    ///
+    /// # Panics
+    ///   See [`set_cloexec`].
+    ///
+    /// # Errors
+    ///   See [`set_cloexec`].
+    pub fn set_cloexec(&self, cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), FdTableError> {
+        self.update_virtualfd_entry(cageid, virtualfd, |entry| entry.should_cloexec = is_cloexec)
+    }
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+    // We're setting an opaque value here. This should be pretty straightforward.
+    /// See [`set_perfdinfo`].
+    ///
+    /// # Panics
+    ///   See [`set_perfdinfo`].
+    ///
+    /// # Errors
+    ///   See [`set_perfdinfo`].
+    pub fn set_perfdinfo(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        perfdinfo: P,
+    ) -> Result<(), FdTableError> {
+        self.update_virtualfd_entry(cageid, virtualfd, |entry| entry.perfdinfo = perfdinfo)
     }
 
-    let cagetable = fdtable.remove(&cageid).unwrap();
-    drop(fdtable);
+    // func runs while I hold the fdtable lock, so nothing else can see or
+    // change the entry partway through.
+    /// See [`update_virtualfd_entry`].
+    ///
+    /// # Panics
+    ///   See [`update_virtualfd_entry`].
+    ///
+    /// # Errors
+    ///   See [`update_virtualfd_entry`].
+    pub fn update_virtualfd_entry<R>(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        func: impl FnOnce(&mut FDTableEntry<P>) -> R,
+    ) -> Result<R, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        // return EBADFD, if the fd is missing...
+        let Some(entry) = fdtable.get_mut(&cageid).unwrap().get_mut(&virtualfd) else {
+            return Err(FdTableError::FdNotOpen);
+        };
 
-    // decrement the reference to items in the fdtable appropriately...
-    for v in cagetable.values() {
-        if v.realfd != NO_REAL_FD {
-            _decrement_realfd(v.realfd);
-        }
-        else {
-            // Let their code know this has been closed...
-            let unrealclosehandler = CLOSEHANDLERTABLE.lock().unwrap().unreal_handler;
-            (unrealclosehandler)(v.optionalinfo);
+        // I need the old entry for the close handler, if func changes what
+        // it refers to.
+        let oldentry = entry.clone();
+        let retval = func(entry);
+
+        if (entry.fdkind, entry.underfd) == (oldentry.fdkind, oldentry.underfd) {
+            return Ok(retval);
         }
+
+        // This is like replacing the fd in get_specific_virtual_fd.  The new
+        // (fdkind,underfd) gets a reference and the old one loses one.  The
+        // number of open fds doesn't change, but _decrement_fdcount takes one
+        // off for the old entry below...
+        self.totalfds.fetch_add(1, Ordering::Relaxed);
+        self._increment_fdcount(cageid, virtualfd, entry);
+        // Only taken here, since most updates (like set_cloexec) don't add a
+        // reference.
+        let mut opened = self._pending_opens();
+        opened.add(entry);
+        drop(fdtable);
+
+        // After dropping the lock, since the handlers may call back into
+        // this library.
+        self._call_open_handlers(opened, OpenReason::Allocate);
+        self._report_unhandled_closes(self._decrement_fdcount(cageid, virtualfd, oldentry));
+
+        Ok(retval)
     }
 
-}
+    /// See [`compare_and_swap_virtualfd_entry`].
+    ///
+    /// # Panics
+    ///   See [`compare_and_swap_virtualfd_entry`].
+    ///
+    /// # Errors
+    ///   See [`compare_and_swap_virtualfd_entry`].
+    pub fn compare_and_swap_virtualfd_entry(
+        &self,
+        cageid: u64,
+        virtualfd: u64,
+        expected: &FDTableEntry<P>,
+        new: FDTableEntry<P>,
+    ) -> Result<bool, FdTableError>
+    where
+        P: PartialEq,
+    {
+        self.update_virtualfd_entry(cageid, virtualfd, |entry| {
+            if entry != expected {
+                return false;
+            }
+            *entry = new;
+            true
+        })
+    }
 
-// This removes all fds with the should_cloexec flag set.  They are returned
-// in a new hashmap...
-#[doc = include_str!("../docs/empty_fds_for_exec.md")]
-pub fn empty_fds_for_exec(cageid: u64) {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+    /// See [`get_status_flags`].
+    ///
+    /// # Panics
+    ///   See [`get_status_flags`].
+    ///
+    /// # Errors
+    ///   See [`get_status_flags`].
+    pub fn get_status_flags(&self, cageid: u64, virtualfd: u64) -> Result<i32, FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.statusflags)
+    }
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+    /// See [`set_status_flags`].
+    ///
+    /// # Panics
+    ///   See [`set_status_flags`].
+    ///
+    /// # Errors
+    ///   See [`set_status_flags`].
+    pub fn set_status_flags(&self, cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.statusflags = statusflags)
     }
 
-    // Create this hashmap through an lambda that checks should_cloexec...
-    // See: https://doc.rust-lang.org/std/collections/struct.HashMap.html#method.extract_if
+    /// See [`get_offset`].
+    ///
+    /// # Panics
+    ///   See [`get_offset`].
+    ///
+    /// # Errors
+    ///   See [`get_offset`].
+    pub fn get_offset(&self, cageid: u64, virtualfd: u64) -> Result<u64, FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.offset)
+    }
 
-/*    fdtable
-        .get_mut(&cageid)
-        .unwrap()
-        .extract_if(|_k, v| v.should_cloexec)
-        .collect()*/
+    /// See [`set_offset`].
+    ///
+    /// # Panics
+    ///   See [`set_offset`].
+    ///
+    /// # Errors
+    ///   See [`set_offset`].
+    pub fn set_offset(&self, cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError> {
+        self._with_description(cageid, virtualfd, |description| description.offset = offset)
+    }
 
-    // I'm writing the below code to avoid using the extract_if experimental 
-    // nightly function...
-    let thiscagefdtable = fdtable.get_mut(&cageid).unwrap();
+    // Calls func on the description virtualfd refers to.
+    fn _with_description<R>(&self, cageid:u64, virtualfd:u64, func:impl FnOnce(&mut FileDescription) -> R) -> Result<R,FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
 
-    let mut without_cloexec_hm:HashMap<u64,FDTableEntry> = HashMap::new();
-    // I bother to put this in a hashmap so I can call the closehandlers
-    // all after I have re-inserted everything.  This ensures the state
-    // is consistent.  I only need the values, not the keys...
-    let mut with_cloexec_vec:Vec<FDTableEntry> = Vec::new();
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
 
-    for (k,v) in thiscagefdtable.drain() {
-        if v.should_cloexec {
-            with_cloexec_vec.push(v);
-        }
-        else{
-            without_cloexec_hm.insert(k,v);
-        }
+        // I hold the fdtable lock while I use the description, so the fd
+        // can't be closed (and the description dropped) partway through.
+        let Some(entry) = fdtable.get(&cageid).unwrap().get(&virtualfd) else {
+            return Err(FdTableError::FdNotOpen);
+        };
 
+        let mut fdcount = self.fdcount.lock().unwrap();
+        Ok(func(fdcount.get_mut(&(entry.fdkind, entry.underfd)).unwrap()))
     }
 
-    // Put the ones without_cloexec back in the hashmap since they shouldn't 
-    // be closed...
-    fdtable.insert(cageid,without_cloexec_hm);
-    // Release the lock...
-    drop(fdtable);
+    /// See [`get_fd_limit`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_limit`].
+    ///
+    /// # Errors
+    ///   See [`get_fd_limit`].
+    pub fn get_fd_limit(&self, cageid: u64) -> Result<(u64, u64), FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+        Ok(self.fdlimits.lock().unwrap().get(&cageid).map_or((FD_PER_PROCESS_MAX,FD_PER_PROCESS_MAX), |limits| *limits))
+    }
 
-    // Now call the close handlers on the others...
-    for v in with_cloexec_vec {
-        if v.realfd == NO_REAL_FD {
-            // Let their code know this has been closed...  I get the handler
-            // repeatedly in case they change it during execution of another
-            // handler...
-            let closehandlerunreal = CLOSEHANDLERTABLE.lock().unwrap().unreal_handler;
-            (closehandlerunreal)(v.optionalinfo);
+    /// See [`set_fd_limit`].
+    ///
+    /// # Panics
+    ///   See [`set_fd_limit`].
+    ///
+    /// # Errors
+    ///   See [`set_fd_limit`].
+    pub fn set_fd_limit(&self, cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        // Like setrlimit, the soft limit can't be above the hard limit...
+        if soft > hard {
+            return Err(FdTableError::InvalidLimit);
         }
-        else {
-            // Let the helper tell the user and decrement the count
-            _decrement_realfd(v.realfd);
+        // ... and the hard limit can't be above the size of the table.
+        if hard > FD_PER_PROCESS_MAX {
+            return Err(FdTableError::LimitTooLarge);
         }
-    }
 
-}
+        // Note that I leave any fds at or above the new soft limit alone.
+        // Linux does the same.  They stay open, but can't be handed out again.
+        self.fdlimits.lock().unwrap().insert(cageid, (soft,hard));
+        Ok(())
+    }
 
-// returns a copy of the fdtable for a cage.  Useful helper function for a
-// caller that needs to examine the table.  Likely could be more efficient by
-// letting the caller borrow this...
-#[doc = include_str!("../docs/return_fdtable_copy.md")]
-pub fn return_fdtable_copy(cageid: u64) -> HashMap<u64, FDTableEntry> {
-    let fdtable = GLOBALFDTABLE.lock().unwrap();
+    /// See [`set_total_fd_limit`].
+    pub fn set_total_fd_limit(&self, limit: u64) {
+        // If this is below the number already open, nothing is closed.  New
+        // fds just fail until enough are closed.
+        self.totalfdlimit.store(limit, Ordering::Relaxed);
+    }
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+    /// See [`get_total_fd_usage`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_total_fd_usage(&self) -> (u64, u64) {
+        (self.totalfds.load(Ordering::Relaxed), self.totalfdlimit.load(Ordering::Relaxed))
     }
 
-    fdtable.get(&cageid).unwrap().clone()
-}
+    // Helper function used for fork...  Copies an fdtable for another process
+    /// See [`copy_fdtable_for_cage`].
+    ///
+    /// # Panics
+    ///   See [`copy_fdtable_for_cage`].
+    ///
+    /// # Errors
+    ///   See [`copy_fdtable_for_cage`].
+    pub fn copy_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let srccageid = self._check_cageid(&mut fdtable, srccageid)?;
+        assert!(!fdtable.contains_key(&newcageid) && !self.sharedtables.lock().unwrap().contains_key(&newcageid),"Known cageid in fdtable access");
+
+        // Insert a copy and ensure it didn't exist...
+        let hmcopy = fdtable.get(&srccageid).unwrap().clone();
+
+        // Every copied fd counts against the total limit...
+        self._reserve_total_fds(hmcopy.len() as u64)?;
+
+        // increment the reference to items in the fdtable appropriately...
+        let mut opened = self._pending_opens();
+        for (&virtualfd, v) in &hmcopy {
+            self._increment_fdcount(newcageid, virtualfd, v);
+            opened.add(v);
+        }
 
-/******************* CLOSE SPECIFIC FUNCTIONALITY *******************/
+        // insert the new table...
+        assert!(fdtable.insert(newcageid, hmcopy).is_none());
 
-// Helper for close.  Returns a tuple of realfd, number of references
-// remaining.
-#[doc = include_str!("../docs/close_virtualfd.md")]
-pub fn close_virtualfd(cageid:u64, virtfd:u64) -> Result<(),threei::RetVal> {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap();
+        // The child inherits the limits too...
+        let mut fdlimits = self.fdlimits.lock().unwrap();
+        if let Some(&limits) = fdlimits.get(&srccageid) {
+            fdlimits.insert(newcageid, limits);
+        }
+        drop(fdlimits);
+        drop(fdtable);
 
-    if !fdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+        self._call_open_handlers(opened, OpenReason::Fork);
+        Ok(())
     }
 
-    // Remove this item from the table (and inspect it)
-    let thisoption = fdtable.get_mut(&cageid).unwrap().remove(&virtfd);
-    drop(fdtable);
+    // Helper function used for clone(CLONE_FILES), which is how threads are
+    // made...  The new cage uses the same table rather than a copy of it.
+    /// See [`share_fdtable_for_cage`].
+    ///
+    /// # Panics
+    ///   See [`share_fdtable_for_cage`].
+    ///
+    /// # Errors
+    ///   See [`share_fdtable_for_cage`].
+    pub fn share_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let srccageid = self._check_cageid(&mut fdtable, srccageid)?;
+        let mut sharedtables = self.sharedtables.lock().unwrap();
+        assert!(!fdtable.contains_key(&newcageid) && !sharedtables.contains_key(&newcageid),"Known cageid in fdtable access");
+
+        // The fds are still in one table, so their reference counts (and the
+        // total number open) don't change.
+        sharedtables.insert(newcageid, srccageid);
+        Ok(())
+    }
 
-    match thisoption {
-        Some(entry) =>
-            if entry.realfd == NO_REAL_FD {
-                // Let their code know this has been closed...
-                let closehandlerunreal = CLOSEHANDLERTABLE.lock().unwrap().unreal_handler;
-                let optionalinfo = entry.optionalinfo;
-                (closehandlerunreal)(optionalinfo);
-                Ok(())
-            }
-            else {
-                _decrement_realfd(entry.realfd);
-                Ok(())
-            }
-        None => Err(threei::Errno::EBADFD as u64),
+    /// See [`unshare_fdtable_for_cage`].
+    ///
+    /// # Panics
+    ///   See [`unshare_fdtable_for_cage`].
+    ///
+    /// # Errors
+    ///   See [`unshare_fdtable_for_cage`].
+    pub fn unshare_fdtable_for_cage(&self, cageid: u64) -> Result<(), FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let tablecageid = self._check_cageid(&mut fdtable, cageid)?;
+        let mut opened = self._pending_opens();
+        self._unshare(&mut fdtable, cageid, tablecageid, false, &mut opened);
+        drop(fdtable);
+        self._call_open_handlers(opened, OpenReason::Fork);
+        Ok(())
     }
-}
 
+    // Gives cageid a table which no other cage uses.  tablecageid is where
+    // its table is now (from _check_cageid).  For exec, a copy made for
+    // cageid leaves out the close-on-exec fds.  The caller holds the fdtable
+    // lock, so no one sees this partway through.  The references the copy
+    // adds go in opened.
+    fn _unshare(&self, fdtable:&mut HashMap<u64,HashMap<u64,FDTableEntry<P>>>, cageid:u64, tablecageid:u64, forexec:bool, opened:&mut PendingOpens<P>) {
+        if tablecageid == cageid {
+            // The table is stored under this cage, so the cages which share
+            // it (if any) get the copy instead.
+            let sharers = self._sharers_of(cageid);
+            if let Some(&newtablecageid) = sharers.first() {
+                self._copy_shared_table(fdtable, cageid, newtablecageid, false, opened);
+                self._repoint_sharers(&sharers);
+            }
+        }
+        else {
+            self._copy_shared_table(fdtable, tablecageid, cageid, forexec, opened);
+            self.sharedtables.lock().unwrap().remove(&cageid);
+        }
+    }
 
-// Register a series of helpers to be called for close.  Can be called
-// multiple times to override the older helpers.
-#[doc = include_str!("../docs/register_close_handlers.md")]
-pub fn register_close_handlers(intermediate_handler: fn(u64), final_handler: fn(u64), unreal_handler: fn(u64)) {
-    // Unlock the table and set the handlers...
-    // TODO: I made a serious attempt to try to keep closehandlers that call
-    // close, etc. from deadlocking the system.  More testing, etc. is needed.
-    let mut closehandlers = CLOSEHANDLERTABLE.lock().unwrap();
-    closehandlers.intermediate_handler = intermediate_handler;
-    closehandlers.final_handler = final_handler;
-    closehandlers.unreal_handler = unreal_handler;
-}
+    // The cages which share the table stored under tablecageid, in order.
+    fn _sharers_of(&self, tablecageid:u64) -> Vec<u64> {
+        let mut sharers: Vec<u64> = self.sharedtables.lock().unwrap().iter()
+            .filter(|(_, &thistablecageid)| thistablecageid == tablecageid)
+            .map(|(&sharer, _)| sharer)
+            .collect();
+        sharers.sort_unstable();
+        sharers
+    }
 
-// Helpers to track the count of times each realfd is used
-#[doc(hidden)]
-fn _decrement_realfd(realfd:u64) -> u64 {
-    // Do nothing if it's not a realfd...
-    assert!(realfd != NO_REAL_FD, "Called _decrement_realfd with NO_REAL_FD");
+    // The first of the sharers is now the cage their table is stored under
+    // (the caller already put it in the fdtable), so the rest use its table.
+    fn _repoint_sharers(&self, sharers:&[u64]) {
+        let Some((&newtablecageid, others)) = sharers.split_first() else {
+            return;
+        };
+        let mut sharedtables = self.sharedtables.lock().unwrap();
+        for &sharer in others {
+            sharedtables.insert(sharer, newtablecageid);
+        }
+        sharedtables.remove(&newtablecageid);
+    }
 
-    // Get this table's lock...
-    let mut realfdcount = GLOBALREALFDCOUNT.lock().unwrap();
+    // Puts a copy of the table stored under tablecageid (and its limits) in
+    // the fdtable under newcageid.  Unlike fork, this isn't held to the total
+    // limit, since nothing new is opened (and exec can't fail).
+    fn _copy_shared_table(&self, fdtable:&mut HashMap<u64,HashMap<u64,FDTableEntry<P>>>, tablecageid:u64, newcageid:u64, forexec:bool, opened:&mut PendingOpens<P>) {
+        let mut hmcopy = fdtable.get(&tablecageid).unwrap().clone();
 
-    let newcount:u64 = realfdcount.get(&realfd).unwrap() - 1;
-    let closehandlers = CLOSEHANDLERTABLE.lock().unwrap();
-    let intermediateclosehandler = closehandlers.intermediate_handler;
-    let finalclosehandler = closehandlers.final_handler;
-    // release the lock...
-    drop(closehandlers);
+        if forexec {
+            hmcopy.retain(|_, entry| !entry.should_cloexec);
+        }
 
-    if newcount > 0 {
-        realfdcount.insert(realfd,newcount);
-        // Need to drop locks to call the handlers or else will deadlock...
-        drop(realfdcount);
+        self.totalfds.fetch_add(hmcopy.len() as u64, Ordering::Relaxed);
+        for (&virtualfd, entry) in &hmcopy {
+            self._increment_fdcount(newcageid, virtualfd, entry);
+            opened.add(entry);
+        }
 
-        (intermediateclosehandler)(realfd);
-    }
-    else {
-        // Remove before calling their close handler in case they do operations
-        // inside the close handler which create / close fds...
-        realfdcount.remove(&realfd);
-        // Need to drop locks to call the handlers or else will deadlock...
-        drop(realfdcount);
+        fdtable.insert(newcageid, hmcopy);
 
-        (finalclosehandler)(realfd);
+        let mut fdlimits = self.fdlimits.lock().unwrap();
+        if let Some(&limits) = fdlimits.get(&tablecageid) {
+            fdlimits.insert(newcageid, limits);
+        }
     }
-    newcount
-}
 
-// Helpers to track the count of times each realfd is used
-#[doc(hidden)]
-fn _increment_realfd(realfd:u64) -> u64 {
-    if realfd == NO_REAL_FD {
-        return 0
-    }
+    // This is mostly used in handling exit, etc.  Returns the HashMap
+    // for the cage.
+    /// See [`remove_cage_from_fdtable`].
+    ///
+    /// # Panics
+    ///   See [`remove_cage_from_fdtable`].
+    pub fn remove_cage_from_fdtable(&self, cageid: u64) {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        // With the Error policy, an unknown cage has no fds to remove...
+        let Ok(tablecageid) = self._check_cageid(&mut fdtable, cageid) else {
+            return;
+        };
 
-    // Get this table's lock...
-    let mut realfdcount = GLOBALREALFDCOUNT.lock().unwrap();
+        // A cage which uses another cage's table just stops using it.  The
+        // fds are still open for the others.
+        if tablecageid != cageid {
+            self.sharedtables.lock().unwrap().remove(&cageid);
+            return;
+        }
 
-    // Get a mutable reference to the entry so we can update it.
-    return match realfdcount.get_mut(&realfd) {
-        Some(count) => {
-            *count += 1;
-            *count
+        let cagetable = fdtable.remove(&cageid).unwrap();
+        let limits = self.fdlimits.lock().unwrap().remove(&cageid);
+
+        // If other cages share this table, it is handed to the first of them
+        // instead, and nothing is closed.  (I hold the fdtable lock until
+        // they are all pointed at it, so none of them can find it missing.)
+        let sharers = self._sharers_of(cageid);
+        if let Some(&newtablecageid) = sharers.first() {
+            let mut fdcount = self.fdcount.lock().unwrap();
+            for (&virtualfd, entry) in &cagetable {
+                fdcount.get_mut(&(entry.fdkind, entry.underfd)).unwrap().move_reference(cageid, newtablecageid, virtualfd);
+            }
+            drop(fdcount);
+            fdtable.insert(newtablecageid, cagetable);
+            if let Some(limits) = limits {
+                self.fdlimits.lock().unwrap().insert(newtablecageid, limits);
+            }
+            self._repoint_sharers(&sharers);
+            return;
         }
-        None => {
-            realfdcount.insert(realfd, 1);
-            1
+        drop(fdtable);
+
+        // decrement the reference to items in the fdtable appropriately...
+        let mut unhandled = Vec::new();
+        for (virtualfd, v) in cagetable {
+            unhandled.extend(self._decrement_fdcount(cageid, virtualfd, v));
         }
+        self._report_unhandled_closes(unhandled);
+
     }
-}
 
-/***************   Code for handling select() ****************/
+    // This removes all fds with the should_cloexec flag set.  They are returned
+    // in a new hashmap...
+    /// See [`empty_fds_for_exec`].
+    ///
+    /// # Panics
+    ///   See [`empty_fds_for_exec`].
+    pub fn empty_fds_for_exec(&self, cageid: u64) {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        // With the Error policy, an unknown cage has no fds to close...
+        let Ok(tablecageid) = self._check_cageid(&mut fdtable, cageid) else {
+            return;
+        };
 
-use libc::fd_set;
-use std::collections::HashSet;
-use std::cmp;
-use std::mem;
+        // Like Linux, exec gives the cage a table of its own first.  If it
+        // used another cage's table, its copy just leaves out the
+        // close-on-exec fds.  Those are still open for the other cages, so
+        // there is nothing to close.
+        let mut opened = self._pending_opens();
+        self._unshare(&mut fdtable, cageid, tablecageid, true, &mut opened);
+        if tablecageid != cageid {
+            drop(fdtable);
+            self._call_open_handlers(opened, OpenReason::Fork);
+            return;
+        }
 
-// Helper to get an empty fd_set.  Helper function to isolate unsafe code,
-// etc.
-#[doc(hidden)]
-pub fn _init_fd_set() -> fd_set {
-    let raw_fd_set:fd_set;
-    unsafe {
-        let mut this_fd_set = mem::MaybeUninit::<libc::fd_set>::uninit();
-        libc::FD_ZERO(this_fd_set.as_mut_ptr());
-        raw_fd_set = this_fd_set.assume_init()
-    }
-    raw_fd_set
-}
+        // Create this hashmap through an lambda that checks should_cloexec...
+        // See: https://doc.rust-lang.org/std/collections/struct.HashMap.html#method.extract_if
 
-#[doc(hidden)]
-pub fn _fd_set(fd:u64, thisfdset:&mut fd_set) {
-    unsafe{libc::FD_SET(fd as i32,thisfdset)}
-}
+    /*    fdtable
+            .get_mut(&cageid)
+            .unwrap()
+            .extract_if(|_k, v| v.should_cloexec)
+            .collect()*/
 
-#[doc(hidden)]
-pub fn _fd_isset(fd:u64, thisfdset:&fd_set) -> bool {
-    unsafe{libc::FD_ISSET(fd as i32,thisfdset)}
-}
+        // I'm writing the below code to avoid using the extract_if experimental
+        // nightly function...
+        let thiscagefdtable = fdtable.get_mut(&cageid).unwrap();
 
-// Computes the bitmodifications and returns a (maxnfds, unrealset) tuple...
-#[doc(hidden)]
-fn _do_bitmods(myfdmap:HashMap<u64,FDTableEntry>, nfds:u64, infdset: fd_set, thisfdset: &mut fd_set, mappingtable: &mut HashMap<u64,u64>) -> Result<(u64,HashSet<(u64,u64)>),threei::RetVal> {
-    let mut unrealhashset:HashSet<(u64,u64)> = HashSet::new();
-    // Iterate through the infdset and set those values as is appropriate
-    let mut highestpos = 0;
-
-    // Clippy is somehow missing how pos is using bit.
-    #[allow(clippy::needless_range_loop)]
-    for bit in 0..nfds as usize {
-        let pos = bit as u64;
-        if _fd_isset(pos,&infdset) {
-            if let Some(entry) = myfdmap.get(&pos) {
-                if entry.realfd == NO_REAL_FD {
-                    unrealhashset.insert((pos,entry.optionalinfo));
-                }
-                else {
-                    mappingtable.insert(entry.realfd, pos);
-                    _fd_set(entry.realfd,thisfdset);
-                    // I add one because select expects nfds to be the max+1
-                    highestpos = cmp::max(highestpos, entry.realfd+1);
-                }
+        let mut without_cloexec_hm:HashMap<u64,FDTableEntry<P>> = HashMap::new();
+        // I bother to put this in a hashmap so I can call the closehandlers
+        // all after I have re-inserted everything.  This ensures the state
+        // is consistent.  I only need the values, not the keys...
+        let mut with_cloexec_vec:Vec<(u64,FDTableEntry<P>)> = Vec::new();
+
+        for (k,v) in thiscagefdtable.drain() {
+            if v.should_cloexec {
+                with_cloexec_vec.push((k,v));
             }
-            else {
-                return Err(threei::Errno::EINVAL as u64);
+            else{
+                without_cloexec_hm.insert(k,v);
             }
+
         }
-    }
-    Ok((highestpos,unrealhashset))
-}
 
-// helper to call before calling select beneath you.  Translates your virtfds
-// to realfds.
-// See: https://man7.org/linux/man-pages/man2/select.2.html for details /
-// corner cases about the arguments.
+        // Put the ones without_cloexec back in the hashmap since they shouldn't
+        // be closed...
+        fdtable.insert(cageid,without_cloexec_hm);
+        // Release the lock...
+        drop(fdtable);
 
-// I hate doing these, but don't know how to make this interface better...
-#[allow(clippy::type_complexity)]
-#[allow(clippy::too_many_arguments)]
-#[doc = include_str!("../docs/get_real_bitmasks_for_select.md")]
-pub fn get_real_bitmasks_for_select(cageid:u64, nfds:u64, readbits:Option<fd_set>, writebits:Option<fd_set>, exceptbits:Option<fd_set>) -> Result<(u64, Option<fd_set>, Option<fd_set>, Option<fd_set>, [HashSet<(u64,u64)>;3], HashMap<u64,u64>),threei::RetVal> {
+        // The cages which shared the table got a copy (with these fds), so
+        // that happens before they are closed here.
+        self._call_open_handlers(opened, OpenReason::Fork);
+
+        // Now call the close handlers on the others...
+        let mut unhandled = Vec::new();
+        for (k,v) in with_cloexec_vec {
+            // Let the helper tell the user and decrement the count
+            unhandled.extend(self._decrement_fdcount(cageid, k, v));
+        }
+        self._report_unhandled_closes(unhandled);
 
-    if nfds >= FD_PER_PROCESS_MAX {
-        return Err(threei::Errno::EINVAL as u64);
     }
 
-    let globfdtable = GLOBALFDTABLE.lock().unwrap();
+    // returns a copy of the fdtable for a cage.  Useful helper function for a
+    // caller that needs to examine the table.  Likely could be more efficient by
+    // letting the caller borrow this...
+    /// See [`return_fdtable_copy`].
+    ///
+    /// # Panics
+    ///   See [`return_fdtable_copy`].
+    #[must_use] // must use the return value if you call it.
+    pub fn return_fdtable_copy(&self, cageid: u64) -> HashMap<u64, FDTableEntry<P>> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        // With the Error policy, an unknown cage has no fds...
+        let Ok(cageid) = self._check_cageid(&mut fdtable, cageid) else {
+            return HashMap::new();
+        };
+
+        fdtable.get(&cageid).unwrap().clone()
+    }
 
-    if !globfdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+    // func is called while I hold the lock on the cage's table, so it sees a
+    // consistent table and nothing is copied.
+    /// See [`for_each_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`for_each_virtualfd`].
+    pub fn for_each_virtualfd(&self, cageid: u64, func: impl FnMut(u64, &FDTableEntry<P>)) {
+        self.for_each_virtualfd_in_range(cageid, 0, u64::MAX, None, func);
     }
 
-    let mut unrealarray:[HashSet<(u64,u64)>;3] = [HashSet::new(),HashSet::new(),HashSet::new()];
-    let mut mappingtable:HashMap<u64,u64> = HashMap::new();
-    let mut newnfds = 0;
+    /// See [`for_each_virtualfd_in_range`].
+    ///
+    /// # Panics
+    ///   See [`for_each_virtualfd_in_range`].
+    pub fn for_each_virtualfd_in_range(
+        &self,
+        cageid: u64,
+        first: u64,
+        last: u64,
+        fdkind: Option<u32>,
+        mut func: impl FnMut(u64, &FDTableEntry<P>),
+    ) {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        // With the Error policy, an unknown cage has no fds...
+        let Ok(cageid) = self._check_cageid(&mut fdtable, cageid) else {
+            return;
+        };
 
-    // putting results in a vec was the cleanest way I found to do this..
-    let mut resultvec = Vec::new();
+        let thiscagefdtable = fdtable.get(&cageid).unwrap();
 
-    for (unrealoffset, inset) in [readbits,writebits, exceptbits].into_iter().enumerate() {
-        match inset {
-            Some(virtualbits) => {
-                let mut retset = _init_fd_set();
-                let (thisnfds,myunrealhashset) = _do_bitmods(globfdtable.get(&cageid).unwrap().clone(),nfds,virtualbits, &mut retset,&mut mappingtable)?;
-                resultvec.push(Some(retset));
-                newnfds = cmp::max(thisnfds, newnfds);
-                unrealarray[unrealoffset] = myunrealhashset;
-            }
-            None => {
-                // This item is null.  No unreal items
-                resultvec.push(None);
-                unrealarray[unrealoffset] = HashSet::new();
+        // A HashMap isn't in fd order, so I look up each fd in the range
+        // instead of iterating over it.  Nothing is open past the end of the
+        // table, so I stop there (last is often !0).
+        for virtualfd in first..=cmp::min(last, FD_PER_PROCESS_MAX - 1) {
+            if let Some(entry) = thiscagefdtable.get(&virtualfd) {
+                if fdkind.is_none_or(|kind| kind == entry.fdkind) {
+                    func(virtualfd, entry);
+                }
             }
         }
     }
+}
 
-    Ok((newnfds, resultvec[0], resultvec[1], resultvec[2], unrealarray, mappingtable))
+/******************* QUERY FUNCTIONS *******************/
 
-}
+// These only read the tables.  They are for things like lsof, or finding out
+// who still has the other end of a pipe open.
 
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-// helper to call after calling select beneath you.  returns the fd_sets you
-// need for your return from a select call and the number of unique flags
-// set...
+    /// See [`get_cageids`].
+    ///
+    /// # Panics
+    ///   See [`get_cageids`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_cageids(&self) -> Vec<u64> {
+        let fdtable = self.fdtable.lock().unwrap();
+        let mut cageids: Vec<u64> = fdtable.keys().chain(self.sharedtables.lock().unwrap().keys()).copied().collect();
+        cageids.sort_unstable();
+        cageids
+    }
 
-// I hate doing these, but don't know how to make this interface better...
-#[allow(clippy::type_complexity)]
-#[allow(clippy::too_many_arguments)]
-#[doc = include_str!("../docs/get_virtual_bitmasks_from_select_result.md")]
-pub fn get_virtual_bitmasks_from_select_result(nfds:u64, readbits:Option<fd_set>, writebits:Option<fd_set>, exceptbits:Option<fd_set>,unrealreadset:HashSet<u64>, unrealwriteset:HashSet<u64>, unrealexceptset:HashSet<u64>, mappingtable:&HashMap<u64,u64>) -> Result<(u64, Option<fd_set>, Option<fd_set>, Option<fd_set>),threei::RetVal> {
+    /// See [`get_fd_count`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_count`].
+    ///
+    /// # Errors
+    ///   See [`get_fd_count`].
+    pub fn get_fd_count(&self, cageid: u64) -> Result<u64, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
 
-    // Note, I don't need the cage_id here because I have the mappingtable...
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
 
-    if nfds >= FD_PER_PROCESS_MAX {
-        panic!("This shouldn't be possible because we shouldn't have returned this previously")
+        Ok(fdtable.get(&cageid).unwrap().len() as u64)
     }
 
-    let mut flagsset = 0;
-    let mut retvec = Vec::new();
-
-    for (insetoption,unrealset) in [(readbits,unrealreadset), (writebits,unrealwriteset), (exceptbits,unrealexceptset)] {
-        // If I don't have any data, just return None (NULL) and skip...
-        if insetoption.is_none()&&unrealset.is_empty() {
-            retvec.push(None);
-            continue;
-        }
-
-        let mut retbits = _init_fd_set();
-        if let Some(inset) = insetoption {
-            for bit in 0..nfds as usize {
-                let pos = bit as u64;
-                if _fd_isset(pos,&inset)&& !_fd_isset(*mappingtable.get(&pos).unwrap(),&retbits) {
-                    flagsset+=1;
-                    _fd_set(*mappingtable.get(&pos).unwrap(),&mut retbits);
-                }
-            }
-        }
-        for virtfd in unrealset {
-            if !_fd_isset(virtfd,&retbits) {
-                flagsset+=1;
-                _fd_set(virtfd,&mut retbits);
-            }
+    /// See [`get_fd_references`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_references`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_fd_references(&self, fdkind: u32, underfd: u64) -> Vec<(u64, u64)> {
+        let mut references = self.fdcount.lock().unwrap().get(&(fdkind, underfd)).map_or_else(Vec::new, |description| description.references.clone());
+        // The cages which share a table have the fds which are in it.
+        for (&sharer, &tablecageid) in self.sharedtables.lock().unwrap().iter() {
+            let shared: Vec<(u64, u64)> = references.iter()
+                .filter(|&&(cageid, _)| cageid == tablecageid)
+                .map(|&(_, virtualfd)| (sharer, virtualfd))
+                .collect();
+            references.extend(shared);
         }
-        retvec.push(Some(retbits));
+        references.sort_unstable();
+        // A replaced fd is briefly in here twice (see FileDescription).
+        references.dedup();
+        references
+    }
+
+    /// See [`get_fd_reference_count`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_reference_count`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_fd_reference_count(&self, fdkind: u32, underfd: u64) -> u64 {
+        self.fdcount.lock().unwrap().get(&(fdkind, underfd)).map_or(0, FileDescription::count)
     }
 
-    Ok((flagsset,retvec[0],retvec[1],retvec[2]))
+    /// See [`get_fd_reference_counts`].
+    ///
+    /// # Panics
+    ///   See [`get_fd_reference_counts`].
+    #[must_use] // must use the return value if you call it.
+    pub fn get_fd_reference_counts(&self, fdkind: u32) -> HashMap<u64, u64> {
+        self.fdcount.lock().unwrap().iter()
+            .filter(|((thisfdkind, _), _)| *thisfdkind == fdkind)
+            .map(|((_, underfd), description)| (*underfd, description.count()))
+            .collect()
+    }
 
+    /// See [`get_virtualfds_of_kind`].
+    ///
+    /// # Panics
+    ///   See [`get_virtualfds_of_kind`].
+    ///
+    /// # Errors
+    ///   See [`get_virtualfds_of_kind`].
+    pub fn get_virtualfds_of_kind(&self, cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        let mut virtualfds: Vec<u64> = fdtable.get(&cageid).unwrap().iter()
+            .filter(|(_, entry)| entry.fdkind == fdkind)
+            .map(|(&virtualfd, _)| virtualfd)
+            .collect();
+        // The HashMap isn't in fd order...
+        virtualfds.sort_unstable();
+        Ok(virtualfds)
+    }
 }
 
 
 
-/********************** POLL SPECIFIC FUNCTIONS **********************/
+/******************* CROSS-CAGE GETFD POLICY *******************/
 
-// helper to call before calling poll beneath you.  replaces the fds in
-// the poll struct with virtual versions and returns the items you need
-// to check yourself...
-#[allow(clippy::type_complexity)]
-#[doc = include_str!("../docs/convert_virtualfds_to_real.md")]
-pub fn convert_virtualfds_to_real(cageid:u64, virtualfds:Vec<u64>) -> (Vec<u64>, Vec<(u64,u64)>, Vec<u64>, HashMap<u64,u64>) {
+// The functions set with set_getfd_policy and set_getfd_audit.  They are
+// taken out (as Arcs) and called after the lock is dropped.
+struct GetfdHooks {
+    policy: GetfdPolicy,
+    audit: GetfdAudit,
+}
 
-    let globfdtable = GLOBALFDTABLE.lock().unwrap();
+impl Default for GetfdHooks {
+    fn default() -> Self {
+        Self {
+            policy: Arc::new(GETFD_SAME_CAGE_ONLY),
+            audit: Arc::new(GETFD_NULL_AUDIT),
+        }
+    }
+}
 
-    if !globfdtable.contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+// The closures can't be printed, so this doesn't show anything.
+impl std::fmt::Debug for GetfdHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GetfdHooks").finish_non_exhaustive()
     }
+}
 
-    let mut unrealvec = Vec::new();
-    let mut realvec = Vec::new();
-    let mut invalidvec = Vec::new();
-    let thefdhm = globfdtable.get(&cageid).unwrap();
-    let mut mappingtable:HashMap<u64,u64> = HashMap::new();
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    // BUG?: I'm ignoring the fact that virtualfds can show up multiple times.
-    // I'm not sure this actually matters, but I didn't think hard about it.
-    for virtfd in virtualfds {
-        match thefdhm.get(&virtfd) {
-            Some(entry) => {
-                // always append the value here.  NO_REAL_FD will be added
-                // in the appropriate places to tell them to handle those calls
-                // themself.
-                realvec.push(entry.realfd);
-                if entry.realfd == NO_REAL_FD {
-                    unrealvec.push((virtfd,entry.optionalinfo));
-                }
-                else{
-                    mappingtable.insert(entry.realfd, virtfd);
-                }
-            }
-            None => {
-                // Add this because they need to handle it if POLLNVAL is set.
-                // An exception should not be raised!!!
-                realvec.push(INVALID_FD);
-                invalidvec.push(virtfd);
-            }
-        }
+    /// See [`set_getfd_policy`].
+    ///
+    /// # Panics
+    ///   See [`set_getfd_policy`].
+    pub fn set_getfd_policy(&self, policy:GetfdPolicy) {
+        self.getfdhooks.lock().unwrap().policy = policy;
     }
 
-    (realvec, unrealvec, invalidvec, mappingtable)
+    /// See [`set_getfd_audit`].
+    ///
+    /// # Panics
+    ///   See [`set_getfd_audit`].
+    pub fn set_getfd_audit(&self, audit:GetfdAudit) {
+        self.getfdhooks.lock().unwrap().audit = audit;
+    }
 }
 
 
 
-// helper to call after calling poll.  replaces the realfds the vector
-// with virtual ones...
-#[doc = include_str!("../docs/convert_realfds_back_to_virtual.md")]
-pub fn convert_realfds_back_to_virtual(realfds:Vec<u64>, mappingtable:&HashMap<u64,u64>) -> Vec<u64> {
-
-    // I don't care what cage was used, and don't need to lock anything...
-    // I have the mappingtable!
+/******************* UNKNOWN CAGE HANDLING *******************/
 
-    let mut virtvec = Vec::new();
+impl<P: Clone + Send + Sync> FdTables<P> {
 
-    for realfd in realfds {
-        virtvec.push(*mappingtable.get(&realfd).unwrap());
+    /// See [`set_unknown_cage_policy`].
+    ///
+    /// # Panics
+    ///   See [`set_unknown_cage_policy`].
+    pub fn set_unknown_cage_policy(&self, policy:UnknownCagePolicy) {
+        *self.unknowncagepolicy.lock().unwrap() = policy;
     }
 
-    virtvec
-}
+    /// See [`unknown_cage_policy`].
+    ///
+    /// # Panics
+    ///   See [`unknown_cage_policy`].
+    #[must_use] // must use the return value if you call it.
+    pub fn unknown_cage_policy(&self) -> UnknownCagePolicy {
+        *self.unknowncagepolicy.lock().unwrap()
+    }
 
-/********************** EPOLL SPECIFIC FUNCTIONS **********************/
+    // Checks that the cage exists, doing whatever the unknown cage policy
+    // says if it doesn't.  The caller already holds the fdtable lock, so I
+    // take the table in rather than locking it again.  Returns the cageid the
+    // cage's table is stored under, which is the cageid unless it shares
+    // another cage's table.  That one is in the fdtable, so callers use it in
+    // place of cageid.
+    fn _check_cageid(&self, fdtable:&mut HashMap<u64,HashMap<u64,FDTableEntry<P>>>, cageid:u64) -> Result<u64,FdTableError> {
+        let tablecageid = self.sharedtables.lock().unwrap().get(&cageid).copied().unwrap_or(cageid);
+        if fdtable.contains_key(&tablecageid) {
+            return Ok(tablecageid);
+        }
+        self._handle_unknown_cageid(cageid, |newcageid| {
+            fdtable.insert(newcageid,HashMap::new());
+        })?;
+        Ok(cageid)
+    }
 
+    // The soft limit for a cage.  Cages which never set a limit have the
+    // default one.  Callers may hold the fdtable lock, but not fdlimits.
+    fn _soft_fd_limit(&self, cageid:u64) -> u64 {
+        self.fdlimits.lock().unwrap().get(&cageid).map_or(FD_PER_PROCESS_MAX, |limits| limits.0)
+    }
 
-// Okay this adds a big wrinkle, epollfds.  The reason these are complex is
-// multi-fold:
-// 1) they themselves are file descriptors and take up a slot.
-// 2) a epollfd can point to any number of other fds
-// 3) an epollfd can point to epollfds, which can point to other epollfds, etc.
-//    and possibly cause a loop to occur (which is an error)
-// 4) an epollfd can point to a mix of virtual and realfds.
-//
-// My thinking is this is handled as similarly to poll as possible.  We push
-// off the problem of understanding what the event types are to the implementer
-// of the library.
-//
-// In my view, epoll_wait() is quite simple to support.  One basically just
-// keeps a list of virtual fds for this epollfd and their corresponding event
-// types, which they may need to poll themselves.  After this, they handle the
-// call.
-//
-// epoll_create makes a new fd type, which really is unfortunate.  To this
-// point, I haven't had to care about anything except in-memory fds (unreal),
-// and doing the virtual <-> real mappings.  The caller can decide whether to
-// create an underlying epollfd when this is called, when epoll_ctl is called
-// to add a realfd, etc.
-//
-// epoll_ctl is complex, but really has the same fundamental problem as
-// epoll_create: the epollfd.
-//
-// What if I just ignore the epollfd problem by just making another table
-// for epoll information?  Then what I do is set the realfd to EPOLLFD and
-// have optionalinfo point into the epollfd table.  If I do this, then when
-// epoll_create is called, if it contains realfds, those need to be passed
-// down to the underlying epoll_create.  Similarly, when epoll_ctl is called,
-// we either modify our data or return the realfd...
-//
-// Interestingly, this actually would be just as easy to build on top of the
-// fdtables library as into it.
-//
-// Each epollfd will have some virtual fds associated with it.  Each of those
-// will have an event mask.  So I'll have a mutex around an EPollTable struct.
-// This contains the next available entry and an epollhashmap<virtfd, event>.
-// I use a hashmap here to better support removing and modifying items.
+    // Does what the policy says for a cage which isn't in the fdtable.
+    // createfunc adds an empty table for the cage.
+    fn _handle_unknown_cageid(&self, cageid:u64, createfunc:impl FnOnce(u64)) -> Result<(),FdTableError> {
+        // Copy this out first, so I don't poison the lock by panicking while
+        // holding it...
+        let policy = *self.unknowncagepolicy.lock().unwrap();
+        match policy {
+            UnknownCagePolicy::Panic => panic!("Unknown cageid in fdtable access"),
+            UnknownCagePolicy::Error => Err(FdTableError::UnknownCage),
+            UnknownCagePolicy::AutoCreate => {
+                createfunc(cageid);
+                Ok(())
+            },
+        }
+    }
+}
 
-// Note, I'm defining a bunch of symbols myself because libc doesn't import
-// them on systems that don't support epoll and I want to be able to build
-// the code anywhere.  See commonconstants.rs for more info.
+/******************* OPEN SPECIFIC FUNCTIONALITY *******************/
 
-// Design notes: I'm not adding realfds.  I return them when you do a epoll_ctl
-// operation that tries to add them.  So, I only have unrealfds in my epoll
-// structures.
+// A handler to call when a reference to an (fdkind,underfd) is added.
+struct OpenHandlers<P> {
+    // Identifies it, so remove_open_handler can find it.
+    token: OpenHandlerToken,
+    // Called with (entry,count,reason)
+    handler: OpenHandler<P>,
+}
 
-// TODO: I don't clean up this table yet.  I probably should when the last
-// reference to a fd is closed, but this bookkeeping seems excessive at this
-// time...
-#[derive(Clone, Debug)]
-struct EPollTable {
-    highestneverusedentry: u64, // Never resets (even after close).  Used to
-                                // let us quickly get an unused entry
-    thisepolltable: HashMap<u64,HashMap<u64,epoll_event>>, // the epollentry ->
-                                                           // virtfd ->
-                                                           // event map
-    realfdtable: HashMap<u64,u64>, // the epollentry -> realfd map.  I need 
-                                   // this because the realfd field in the
-                                   // main data structure is EPOLLFD
+// The closure can't be printed, so this only shows the token.
+impl<P> std::fmt::Debug for OpenHandlers<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenHandlers").field("token", &self.token).finish_non_exhaustive()
+    }
 }
 
-lazy_static! {
+// Each fdkind's open handlers, in the order they were added.
+type OpenHandlerTable<P> = Arc<HashMap<u32,Vec<Arc<OpenHandlers<P>>>>>;
 
-    #[derive(Debug)]
-    static ref EPOLLTABLE: Mutex<EPollTable> = {
-        let newetable = HashMap::new();
-        let newrealfdtable = HashMap::new();
-        let m = EPollTable {
-            highestneverusedentry:0,
-            realfdtable:newrealfdtable,
-            thisepolltable:newetable,
-        };
-        Mutex::new(m)
-    };
+// The references an operation added, for the open handlers.  It is filled
+// in while the operation holds its locks, and the handlers are called once
+// it drops them.  Only entries whose fdkind has handlers are kept, so this
+// costs nothing much if there aren't any.
+struct PendingOpens<P> {
+    openhandlers: OpenHandlerTable<P>,
+    entries: Vec<FDTableEntry<P>>,
 }
 
+impl<P: Clone> PendingOpens<P> {
+    fn add(&mut self, entry:&FDTableEntry<P>) {
+        if self.openhandlers.contains_key(&entry.fdkind) {
+            self.entries.push(entry.clone());
+        }
+    }
+}
 
-#[doc = include_str!("../docs/epoll_create_helper.md")]
-pub fn epoll_create_helper(cageid:u64, realfd:u64, should_cloexec:bool) -> Result<u64,threei::RetVal> {
-
-    let mut ept = EPOLLTABLE.lock().unwrap();
+impl<P: Clone + Send + Sync> FdTables<P> {
+
+    // The table is copied on write, so a call which is already running the
+    // old one doesn't see the change.
+    /// See [`add_open_handler`].
+    ///
+    /// # Panics
+    ///   See [`add_open_handler`].
+    #[must_use] // must use the return value if you call it.
+    pub fn add_open_handler(&self, fdkind:u32, handler: OpenHandler<P>) -> OpenHandlerToken {
+        let token = OpenHandlerToken(self.nexthandlertoken.fetch_add(1, Ordering::Relaxed));
+        let openhandler = OpenHandlers {
+            token,
+            handler,
+        };
+        let mut openhandlertable = self.openhandlertable.lock().unwrap();
+        Arc::make_mut(&mut openhandlertable).entry(fdkind).or_default().push(Arc::new(openhandler));
+        token
+    }
 
-    // I'll use my other functions to make this easier.
-    // return the same errno (EMFile), if we get one
-    let newepollfd = get_unused_virtual_fd(cageid, EPOLLFD, should_cloexec, ept.highestneverusedentry)?;
+    /// See [`remove_open_handler`].
+    ///
+    /// # Panics
+    ///   See [`remove_open_handler`].
+    #[must_use] // must use the return value if you call it.
+    pub fn remove_open_handler(&self, token:OpenHandlerToken) -> bool {
+        let mut openhandlertable = self.openhandlertable.lock().unwrap();
+        let Some(&fdkind) = openhandlertable.iter().find(|(_, openhandlers)| openhandlers.iter().any(|openhandler| openhandler.token == token)).map(|(fdkind, _)| fdkind) else {
+            return false;
+        };
+        let openhandlertable = Arc::make_mut(&mut openhandlertable);
+        let openhandlers = openhandlertable.get_mut(&fdkind).unwrap();
+        openhandlers.retain(|openhandler| openhandler.token != token);
+        // Leave no empty lists, so the fdkind has no handlers again.
+        if openhandlers.is_empty() {
+            openhandlertable.remove(&fdkind);
+        }
+        true
+    }
 
-    let newentry = ept.highestneverusedentry;
-    ept.highestneverusedentry+=1;
-    // add in my realfd.
-    ept.realfdtable.insert(newentry,realfd);
-    // if it errored out above that is okay. I haven't changed any state yet.
-    ept.thisepolltable.insert(newentry, HashMap::new());
-    Ok(newepollfd)
+    // This only holds the openhandlertable lock for a moment, so it may be
+    // called while holding others.  Pass the result to _call_open_handlers
+    // once they are all dropped.
+    fn _pending_opens(&self) -> PendingOpens<P> {
+        PendingOpens {
+            openhandlers: Arc::clone(&self.openhandlertable.lock().unwrap()),
+            entries: Vec::new(),
+        }
+    }
 
+    // Calls the open handlers for the references the operation added.  The
+    // count is looked up now, so it includes every reference the operation
+    // added (and nothing it is about to close).
+    fn _call_open_handlers(&self, opened:PendingOpens<P>, reason:OpenReason) {
+        for entry in opened.entries {
+            let count = self.get_fd_reference_count(entry.fdkind, entry.underfd);
+            for openhandler in &opened.openhandlers[&entry.fdkind] {
+                (openhandler.handler)(&entry, count, reason);
+            }
+        }
+    }
 }
 
 
 
-#[doc = include_str!("../docs/try_epoll_ctl.md")]
-pub fn try_epoll_ctl(cageid:u64, epfd:u64, op:i32, virtfd:u64, event:epoll_event) -> Result<(u64,u64),threei::RetVal> {
+/******************* CLOSE SPECIFIC FUNCTIONALITY *******************/
+
+// These indicate what functions should be called upon a virtualfd closing.
+// The handler which is called depends on number of (fdkind,underfd) tuples
+// that are used across *all instances managed by this library including in
+// other cages*.
+struct CloseHandlers<P> {
+    // Identifies these, so remove_close_handlers can find them.
+    token: CloseHandlerToken,
+    // Called when close is called, but at least one (fdkind,underfd)
+    // reference still remains.  Called with (entry,count)
+    intermediate: CloseHandler<P>,
+    // Called when the last (fdkind,underfd) reference is closed.  Called
+    // with (entry,0)
+    last: CloseHandler<P>,
+}
 
-    if !GLOBALFDTABLE.lock().unwrap().contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+// The closures can't be printed, so this only shows the token.
+impl<P> std::fmt::Debug for CloseHandlers<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloseHandlers").field("token", &self.token).finish_non_exhaustive()
     }
+}
 
-    if epfd == virtfd {
-        return Err(threei::Errno::EINVAL as u64);
+impl<P> CloseHandlers<P> {
+    fn call(&self, entry:FDTableEntry<P>, count:u64) {
+        if count > 0 {
+            (self.intermediate)(entry,count);
+        }
+        else {
+            (self.last)(entry,0);
+        }
     }
-    // Is the epfd ok?
-    let epollentrynum:u64 = match GLOBALFDTABLE.lock().unwrap().get(&cageid).unwrap().get(&epfd) {
-        None => {
-            return Err(threei::Errno::EBADF as u64);
-        },
-        // Do I need to have EPOLLFDs here too?
-        Some(tableentry) => {
-            if tableentry.realfd != EPOLLFD {
-                return Err(threei::Errno::EINVAL as u64);
-            }
-            tableentry.optionalinfo
-        },
-    };
+}
 
-    // Okay, I know which table entry and verified the virtfd...
-
-    let mut epttable = EPOLLTABLE.lock().unwrap();
-    let realepollfd = epttable.realfdtable.get(&epollentrynum).unwrap().clone();
-    let eptentry = epttable.thisepolltable.get_mut(&epollentrynum).unwrap();
-
-    // check if the virtfd is real and error...
-    // I don't care about its contents except to ensure it isn't real...
-    match GLOBALFDTABLE.lock().unwrap().get(&cageid).unwrap().get(&virtfd) {
-        // Do I need to have EPOLLFDs here too?
-        Some(tableentry) => {
-            if tableentry.realfd != NO_REAL_FD {
-                // Return realfds because the caller should handle them instead
-                // I only track unrealfds.
-                if tableentry.realfd == EPOLLFD {
-                    // BUG: How should I be doing this, really!?!
-                    println!("epollfds acting on epollfds is not supported!");
-                }
-                return Ok((realepollfd,tableentry.realfd));
+// An fdkind's handlers, in the order they were added.
+type CloseHandlerList<P> = Arc<Vec<Arc<CloseHandlers<P>>>>;
+
+impl<P: Clone + Send + Sync> FdTables<P> {
+
+    /// See [`close_virtualfd`].
+    ///
+    /// # Panics
+    ///   See [`close_virtualfd`].
+    ///
+    /// # Errors
+    ///   See [`close_virtualfd`].
+    pub fn close_virtualfd(&self, cageid:u64, virtfd:u64) -> Result<(),FdTableError> {
+        let mut fdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        // Remove this item from the table (and inspect it)
+        let thisoption = fdtable.get_mut(&cageid).unwrap().remove(&virtfd);
+        // Need to drop the lock, before calling the handlers.
+        drop(fdtable);
+
+        match thisoption {
+            Some(entry) => {
+                self._report_unhandled_closes(self._decrement_fdcount(cageid, virtfd, entry));
+                Ok(())
             }
-        },
-        None => {
-            return Err(threei::Errno::EBADF as u64);
-        },
-    };
+            None => Err(FdTableError::FdNotOpen),
+        }
+    }
+
+
+    // Like empty_fds_for_exec, this takes everything out under one lock and
+    // calls the close handlers after, so they see a consistent table.
+    /// See [`close_virtualfd_range`].
+    ///
+    /// # Panics
+    ///   See [`close_virtualfd_range`].
+    ///
+    /// # Errors
+    ///   See [`close_virtualfd_range`].
+    pub fn close_virtualfd_range(&self, cageid:u64, first:u64, last:u64, flags:u32) -> Result<(),FdTableError> {
+        if flags & !(CLOSE_RANGE_UNSHARE | CLOSE_RANGE_CLOEXEC) != 0 {
+            return Err(FdTableError::InvalidFlags);
+        }
+        if first > last {
+            return Err(FdTableError::InvalidRange);
+        }
+
+        // Like Linux, the fds are closed in the cage's own copy of the table.
+        if flags & CLOSE_RANGE_UNSHARE != 0 {
+            self.unshare_fdtable_for_cage(cageid)?;
+        }
 
-    // okay, virtfd is real...
+        let mut fdtable = self.fdtable.lock().unwrap();
 
-    match op {
-        EPOLL_CTL_ADD => {
-            if eptentry.contains_key(&virtfd) {
-                return Err(threei::Errno::EEXIST as u64);
+        let cageid = self._check_cageid(&mut fdtable, cageid)?;
+
+        let thiscagefdtable = fdtable.get_mut(&cageid).unwrap();
+
+        // Nothing is open past the end of the table, so I stop there (last is
+        // often !0).
+        let fdrange = first..=cmp::min(last, FD_PER_PROCESS_MAX - 1);
+
+        if flags & CLOSE_RANGE_CLOEXEC != 0 {
+            for virtualfd in fdrange {
+                if let Some(entry) = thiscagefdtable.get_mut(&virtualfd) {
+                    entry.should_cloexec = true;
+                }
             }
-            // BUG: Need to check for ELOOP here...
+            return Ok(());
+        }
 
-            eptentry.insert(virtfd, event);
-        },
-        EPOLL_CTL_MOD => {
-            if !eptentry.contains_key(&virtfd) {
-                return Err(threei::Errno::ENOENT as u64);
+        // In fd order, so the handlers are called in that order too.
+        let mut closevec = Vec::new();
+        for virtualfd in fdrange {
+            if let Some(entry) = thiscagefdtable.remove(&virtualfd) {
+                closevec.push((virtualfd, entry));
             }
-            eptentry.insert(virtfd, event);
-        },
-        EPOLL_CTL_DEL => {
-            if !eptentry.contains_key(&virtfd) {
-                return Err(threei::Errno::ENOENT as u64);
+        }
+
+        // Need to drop the lock, before calling the handlers.
+        drop(fdtable);
+
+        let mut unhandled = Vec::new();
+        for (virtualfd, entry) in closevec {
+            unhandled.extend(self._decrement_fdcount(cageid, virtualfd, entry));
+        }
+        self._report_unhandled_closes(unhandled);
+
+        Ok(())
+    }
+
+    // Register a series of helpers to be called for close.  Can be called
+    // multiple times to override the older helpers.
+    /// See [`register_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`register_close_handlers`].
+    pub fn register_close_handlers(&self, fdkind:u32, intermediate: fn(FDTableEntry<P>,u64), last: fn(FDTableEntry<P>,u64)) where P: 'static {
+        let closehandler = CloseHandlers {
+            token: self._new_close_handler_token(),
+            intermediate: Box::new(intermediate),
+            last: Box::new(last),
+        };
+        // overwrite whatever is in there (including any added handlers)...
+        self.closehandlertable.lock().unwrap().insert(fdkind, Arc::new(vec![Arc::new(closehandler)]));
+    }
+
+    // The list is copied on write, so a close which is already running the
+    // old list doesn't see the change.
+    /// See [`add_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`add_close_handlers`].
+    #[must_use] // must use the return value if you call it.
+    pub fn add_close_handlers(&self, fdkind:u32, intermediate: CloseHandler<P>, last: CloseHandler<P>) -> CloseHandlerToken {
+        let token = self._new_close_handler_token();
+        let closehandler = CloseHandlers {
+            token,
+            intermediate,
+            last,
+        };
+        let mut closehandlertable = self.closehandlertable.lock().unwrap();
+        Arc::make_mut(closehandlertable.entry(fdkind).or_default()).push(Arc::new(closehandler));
+        token
+    }
+
+    /// See [`remove_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`remove_close_handlers`].
+    #[must_use] // must use the return value if you call it.
+    pub fn remove_close_handlers(&self, token:CloseHandlerToken) -> bool {
+        let mut closehandlertable = self.closehandlertable.lock().unwrap();
+        let Some((&fdkind, closehandlers)) = closehandlertable.iter_mut().find(|(_, closehandlers)| closehandlers.iter().any(|closehandler| closehandler.token == token)) else {
+            return false;
+        };
+        Arc::make_mut(closehandlers).retain(|closehandler| closehandler.token != token);
+        // Leave no empty lists, so the fdkind looks unregistered again.
+        if closehandlers.is_empty() {
+            closehandlertable.remove(&fdkind);
+        }
+        true
+    }
+
+    fn _new_close_handler_token(&self) -> CloseHandlerToken {
+        CloseHandlerToken(self.nexthandlertoken.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// See [`register_default_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`register_default_close_handlers`].
+    pub fn register_default_close_handlers(&self, intermediate: CloseHandler<P>, last: CloseHandler<P>) {
+        let closehandler = CloseHandlers {
+            token: self._new_close_handler_token(),
+            intermediate,
+            last,
+        };
+        *self.defaultclosehandlers.lock().unwrap() = Some(Arc::new(vec![Arc::new(closehandler)]));
+    }
+
+    /// See [`set_strict_close_handlers`].
+    ///
+    /// # Panics
+    ///   See [`set_strict_close_handlers`].
+    pub fn set_strict_close_handlers(&self, strict:bool) {
+        self.strictclosehandlers.store(strict, Ordering::Relaxed);
+    }
+
+    // The handlers for fdkind, or the default ones if it has none.  The
+    // reserved fdkinds (like epoll fds) are mine, so they never get the
+    // default handlers.
+    fn _close_handlers_for(&self, fdkind:u32) -> Option<CloseHandlerList<P>> {
+        let closehandlers = self.closehandlertable.lock().unwrap().get(&fdkind).cloned();
+        if closehandlers.is_some() || fdkind >= FDT_KINDMAX {
+            return closehandlers;
+        }
+        self.defaultclosehandlers.lock().unwrap().clone()
+    }
+
+    // Calls each of the fdkind's handlers in order.  All but the last get a
+    // copy of the entry.
+    // Returns the fdkind if it had no handlers to call, for
+    // _report_unhandled_closes.
+    fn _run_close_handlers(closehandlers:Option<CloseHandlerList<P>>, entry:FDTableEntry<P>, count:u64) -> Option<u32> {
+        let Some(closehandlers) = closehandlers else {
+            // My own fdkinds, like epoll fds, don't need any.
+            return (entry.fdkind < FDT_KINDMAX).then_some(entry.fdkind);
+        };
+        if let Some((lastone, others)) = closehandlers.split_last() {
+            for closehandler in others {
+                closehandler.call(entry.clone(), count);
             }
-            eptentry.remove(&virtfd);
-        },
-        _ => {
-            return Err(threei::Errno::EINVAL as u64);
-        },
-    };
-    Ok((realepollfd,NO_REAL_FD))
+            lastone.call(entry, count);
+        }
+        None
+    }
+
+    // In strict mode, panics if any of the closes had no handlers to call
+    // (fdkinds is what _decrement_fdcount returned for them).  A call which
+    // closes several fds does them all first, and then reports them at once,
+    // so the tables and counts are still consistent if the panic is caught.
+    fn _report_unhandled_closes(&self, fdkinds:impl IntoIterator<Item = u32>) {
+        let mut fdkinds: Vec<u32> = fdkinds.into_iter().collect();
+        if fdkinds.is_empty() || !self.strictclosehandlers.load(Ordering::Relaxed) {
+            return;
+        }
+        fdkinds.sort_unstable();
+        fdkinds.dedup();
+        panic!("closed fds of fdkinds {fdkinds:?}, which have no close handlers");
+    }
+
+    // Helpers to track the count of times each (fdkind,underfd) is used.
+    // The caller must not hold the fdtable lock, since this calls the user's
+    // close handler.
+    // Returns the fdkind if it had no close handlers to call (see
+    // _report_unhandled_closes).
+    #[doc(hidden)]
+    fn _decrement_fdcount(&self, cageid:u64, virtualfd:u64, entry:FDTableEntry<P>) -> Option<u32> {
+
+        // This fd no longer counts against the total limit.
+        self.totalfds.fetch_sub(1, Ordering::Relaxed);
+
+        let mytuple = (entry.fdkind, entry.underfd);
+
+        // Get this table's lock...
+        let mut fdcount = self.fdcount.lock().unwrap();
+
+        let description = fdcount.get_mut(&mytuple).unwrap();
+        description.remove_reference(cageid, virtualfd);
+        let newcount:u64 = description.count();
+
+        // Take the list, and release the lock, since the handlers may add
+        // or remove handlers...
+        let closehandlers = self._close_handlers_for(entry.fdkind);
+
+        if newcount > 0 {
+            // Need to drop locks to call the handlers or else will deadlock...
+            drop(fdcount);
+
+            Self::_run_close_handlers(closehandlers, entry, newcount)
+        }
+        else {
+            // Remove before calling their close handler in case they do operations
+            // inside the close handler which create / close fds...
+            fdcount.remove(&mytuple);
+            // Need to drop locks to call the handlers or else will deadlock...
+            drop(fdcount);
+
+            Self::_run_close_handlers(closehandlers, entry, 0)
+        }
+    }
+
+    // Helpers to track the count of times each (fdkind,underfd) is used,
+    // and where.  cageid and virtualfd are where the entry is being added.
+    #[doc(hidden)]
+    fn _increment_fdcount(&self, cageid:u64, virtualfd:u64, entry:&FDTableEntry<P>) {
+
+        let mytuple = (entry.fdkind, entry.underfd);
+
+        // Get this table's lock...
+        let mut fdcount = self.fdcount.lock().unwrap();
+
+        // The first fd for this tuple makes a new description.
+        fdcount.entry(mytuple).or_default().references.push((cageid, virtualfd));
+    }
+
+    // Takes count fds from the total limit, or returns ENFILE (and takes
+    // nothing) if there aren't that many left.
+    fn _reserve_total_fds(&self, count:u64) -> Result<(),FdTableError> {
+        let limit = self.totalfdlimit.load(Ordering::Relaxed);
+        self.totalfds.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+            (total + count <= limit).then_some(total + count)
+        }).map(|_| ()).map_err(|_| FdTableError::SystemTableFull)
+    }
 }
 
+/***************   Code for handling select() ****************/
 
-#[doc = include_str!("../docs/get_epoll_wait_data.md")]
-pub fn get_epoll_wait_data(cageid:u64, epfd:u64) -> Result<(u64,HashMap<u64,epoll_event>),threei::RetVal> {
+use libc::fd_set;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::cmp;
+use std::mem;
 
-    if !GLOBALFDTABLE.lock().unwrap().contains_key(&cageid) {
-        panic!("Unknown cageid in fdtable access");
+// Helper to get an empty fd_set.  Helper function to isolate unsafe code,
+// etc.
+#[doc(hidden)]
+#[must_use] // must use the return value if you call it.
+pub fn _init_fd_set() -> fd_set {
+    let raw_fd_set:fd_set;
+    unsafe {
+        let mut this_fd_set = mem::MaybeUninit::<libc::fd_set>::uninit();
+        libc::FD_ZERO(this_fd_set.as_mut_ptr());
+        raw_fd_set = this_fd_set.assume_init();
     }
+    raw_fd_set
+}
+
+#[doc(hidden)]
+pub fn _fd_set(fd:u64, thisfdset:&mut fd_set) {
+    unsafe{libc::FD_SET(fd as i32,thisfdset)}
+}
+
+#[doc(hidden)]
+#[must_use] // must use the return value if you call it.
+pub fn _fd_isset(fd:u64, thisfdset:&fd_set) -> bool {
+    unsafe{libc::FD_ISSET(fd as i32,thisfdset)}
+}
 
-    // Note that because I don't track realfds or deal with epollfds, I just
-    // return the epolltable...
-    let epollentrynum:u64 = match GLOBALFDTABLE.lock().unwrap().get(&cageid).unwrap().get(&epfd) {
-        None => {
-            return Err(threei::Errno::EBADF as u64);
-        },
-        // Do I need to have EPOLLFDs here too?
-        Some(tableentry) => {
-            if tableentry.realfd != EPOLLFD {
-                return Err(threei::Errno::EINVAL as u64);
+impl<P: Clone + Send + Sync + Eq + Hash> FdTables<P> {
+
+    // helper to call before calling select beneath you.  Translates your virtfds
+    // into a bitmask you may use for select.
+    // See: https://man7.org/linux/man-pages/man2/select.2.html for details /
+    // corner cases about the arguments.
+
+    // I hate doing these, but don't know how to make this interface better...
+    /// See [`get_bitmask_for_select`].
+    ///
+    /// # Panics
+    ///   See [`get_bitmask_for_select`].
+    ///
+    /// # Errors
+    ///   See [`get_bitmask_for_select`].
+    #[allow(clippy::type_complexity)]
+    #[allow(clippy::implicit_hasher)]
+    pub fn get_bitmask_for_select(&self, cageid:u64, nfds:u64, bits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry<P>>>, HashMap<(u32,u64),u64>),FdTableError> {
+
+        if nfds >= FD_PER_PROCESS_MAX {
+            return Err(FdTableError::NfdsTooLarge);
+        }
+
+        let mut globfdtable = self.fdtable.lock().unwrap();
+
+        let cageid = self._check_cageid(&mut globfdtable, cageid)?;
+
+        // The three things I will return...
+        let mut retbittable:HashMap<u32,(u64,fd_set)> = HashMap::new();
+        let mut retunparsedtable:HashMap<u32,HashSet<FDTableEntry<P>>> = HashMap::new();
+        let mut mappingtable:HashMap<(u32,u64),u64> = HashMap::new();
+
+        // If we were asked to do this on nothing, return empty mappings...
+        let Some(infdset) = bits else {
+            return Ok((retbittable, retunparsedtable, mappingtable));
+        };
+
+        let myfdmap = globfdtable.get(&cageid).unwrap();
+
+        for pos in 0..nfds {
+            if _fd_isset(pos,&infdset) {
+                let Some(entry) = myfdmap.get(&pos) else {
+                    return Err(FdTableError::BadFd);
+                };
+                // and update the mappingtable to have the bit from the
+                // original fd...
+                mappingtable.insert((entry.fdkind,entry.underfd),pos);
+
+                if fdkinds.contains(&entry.fdkind) {
+                    // Either initialize it or use what exists.  I don't init
+                    // the fd_set up front because it's a large data structure.
+                    let (startingnfds, startingfdset) = retbittable.entry(entry.fdkind).or_insert_with(|| (1,_init_fd_set()));
+
+                    // Update the table and the nfds
+                    _fd_set(entry.underfd,startingfdset);
+                    *startingnfds = cmp::max(*startingnfds, entry.underfd+1);
+                }
+                else {
+                    // Is unparsed...
+                    retunparsedtable.entry(entry.fdkind).or_default().insert(entry.clone());
+                }
             }
-            tableentry.optionalinfo
-        },
+        }
+        Ok((retbittable, retunparsedtable, mappingtable))
+
+    }
+
+
+    /// See [`prepare_bitmasks_for_select`].
+    ///
+    /// # Panics
+    ///   See [`prepare_bitmasks_for_select`].
+    ///
+    /// # Errors
+    ///   See [`prepare_bitmasks_for_select`].
+    #[allow(clippy::type_complexity)]
+    #[allow(clippy::implicit_hasher)]
+    pub fn prepare_bitmasks_for_select(&self, cageid:u64, nfds:u64, rbits:Option<fd_set>, wbits:Option<fd_set>, ebits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry<P>>>;3], HashMap<(u32,u64),u64>),FdTableError> {
+
+        // return the error, if need be
+        let rresult = self.get_bitmask_for_select(cageid, nfds, rbits, fdkinds)?;
+        let wresult = self.get_bitmask_for_select(cageid, nfds, wbits, fdkinds)?;
+        let eresult = self.get_bitmask_for_select(cageid, nfds, ebits, fdkinds)?;
+
+        let mut mappingtable = rresult.2;
+        mappingtable.extend(wresult.2);
+        mappingtable.extend(eresult.2);
+
+        Ok(([rresult.0,wresult.0,eresult.0],[rresult.1,wresult.1,eresult.1],mappingtable))
+
+    }
+}
+
+
+// helper to call after calling select beneath you.  returns the fd_set you
+// need for your return from a select call and the number of unique flags
+// set...
+
+// I've given them the hashmap, so don't need flexibility in what they return...
+#[allow(clippy::implicit_hasher)]
+#[must_use] // must use the return value if you call it.
+#[doc = include_str!("../docs/get_one_virtual_bitmask_from_select_result.md")]
+pub fn get_one_virtual_bitmask_from_select_result(fdkind:u32, nfds:u64, bits:Option<fd_set>, unprocessedset:HashSet<u64>, startingbits:Option<fd_set>,mappingtable:&HashMap<(u32,u64),u64>) -> (u64, Option<fd_set>) {
+
+    // Note, I don't need the cage_id here because I have the mappingtable...
+
+    assert!(nfds < FD_PER_PROCESS_MAX,"This shouldn't be possible because we shouldn't have returned this previously");
+
+    let mut flagsset = 0;
+
+    if bits.is_none() && unprocessedset.is_empty() {
+        return (flagsset,None);
+    }
+
+    let mut retbits = match startingbits {
+        Some(val) => val,
+        None => _init_fd_set(),
     };
 
-    let epttable = EPOLLTABLE.lock().unwrap();
-    Ok((*epttable.realfdtable.get(&epollentrynum).unwrap(),epttable.thisepolltable[&epollentrynum].clone()))
+    if let Some(inset) = bits {
+        for pos in 0..nfds {
+            if _fd_isset(pos,&inset)&& !_fd_isset(*mappingtable.get(&(fdkind,pos)).unwrap(),&retbits) {
+                flagsset+=1;
+                _fd_set(*mappingtable.get(&(fdkind,pos)).unwrap(),&mut retbits);
+            }
+        }
+    }
+    for virtfd in unprocessedset {
+        if !_fd_isset(virtfd,&retbits) {
+            flagsset+=1;
+            _fd_set(virtfd,&mut retbits);
+        }
+    }
+
+    (flagsset,Some(retbits))
+
 }
 
 
-/********************** TESTING HELPER FUNCTION **********************/
 
-// Helper to initialize / empty out state so we can test with a clean system...
-// only used when testing...
-//
-// I'm cleaning up "poisoned" mutexes here so that I can handle tests that 
-// panic
-#[doc(hidden)]
-pub fn refresh() {
-    let mut fdtable = GLOBALFDTABLE.lock().unwrap_or_else(|e| {
-        GLOBALFDTABLE.clear_poison();
-        e.into_inner()
-    });
-    fdtable.clear();
-    fdtable.insert(threei::TESTING_CAGEID, HashMap::new());
-    let mut closehandlers = CLOSEHANDLERTABLE.lock().unwrap_or_else(|e| {
-        CLOSEHANDLERTABLE.clear_poison();
-        e.into_inner()
-    });
-    closehandlers.intermediate_handler = NULL_FUNC;
-    closehandlers.final_handler = NULL_FUNC;
-    closehandlers.unreal_handler = NULL_FUNC;
-    let mut _realfdcount = GLOBALREALFDCOUNT.lock().unwrap_or_else(|e| {
-        GLOBALREALFDCOUNT.clear_poison();
-        e.into_inner()
-    });
+/********************** POLL SPECIFIC FUNCTIONS **********************/
+
+impl<P: Clone + Send + Sync + Eq + Hash + Default> FdTables<P> {
+
+    // helper to call before calling poll beneath you.  replaces the fds in
+    // the poll struct with virtual versions and returns the items you need
+    // to check yourself...
+    /// See [`convert_virtualfds_for_poll`].
+    ///
+    /// # Panics
+    ///   See [`convert_virtualfds_for_poll`].
+    #[allow(clippy::implicit_hasher)]
+    #[allow(clippy::type_complexity)]
+    #[must_use] // must use the return value if you call it.
+    pub fn convert_virtualfds_for_poll(&self, cageid:u64, virtualfds:HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry<P>)>>, HashMap<(u32,u64),u64>) {
+
+        let mut globfdtable = self.fdtable.lock().unwrap();
+
+        // With the Error policy, an unknown cage has no fds, so they all
+        // come back as invalid...
+        let emptyhm = HashMap::new();
+        let thefdhm = if let Ok(cageid) = self._check_cageid(&mut globfdtable, cageid) {
+            globfdtable.get(&cageid).unwrap()
+        } else {
+            &emptyhm
+        };
+        let mut mappingtable:HashMap<(u32,u64),u64> = HashMap::new();
+        let mut rethashmap:HashMap<u32,HashSet<(u64,FDTableEntry<P>)>> = HashMap::new();
+
+        // BUG?: I'm ignoring the fact that virtualfds can show up multiple times.
+        // I'm not sure this actually matters, but I didn't think hard about it.
+        for virtfd in virtualfds {
+            if let Some(entry) = thefdhm.get(&virtfd) {
+                rethashmap.entry(entry.fdkind).or_default().insert((virtfd,entry.clone()));
+                mappingtable.insert((entry.fdkind,entry.underfd), virtfd);
+            }
+            else {
+                // Add this because they need to handle it if POLLNVAL is set.
+                // An exception should not be raised!!!  I am setting the
+                // underfd to be the virtfd, so I can reverse this process.
+                let myentry = FDTableEntry {
+                    fdkind:FDT_INVALID_FD,
+                    underfd:virtfd,
+                    should_cloexec:false,
+                    perfdinfo:P::default(),
+                };
+                rethashmap.entry(FDT_INVALID_FD).or_default().insert((virtfd,myentry));
+                mappingtable.insert((FDT_INVALID_FD,virtfd), virtfd);
+            }
+        }
+
+        (rethashmap, mappingtable)
+    }
+}
+
+
+
+// helper to call after calling poll.  replaces the fds in the vector
+// with virtual ones...
+#[doc = include_str!("../docs/convert_poll_result_back_to_virtual.md")]
+// I give them the hashmap, so don't need flexibility in what they return...
+#[allow(clippy::implicit_hasher)]
+#[must_use] // must use the return value if you call it.
+pub fn convert_poll_result_back_to_virtual(fdkind:u32,underfd:u64, mappingtable:&HashMap<(u32,u64),u64>) -> Option<u64> {
+
+    // I don't care what cage was used, and don't need to lock anything...
+    // I have the mappingtable!
+    mappingtable.get(&(fdkind,underfd)).copied()
+}
+
+/********************** EPOLL SPECIFIC FUNCTIONS **********************/
+
+
+// Supporting epollfds is done by a fdkind which is not set by the user.  See
+// dashmaparrayglobal.rs for the longer discussion about the design.  The
+// short version is:
+// 1) epoll_create_empty sets up an epollfd (of fdkind FDT_KINDEPOLL) whose
+// underfd is an index into the EPollTable below.
+// 2) epoll_add_underfd records the underlying epollfd for a fdkind, so the
+// caller can call down for those.
+// 3) virtualize_epoll_ctl / get_virtual_epoll_wait_data track the virtual fds
+// for all of the other fdkinds.
+
+// Note, I'm defining a bunch of symbols myself because libc doesn't import
+// them on systems that don't support epoll and I want to be able to build
+// the code anywhere.  See commonconstants.rs for more info.
+
+// a structure that exists for each epoll descriptor to track the underfd(s)
+// and parts the user will handle
+#[derive(Clone, Debug, Default)]
+struct EPollDescriptorInfo {
+    underfdhashmap: HashMap<u32,u64>, // The underfd for a specific fdkind.
+                                      // Used only when an epoll call will
+                                      // call down beneath it.
+    userhandledhashmap: HashMap<u32,HashMap<u64,epoll_event>>,
+                                      // This has all of the things the user
+                                      // will virtualize and handle.  The key
+                                      // is the fdkind.
+}
+
+// TODO: I don't clean up this table yet.  I probably should when the last
+// reference to a fd is closed, but this bookkeeping seems excessive at this
+// time...
+#[derive(Clone, Debug)]
+struct EPollTable {
+    highestneverusedentry: u64, // Never resets (even after close).  Used to
+                                // let us quickly get an unused entry
+    thisepolltable: HashMap<u64,EPollDescriptorInfo>,
 }
 
+impl<P: Clone + Send + Sync> FdTables<P> {
+
+    // Looks up the epoll entry for epfd.  Only holds the fdtable lock while
+    // doing so.
+    fn _get_epoll_entrynum_or_error(&self, cageid:u64, epfd:u64) -> Result<u64,FdTableError> {
+        // Is the epfd ok?
+        match self.fdtable.lock().unwrap().get(&cageid).unwrap().get(&epfd) {
+            None => {
+                Err(FdTableError::BadFd)
+            },
+            Some(tableentry) => {
+                // You must call this on an epoll fd
+                if tableentry.fdkind == FDT_KINDEPOLL {
+                    Ok(tableentry.underfd)
+                }
+                else {
+                    Err(FdTableError::NotEpollFd)
+                }
+            },
+        }
+    }
+
+
+    /// See [`epoll_create_empty`].
+    ///
+    /// # Panics
+    ///   See [`epoll_create_empty`].
+    ///
+    /// # Errors
+    ///   See [`epoll_create_empty`].
+    pub fn epoll_create_empty(&self, cageid:u64, should_cloexec:bool) -> Result<u64,FdTableError>
+    where
+        P: Default,
+    {
+
+        // Set up the entry first, so it is there if an open handler uses the
+        // new epoll fd.  The lock is dropped before the fd is allocated, since
+        // that calls the open handlers (which may call the epoll functions).
+        let mut ept = self.epolltable.lock().unwrap();
+        let newentrynum = ept.highestneverusedentry;
+        ept.highestneverusedentry+=1;
+        ept.thisepolltable.entry(newentrynum).or_default();
+        drop(ept);
+
+        // return the same errno (EMFile), if we get one.  No fd refers to
+        // the entry then, so it just goes away.
+        let result = self.get_unused_virtual_fd(cageid, FDT_KINDEPOLL, newentrynum, should_cloexec, P::default());
+        if result.is_err() {
+            self.epolltable.lock().unwrap().thisepolltable.remove(&newentrynum);
+        }
+        result
+
+    }
+
+    /// See [`epoll_add_underfd`].
+    ///
+    /// # Panics
+    ///   See [`epoll_add_underfd`].
+    ///
+    /// # Errors
+    ///   See [`epoll_add_underfd`].
+    pub fn epoll_add_underfd(&self, cageid:u64, virtepollfd:u64, fdkind:u32, underfd:u64) -> Result<(),FdTableError> {
+
+        let cageid = self._check_cageid(&mut self.fdtable.lock().unwrap(), cageid)?;
+
+        let mut ept = self.epolltable.lock().unwrap();
+
+        // get this or error out...
+        let epentrynum = self._get_epoll_entrynum_or_error(cageid, virtepollfd)?;
+
+        let myhm = &mut ept.thisepolltable.get_mut(&epentrynum).unwrap().underfdhashmap;
+
+        assert!(!myhm.contains_key(&fdkind),"Adding duplicate underfd to epollfd");
+
+        myhm.insert(fdkind,underfd);
+
+        Ok(())
+
+    }
+
+
+    /// See [`epoll_get_underfd_hashmap`].
+    ///
+    /// # Panics
+    ///   See [`epoll_get_underfd_hashmap`].
+    ///
+    /// # Errors
+    ///   See [`epoll_get_underfd_hashmap`].
+    pub fn epoll_get_underfd_hashmap(&self, cageid:u64, virtepollfd:u64) -> Result<HashMap<u32,u64>,FdTableError> {
+
+        let cageid = self._check_cageid(&mut self.fdtable.lock().unwrap(), cageid)?;
+
+        let ept = self.epolltable.lock().unwrap();
+
+        // get this or error out...
+        let epentrynum = self._get_epoll_entrynum_or_error(cageid, virtepollfd)?;
+
+        Ok(ept.thisepolltable.get(&epentrynum).unwrap().underfdhashmap.clone())
+
+    }
+
+
+    /// See [`virtualize_epoll_ctl`].
+    ///
+    /// # Panics
+    ///   See [`virtualize_epoll_ctl`].
+    ///
+    /// # Errors
+    ///   See [`virtualize_epoll_ctl`].
+    pub fn virtualize_epoll_ctl(&self, cageid:u64, epfd:u64, op:i32, virtfd:u64, event:epoll_event) -> Result<(),FdTableError> {
+
+        let cageid = self._check_cageid(&mut self.fdtable.lock().unwrap(), cageid)?;
+
+        if epfd == virtfd {
+            return Err(FdTableError::EpollTargetIsSelf);
+        }
+
+        // get this or error out...
+        let epentrynum = self._get_epoll_entrynum_or_error(cageid, epfd)?;
+
+        // Okay, I know which table entry, now verify the virtfd...
+        let virtfdkind:u32 = match self.fdtable.lock().unwrap().get(&cageid).unwrap().get(&virtfd) {
+            Some(tableentry) => {
+                // Right now, I don't support this, so error...
+                if tableentry.fdkind == FDT_KINDEPOLL {
+                    // TODO: support EPOLLFDs...
+                    return Err(FdTableError::NestedEpollUnsupported);
+                }
+                tableentry.fdkind
+            },
+            None => {
+                // The virtual Fd doesn't exist -- error...
+                return Err(FdTableError::BadFd);
+            },
+        };
+
+        let mut eptable = self.epolltable.lock().unwrap();
+        let userhm = &mut eptable.thisepolltable.get_mut(&epentrynum).unwrap().userhandledhashmap;
+
+        match op {
+            EPOLL_CTL_ADD => {
+                let thisuserhm = userhm.entry(virtfdkind).or_default();
+                if thisuserhm.contains_key(&virtfd) {
+                    return Err(FdTableError::EpollEntryExists);
+                }
+                // BUG: Need to check for ELOOP here once I support EPOLLFDs
+                // referencing each other...
+
+                thisuserhm.insert(virtfd, event);
+            },
+            EPOLL_CTL_MOD => {
+                let Some(thisuserhm) = userhm.get_mut(&virtfdkind) else {
+                    return Err(FdTableError::EpollEntryMissing);
+                };
+                if !thisuserhm.contains_key(&virtfd) {
+                    return Err(FdTableError::EpollEntryMissing);
+                }
+                thisuserhm.insert(virtfd, event);
+            },
+            EPOLL_CTL_DEL => {
+                let Some(thisuserhm) = userhm.get_mut(&virtfdkind) else {
+                    return Err(FdTableError::EpollEntryMissing);
+                };
+                if thisuserhm.remove(&virtfd).is_none() {
+                    return Err(FdTableError::EpollEntryMissing);
+                }
+                // If this was the last entry, delete the key altogether...
+                if thisuserhm.is_empty() {
+                    userhm.remove(&virtfdkind);
+                }
+            },
+            _ => {
+                return Err(FdTableError::InvalidEpollOp);
+            },
+        }
+        Ok(())
+    }
+
+
+    /// See [`get_virtual_epoll_wait_data`].
+    ///
+    /// # Panics
+    ///   See [`get_virtual_epoll_wait_data`].
+    ///
+    /// # Errors
+    ///   See [`get_virtual_epoll_wait_data`].
+    pub fn get_virtual_epoll_wait_data(&self, cageid:u64, epfd:u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,FdTableError> {
+
+        let cageid = self._check_cageid(&mut self.fdtable.lock().unwrap(), cageid)?;
+
+        // get this or error out...
+        let epentrynum = self._get_epoll_entrynum_or_error(cageid, epfd)?;
+
+        let eptable = self.epolltable.lock().unwrap();
+        Ok(eptable.thisepolltable.get(&epentrynum).unwrap().userhandledhashmap.clone())
+    }
+}
+
+
+
+/********************** GLOBAL INSTANCE FUNCTIONS **********************/
+
+// These are the functions most callers use.  Each one just calls the method
+// of the same name on the global FdTables instance.
+
+#[doc = include_str!("../docs/init_empty_cage.md")]
+pub fn init_empty_cage(cageid: u64) {
+    GLOBALFDTABLES.init_empty_cage(cageid);
+}
+
+#[doc = include_str!("../docs/translate_virtual_fd.md")]
+pub fn translate_virtual_fd(cageid: u64, virtualfd: u64) -> Result<FDTableEntry, FdTableError> {
+    GLOBALFDTABLES.translate_virtual_fd(cageid, virtualfd)
+}
+
+#[doc = include_str!("../docs/get_unused_virtual_fd.md")]
+pub fn get_unused_virtual_fd(
+    cageid: u64,
+    fdkind: u32,
+    underfd: u64,
+    should_cloexec: bool,
+    perfdinfo: u64,
+) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_unused_virtual_fd(cageid, fdkind, underfd, should_cloexec, perfdinfo)
+}
+
+#[doc = include_str!("../docs/get_unused_virtual_fd_from.md")]
+pub fn get_unused_virtual_fd_from(
+    cageid: u64,
+    minfd: u64,
+    fdkind: u32,
+    underfd: u64,
+    should_cloexec: bool,
+    perfdinfo: u64,
+) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_unused_virtual_fd_from(cageid, minfd, fdkind, underfd, should_cloexec, perfdinfo)
+}
+
+#[doc = include_str!("../docs/dup_virtualfd.md")]
+pub fn dup_virtualfd(cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.dup_virtualfd(cageid, oldvirtualfd)
+}
+
+#[doc = include_str!("../docs/dup2_virtualfd.md")]
+pub fn dup2_virtualfd(cageid: u64, oldvirtualfd: u64, newvirtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.dup2_virtualfd(cageid, oldvirtualfd, newvirtualfd)
+}
+
+#[doc = include_str!("../docs/dup3_virtualfd.md")]
+pub fn dup3_virtualfd(cageid: u64, oldvirtualfd: u64, newvirtualfd: u64, should_cloexec: bool) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.dup3_virtualfd(cageid, oldvirtualfd, newvirtualfd, should_cloexec)
+}
+
+#[doc = include_str!("../docs/get_specific_virtual_fd.md")]
+pub fn get_specific_virtual_fd(
+    cageid: u64,
+    requested_virtualfd: u64,
+    fdkind: u32,
+    underfd: u64,
+    should_cloexec: bool,
+    perfdinfo: u64,
+) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.get_specific_virtual_fd(cageid, requested_virtualfd, fdkind, underfd, should_cloexec, perfdinfo)
+}
+
+#[doc = include_str!("../docs/transfer_virtualfds.md")]
+pub fn transfer_virtualfds(srccageid: u64, srcvirtualfds: &[u64], dstcageid: u64, should_cloexec: bool) -> Result<Vec<u64>, FdTableError> {
+    GLOBALFDTABLES.transfer_virtualfds(srccageid, srcvirtualfds, dstcageid, should_cloexec)
+}
+
+#[doc = include_str!("../docs/getfd_virtualfd.md")]
+pub fn getfd_virtualfd(requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.getfd_virtualfd(requestercageid, targetcageid, targetvirtualfd)
+}
+
+#[doc = include_str!("../docs/set_cloexec.md")]
+pub fn set_cloexec(cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_cloexec(cageid, virtualfd, is_cloexec)
+}
+
+#[doc = include_str!("../docs/set_perfdinfo.md")]
+pub fn set_perfdinfo(cageid: u64, virtualfd: u64, perfdinfo: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_perfdinfo(cageid, virtualfd, perfdinfo)
+}
+
+#[doc = include_str!("../docs/update_virtualfd_entry.md")]
+pub fn update_virtualfd_entry<R>(cageid: u64, virtualfd: u64, func: impl FnOnce(&mut FDTableEntry) -> R) -> Result<R, FdTableError> {
+    GLOBALFDTABLES.update_virtualfd_entry(cageid, virtualfd, func)
+}
+
+#[doc = include_str!("../docs/compare_and_swap_virtualfd_entry.md")]
+pub fn compare_and_swap_virtualfd_entry(cageid: u64, virtualfd: u64, expected: &FDTableEntry, new: FDTableEntry) -> Result<bool, FdTableError> {
+    GLOBALFDTABLES.compare_and_swap_virtualfd_entry(cageid, virtualfd, expected, new)
+}
+
+#[doc = include_str!("../docs/get_status_flags.md")]
+pub fn get_status_flags(cageid: u64, virtualfd: u64) -> Result<i32, FdTableError> {
+    GLOBALFDTABLES.get_status_flags(cageid, virtualfd)
+}
+
+#[doc = include_str!("../docs/set_status_flags.md")]
+pub fn set_status_flags(cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_status_flags(cageid, virtualfd, statusflags)
+}
+
+#[doc = include_str!("../docs/get_offset.md")]
+pub fn get_offset(cageid: u64, virtualfd: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_offset(cageid, virtualfd)
+}
+
+#[doc = include_str!("../docs/set_offset.md")]
+pub fn set_offset(cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_offset(cageid, virtualfd, offset)
+}
+
+#[doc = include_str!("../docs/get_fd_limit.md")]
+pub fn get_fd_limit(cageid: u64) -> Result<(u64, u64), FdTableError> {
+    GLOBALFDTABLES.get_fd_limit(cageid)
+}
+
+#[doc = include_str!("../docs/set_fd_limit.md")]
+pub fn set_fd_limit(cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.set_fd_limit(cageid, soft, hard)
+}
+
+#[doc = include_str!("../docs/set_total_fd_limit.md")]
+pub fn set_total_fd_limit(limit: u64) {
+    GLOBALFDTABLES.set_total_fd_limit(limit);
+}
+
+#[doc = include_str!("../docs/get_total_fd_usage.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_total_fd_usage() -> (u64, u64) {
+    GLOBALFDTABLES.get_total_fd_usage()
+}
+
+#[doc = include_str!("../docs/copy_fdtable_for_cage.md")]
+pub fn copy_fdtable_for_cage(srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.copy_fdtable_for_cage(srccageid, newcageid)
+}
+
+#[doc = include_str!("../docs/share_fdtable_for_cage.md")]
+pub fn share_fdtable_for_cage(srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.share_fdtable_for_cage(srccageid, newcageid)
+}
+
+#[doc = include_str!("../docs/unshare_fdtable_for_cage.md")]
+pub fn unshare_fdtable_for_cage(cageid: u64) -> Result<(), FdTableError> {
+    GLOBALFDTABLES.unshare_fdtable_for_cage(cageid)
+}
+
+#[doc = include_str!("../docs/remove_cage_from_fdtable.md")]
+pub fn remove_cage_from_fdtable(cageid: u64) {
+    GLOBALFDTABLES.remove_cage_from_fdtable(cageid);
+}
+
+#[doc = include_str!("../docs/empty_fds_for_exec.md")]
+pub fn empty_fds_for_exec(cageid: u64) {
+    GLOBALFDTABLES.empty_fds_for_exec(cageid);
+}
+
+#[doc = include_str!("../docs/return_fdtable_copy.md")]
+#[must_use] // must use the return value if you call it.
+pub fn return_fdtable_copy(cageid: u64) -> HashMap<u64, FDTableEntry> {
+    GLOBALFDTABLES.return_fdtable_copy(cageid)
+}
+
+#[doc = include_str!("../docs/for_each_virtualfd.md")]
+pub fn for_each_virtualfd(cageid: u64, func: impl FnMut(u64, &FDTableEntry)) {
+    GLOBALFDTABLES.for_each_virtualfd(cageid, func);
+}
+
+#[doc = include_str!("../docs/for_each_virtualfd_in_range.md")]
+pub fn for_each_virtualfd_in_range(cageid: u64, first: u64, last: u64, fdkind: Option<u32>, func: impl FnMut(u64, &FDTableEntry)) {
+    GLOBALFDTABLES.for_each_virtualfd_in_range(cageid, first, last, fdkind, func);
+}
+
+#[doc = include_str!("../docs/get_cageids.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_cageids() -> Vec<u64> {
+    GLOBALFDTABLES.get_cageids()
+}
+
+#[doc = include_str!("../docs/get_fd_count.md")]
+pub fn get_fd_count(cageid: u64) -> Result<u64, FdTableError> {
+    GLOBALFDTABLES.get_fd_count(cageid)
+}
+
+#[doc = include_str!("../docs/get_fd_references.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_fd_references(fdkind: u32, underfd: u64) -> Vec<(u64, u64)> {
+    GLOBALFDTABLES.get_fd_references(fdkind, underfd)
+}
+
+#[doc = include_str!("../docs/get_fd_reference_count.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_fd_reference_count(fdkind: u32, underfd: u64) -> u64 {
+    GLOBALFDTABLES.get_fd_reference_count(fdkind, underfd)
+}
+
+#[doc = include_str!("../docs/get_fd_reference_counts.md")]
+#[must_use] // must use the return value if you call it.
+pub fn get_fd_reference_counts(fdkind: u32) -> HashMap<u64, u64> {
+    GLOBALFDTABLES.get_fd_reference_counts(fdkind)
+}
+
+#[doc = include_str!("../docs/get_virtualfds_of_kind.md")]
+pub fn get_virtualfds_of_kind(cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError> {
+    GLOBALFDTABLES.get_virtualfds_of_kind(cageid, fdkind)
+}
+
+#[doc = include_str!("../docs/set_getfd_policy.md")]
+pub fn set_getfd_policy(policy:GetfdPolicy) {
+    GLOBALFDTABLES.set_getfd_policy(policy);
+}
+
+#[doc = include_str!("../docs/set_getfd_audit.md")]
+pub fn set_getfd_audit(audit:GetfdAudit) {
+    GLOBALFDTABLES.set_getfd_audit(audit);
+}
+
+#[doc = include_str!("../docs/set_unknown_cage_policy.md")]
+pub fn set_unknown_cage_policy(policy:UnknownCagePolicy) {
+    GLOBALFDTABLES.set_unknown_cage_policy(policy);
+}
+
+#[doc = include_str!("../docs/unknown_cage_policy.md")]
+#[must_use] // must use the return value if you call it.
+pub fn unknown_cage_policy() -> UnknownCagePolicy {
+    GLOBALFDTABLES.unknown_cage_policy()
+}
+
+#[doc = include_str!("../docs/close_virtualfd.md")]
+pub fn close_virtualfd(cageid:u64, virtfd:u64) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.close_virtualfd(cageid, virtfd)
+}
+
+#[doc = include_str!("../docs/close_virtualfd_range.md")]
+pub fn close_virtualfd_range(cageid:u64, first:u64, last:u64, flags:u32) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.close_virtualfd_range(cageid, first, last, flags)
+}
+
+#[doc = include_str!("../docs/register_close_handlers.md")]
+pub fn register_close_handlers(fdkind:u32, intermediate: fn(FDTableEntry,u64), last: fn(FDTableEntry,u64)) {
+    GLOBALFDTABLES.register_close_handlers(fdkind, intermediate, last);
+}
+
+#[doc = include_str!("../docs/add_close_handlers.md")]
+#[must_use] // must use the return value if you call it.
+pub fn add_close_handlers(fdkind:u32, intermediate: CloseHandler, last: CloseHandler) -> CloseHandlerToken {
+    GLOBALFDTABLES.add_close_handlers(fdkind, intermediate, last)
+}
+
+#[doc = include_str!("../docs/register_default_close_handlers.md")]
+pub fn register_default_close_handlers(intermediate: CloseHandler, last: CloseHandler) {
+    GLOBALFDTABLES.register_default_close_handlers(intermediate, last);
+}
+
+#[doc = include_str!("../docs/set_strict_close_handlers.md")]
+pub fn set_strict_close_handlers(strict:bool) {
+    GLOBALFDTABLES.set_strict_close_handlers(strict);
+}
+
+#[doc = include_str!("../docs/add_open_handler.md")]
+#[must_use] // must use the return value if you call it.
+pub fn add_open_handler(fdkind:u32, handler: OpenHandler) -> OpenHandlerToken {
+    GLOBALFDTABLES.add_open_handler(fdkind, handler)
+}
+
+#[doc = include_str!("../docs/remove_open_handler.md")]
+#[must_use] // must use the return value if you call it.
+pub fn remove_open_handler(token:OpenHandlerToken) -> bool {
+    GLOBALFDTABLES.remove_open_handler(token)
+}
+
+#[doc = include_str!("../docs/remove_close_handlers.md")]
+#[must_use] // must use the return value if you call it.
+pub fn remove_close_handlers(token:CloseHandlerToken) -> bool {
+    GLOBALFDTABLES.remove_close_handlers(token)
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::implicit_hasher)]
+#[doc = include_str!("../docs/get_bitmask_for_select.md")]
+pub fn get_bitmask_for_select(cageid:u64, nfds:u64, bits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry>>, HashMap<(u32,u64),u64>),FdTableError> {
+    GLOBALFDTABLES.get_bitmask_for_select(cageid, nfds, bits, fdkinds)
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::implicit_hasher)]
+#[doc = include_str!("../docs/prepare_bitmasks_for_select.md")]
+pub fn prepare_bitmasks_for_select(cageid:u64, nfds:u64, rbits:Option<fd_set>, wbits:Option<fd_set>, ebits:Option<fd_set>, fdkinds:&HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry>>;3], HashMap<(u32,u64),u64>),FdTableError> {
+    GLOBALFDTABLES.prepare_bitmasks_for_select(cageid, nfds, rbits, wbits, ebits, fdkinds)
+}
+
+#[allow(clippy::implicit_hasher)]
+#[allow(clippy::type_complexity)]
+#[doc = include_str!("../docs/convert_virtualfds_for_poll.md")]
+#[must_use] // must use the return value if you call it.
+pub fn convert_virtualfds_for_poll(cageid:u64, virtualfds:HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry)>>, HashMap<(u32,u64),u64>) {
+    GLOBALFDTABLES.convert_virtualfds_for_poll(cageid, virtualfds)
+}
+
+#[doc = include_str!("../docs/epoll_create_empty.md")]
+pub fn epoll_create_empty(cageid:u64, should_cloexec:bool) -> Result<u64,FdTableError> {
+    GLOBALFDTABLES.epoll_create_empty(cageid, should_cloexec)
+}
+
+#[doc = include_str!("../docs/epoll_add_underfd.md")]
+pub fn epoll_add_underfd(cageid:u64, virtepollfd:u64, fdkind:u32, underfd:u64) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.epoll_add_underfd(cageid, virtepollfd, fdkind, underfd)
+}
+
+#[doc = include_str!("../docs/epoll_get_underfd_hashmap.md")]
+pub fn epoll_get_underfd_hashmap(cageid:u64, virtepollfd:u64) -> Result<HashMap<u32,u64>,FdTableError> {
+    GLOBALFDTABLES.epoll_get_underfd_hashmap(cageid, virtepollfd)
+}
+
+#[doc = include_str!("../docs/virtualize_epoll_ctl.md")]
+pub fn virtualize_epoll_ctl(cageid:u64, epfd:u64, op:i32, virtfd:u64, event:epoll_event) -> Result<(),FdTableError> {
+    GLOBALFDTABLES.virtualize_epoll_ctl(cageid, epfd, op, virtfd, event)
+}
+
+#[doc = include_str!("../docs/get_virtual_epoll_wait_data.md")]
+pub fn get_virtual_epoll_wait_data(cageid:u64, epfd:u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,FdTableError> {
+    GLOBALFDTABLES.get_virtual_epoll_wait_data(cageid, epfd)
+}
+
+
+
+/********************** BACKEND TRAIT IMPLEMENTATION **********************/
+
+impl<P: Clone + Send + Sync> FdTableBackend<P> for FdTables<P> {
+    const ALGONAME: &'static str = ALGONAME;
+
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn init_empty_cage(&self, cageid: u64) {
+        self.init_empty_cage(cageid);
+    }
+
+    fn translate_virtual_fd(&self, cageid: u64, virtualfd: u64) -> Result<FDTableEntry<P>, FdTableError> {
+        self.translate_virtual_fd(cageid, virtualfd)
+    }
+
+    fn get_unused_virtual_fd(&self, cageid: u64, fdkind: u32, underfd: u64, should_cloexec: bool, perfdinfo: P) -> Result<u64, FdTableError> {
+        self.get_unused_virtual_fd(cageid, fdkind, underfd, should_cloexec, perfdinfo)
+    }
+
+    fn get_unused_virtual_fd_from(&self, cageid: u64, minfd: u64, fdkind: u32, underfd: u64, should_cloexec: bool, perfdinfo: P) -> Result<u64, FdTableError> {
+        self.get_unused_virtual_fd_from(cageid, minfd, fdkind, underfd, should_cloexec, perfdinfo)
+    }
+
+    fn dup_virtualfd(&self, cageid: u64, oldvirtualfd: u64) -> Result<u64, FdTableError> {
+        self.dup_virtualfd(cageid, oldvirtualfd)
+    }
+
+    fn dup2_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64) -> Result<u64, FdTableError> {
+        self.dup2_virtualfd(cageid, oldvirtualfd, newvirtualfd)
+    }
+
+    fn dup3_virtualfd(&self, cageid: u64, oldvirtualfd: u64, newvirtualfd: u64, should_cloexec: bool) -> Result<u64, FdTableError> {
+        self.dup3_virtualfd(cageid, oldvirtualfd, newvirtualfd, should_cloexec)
+    }
+
+    fn get_specific_virtual_fd(&self, cageid: u64, requested_virtualfd: u64, fdkind: u32, underfd: u64, should_cloexec: bool, perfdinfo: P) -> Result<(), FdTableError> {
+        self.get_specific_virtual_fd(cageid, requested_virtualfd, fdkind, underfd, should_cloexec, perfdinfo)
+    }
+
+    fn transfer_virtualfds(&self, srccageid: u64, srcvirtualfds: &[u64], dstcageid: u64, should_cloexec: bool) -> Result<Vec<u64>, FdTableError> {
+        self.transfer_virtualfds(srccageid, srcvirtualfds, dstcageid, should_cloexec)
+    }
+
+    fn getfd_virtualfd(&self, requestercageid: u64, targetcageid: u64, targetvirtualfd: u64) -> Result<u64, FdTableError> {
+        self.getfd_virtualfd(requestercageid, targetcageid, targetvirtualfd)
+    }
+
+    fn set_cloexec(&self, cageid: u64, virtualfd: u64, is_cloexec: bool) -> Result<(), FdTableError> {
+        self.set_cloexec(cageid, virtualfd, is_cloexec)
+    }
+
+    fn set_perfdinfo(&self, cageid: u64, virtualfd: u64, perfdinfo: P) -> Result<(), FdTableError> {
+        self.set_perfdinfo(cageid, virtualfd, perfdinfo)
+    }
+
+    fn update_virtualfd_entry<R>(&self, cageid: u64, virtualfd: u64, func: impl FnOnce(&mut FDTableEntry<P>) -> R) -> Result<R, FdTableError> {
+        self.update_virtualfd_entry(cageid, virtualfd, func)
+    }
+
+    fn compare_and_swap_virtualfd_entry(&self, cageid: u64, virtualfd: u64, expected: &FDTableEntry<P>, new: FDTableEntry<P>) -> Result<bool, FdTableError>
+    where
+        P: PartialEq,
+    {
+        self.compare_and_swap_virtualfd_entry(cageid, virtualfd, expected, new)
+    }
+
+    fn get_status_flags(&self, cageid: u64, virtualfd: u64) -> Result<i32, FdTableError> {
+        self.get_status_flags(cageid, virtualfd)
+    }
+
+    fn set_status_flags(&self, cageid: u64, virtualfd: u64, statusflags: i32) -> Result<(), FdTableError> {
+        self.set_status_flags(cageid, virtualfd, statusflags)
+    }
+
+    fn get_offset(&self, cageid: u64, virtualfd: u64) -> Result<u64, FdTableError> {
+        self.get_offset(cageid, virtualfd)
+    }
+
+    fn set_offset(&self, cageid: u64, virtualfd: u64, offset: u64) -> Result<(), FdTableError> {
+        self.set_offset(cageid, virtualfd, offset)
+    }
+
+    fn get_fd_limit(&self, cageid: u64) -> Result<(u64, u64), FdTableError> {
+        self.get_fd_limit(cageid)
+    }
+
+    fn set_fd_limit(&self, cageid: u64, soft: u64, hard: u64) -> Result<(), FdTableError> {
+        self.set_fd_limit(cageid, soft, hard)
+    }
+
+    fn set_total_fd_limit(&self, limit: u64) {
+        self.set_total_fd_limit(limit);
+    }
+
+    fn get_total_fd_usage(&self) -> (u64, u64) {
+        self.get_total_fd_usage()
+    }
+
+    fn copy_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+        self.copy_fdtable_for_cage(srccageid, newcageid)
+    }
+
+    fn share_fdtable_for_cage(&self, srccageid: u64, newcageid: u64) -> Result<(), FdTableError> {
+        self.share_fdtable_for_cage(srccageid, newcageid)
+    }
+
+    fn unshare_fdtable_for_cage(&self, cageid: u64) -> Result<(), FdTableError> {
+        self.unshare_fdtable_for_cage(cageid)
+    }
+
+    fn remove_cage_from_fdtable(&self, cageid: u64) {
+        self.remove_cage_from_fdtable(cageid);
+    }
+
+    fn empty_fds_for_exec(&self, cageid: u64) {
+        self.empty_fds_for_exec(cageid);
+    }
+
+    fn return_fdtable_copy(&self, cageid: u64) -> HashMap<u64, FDTableEntry<P>> {
+        self.return_fdtable_copy(cageid)
+    }
+
+    fn for_each_virtualfd(&self, cageid: u64, func: impl FnMut(u64, &FDTableEntry<P>)) {
+        self.for_each_virtualfd(cageid, func);
+    }
+
+    fn for_each_virtualfd_in_range(&self, cageid: u64, first: u64, last: u64, fdkind: Option<u32>, func: impl FnMut(u64, &FDTableEntry<P>)) {
+        self.for_each_virtualfd_in_range(cageid, first, last, fdkind, func);
+    }
+
+    fn get_cageids(&self) -> Vec<u64> {
+        self.get_cageids()
+    }
+
+    fn get_fd_count(&self, cageid: u64) -> Result<u64, FdTableError> {
+        self.get_fd_count(cageid)
+    }
+
+    fn get_fd_references(&self, fdkind: u32, underfd: u64) -> Vec<(u64, u64)> {
+        self.get_fd_references(fdkind, underfd)
+    }
+
+    fn get_fd_reference_count(&self, fdkind: u32, underfd: u64) -> u64 {
+        self.get_fd_reference_count(fdkind, underfd)
+    }
+
+    fn get_fd_reference_counts(&self, fdkind: u32) -> HashMap<u64, u64> {
+        self.get_fd_reference_counts(fdkind)
+    }
+
+    fn get_virtualfds_of_kind(&self, cageid: u64, fdkind: u32) -> Result<Vec<u64>, FdTableError> {
+        self.get_virtualfds_of_kind(cageid, fdkind)
+    }
+
+    fn set_getfd_policy(&self, policy: GetfdPolicy) {
+        self.set_getfd_policy(policy);
+    }
+
+    fn set_getfd_audit(&self, audit: GetfdAudit) {
+        self.set_getfd_audit(audit);
+    }
+
+    fn set_unknown_cage_policy(&self, policy: UnknownCagePolicy) {
+        self.set_unknown_cage_policy(policy);
+    }
+
+    fn unknown_cage_policy(&self) -> UnknownCagePolicy {
+        self.unknown_cage_policy()
+    }
+
+    fn close_virtualfd(&self, cageid: u64, virtfd: u64) -> Result<(), FdTableError> {
+        self.close_virtualfd(cageid, virtfd)
+    }
+
+    fn close_virtualfd_range(&self, cageid: u64, first: u64, last: u64, flags: u32) -> Result<(), FdTableError> {
+        self.close_virtualfd_range(cageid, first, last, flags)
+    }
+
+    fn register_close_handlers(&self, fdkind: u32, intermediate: fn(FDTableEntry<P>, u64), last: fn(FDTableEntry<P>, u64)) where P: 'static {
+        self.register_close_handlers(fdkind, intermediate, last);
+    }
+
+    fn add_close_handlers(&self, fdkind: u32, intermediate: CloseHandler<P>, last: CloseHandler<P>) -> CloseHandlerToken {
+        self.add_close_handlers(fdkind, intermediate, last)
+    }
+
+    fn remove_close_handlers(&self, token: CloseHandlerToken) -> bool {
+        self.remove_close_handlers(token)
+    }
+
+    fn register_default_close_handlers(&self, intermediate: CloseHandler<P>, last: CloseHandler<P>) {
+        self.register_default_close_handlers(intermediate, last);
+    }
+
+    fn set_strict_close_handlers(&self, strict: bool) {
+        self.set_strict_close_handlers(strict);
+    }
+
+    fn add_open_handler(&self, fdkind: u32, handler: OpenHandler<P>) -> OpenHandlerToken {
+        self.add_open_handler(fdkind, handler)
+    }
+
+    fn remove_open_handler(&self, token: OpenHandlerToken) -> bool {
+        self.remove_open_handler(token)
+    }
+
+    fn get_bitmask_for_select(&self, cageid: u64, nfds: u64, bits: Option<fd_set>, fdkinds: &HashSet<u32>) -> Result<(HashMap<u32,(u64, fd_set)>, HashMap<u32,HashSet<FDTableEntry<P>>>, HashMap<(u32,u64),u64>),FdTableError>
+    where
+        P: Eq + Hash,
+    {
+        self.get_bitmask_for_select(cageid, nfds, bits, fdkinds)
+    }
+
+    fn prepare_bitmasks_for_select(&self, cageid: u64, nfds: u64, rbits: Option<fd_set>, wbits: Option<fd_set>, ebits: Option<fd_set>, fdkinds: &HashSet<u32>) -> Result<([HashMap<u32,(u64, fd_set)>;3], [HashMap<u32,HashSet<FDTableEntry<P>>>;3], HashMap<(u32,u64),u64>),FdTableError>
+    where
+        P: Eq + Hash,
+    {
+        self.prepare_bitmasks_for_select(cageid, nfds, rbits, wbits, ebits, fdkinds)
+    }
+
+    fn convert_virtualfds_for_poll(&self, cageid: u64, virtualfds: HashSet<u64>) -> (HashMap<u32,HashSet<(u64,FDTableEntry<P>)>>, HashMap<(u32,u64),u64>)
+    where
+        P: Eq + Hash + Default,
+    {
+        self.convert_virtualfds_for_poll(cageid, virtualfds)
+    }
+
+    fn epoll_create_empty(&self, cageid: u64, should_cloexec: bool) -> Result<u64, FdTableError>
+    where
+        P: Default,
+    {
+        self.epoll_create_empty(cageid, should_cloexec)
+    }
+
+    fn epoll_add_underfd(&self, cageid: u64, virtepollfd: u64, fdkind: u32, underfd: u64) -> Result<(), FdTableError> {
+        self.epoll_add_underfd(cageid, virtepollfd, fdkind, underfd)
+    }
+
+    fn epoll_get_underfd_hashmap(&self, cageid: u64, virtepollfd: u64) -> Result<HashMap<u32, u64>, FdTableError> {
+        self.epoll_get_underfd_hashmap(cageid, virtepollfd)
+    }
+
+    fn virtualize_epoll_ctl(&self, cageid: u64, epfd: u64, op: i32, virtfd: u64, event: epoll_event) -> Result<(), FdTableError> {
+        self.virtualize_epoll_ctl(cageid, epfd, op, virtfd, event)
+    }
+
+    fn get_virtual_epoll_wait_data(&self, cageid: u64, epfd: u64) -> Result<HashMap<u32,HashMap<u64,epoll_event>>,FdTableError> {
+        self.get_virtual_epoll_wait_data(cageid, epfd)
+    }
+
+    fn refresh(&self) {
+        self.refresh();
+    }
+}
+
+
+/********************** TESTING HELPER FUNCTION **********************/
+
+impl<P: Clone + Send + Sync> FdTables<P> {
+    // Helper to initialize / empty out state so we can test with a clean system...
+    // only used when testing...
+    //
+    // I'm cleaning up "poisoned" mutexes here so that I can handle tests that
+    // panic
+    #[doc(hidden)]
+    pub fn refresh(&self) {
+        let mut fdtable = self.fdtable.lock().unwrap_or_else(|e| {
+            self.fdtable.clear_poison();
+            e.into_inner()
+        });
+        fdtable.clear();
+        fdtable.insert(threei::TESTING_CAGEID, HashMap::new());
+        let mut closehandlers = self.closehandlertable.lock().unwrap_or_else(|e| {
+            self.closehandlertable.clear_poison();
+            e.into_inner()
+        });
+        closehandlers.clear();
+        *self.defaultclosehandlers.lock().unwrap_or_else(|e| {
+            self.defaultclosehandlers.clear_poison();
+            e.into_inner()
+        }) = None;
+        self.strictclosehandlers.store(false, Ordering::Relaxed);
+        *self.openhandlertable.lock().unwrap_or_else(|e| {
+            self.openhandlertable.clear_poison();
+            e.into_inner()
+        }) = Arc::new(HashMap::new());
+        let mut fdlimits = self.fdlimits.lock().unwrap_or_else(|e| {
+            self.fdlimits.clear_poison();
+            e.into_inner()
+        });
+        fdlimits.clear();
+        let mut sharedtables = self.sharedtables.lock().unwrap_or_else(|e| {
+            self.sharedtables.clear_poison();
+            e.into_inner()
+        });
+        sharedtables.clear();
+        let mut policy = self.unknowncagepolicy.lock().unwrap_or_else(|e| {
+            self.unknowncagepolicy.clear_poison();
+            e.into_inner()
+        });
+        *policy = UnknownCagePolicy::Panic;
+        *self.getfdhooks.lock().unwrap_or_else(|e| {
+            self.getfdhooks.clear_poison();
+            e.into_inner()
+        }) = GetfdHooks::default();
+        self.totalfds.store(0, Ordering::Relaxed);
+        self.totalfdlimit.store(TOTAL_FD_MAX, Ordering::Relaxed);
+        let mut fdcount = self.fdcount.lock().unwrap_or_else(|e| {
+            self.fdcount.clear_poison();
+            e.into_inner()
+        });
+        fdcount.clear();
+        let mut _epolltable = self.epolltable.lock().unwrap_or_else(|e| {
+            self.epolltable.clear_poison();
+            e.into_inner()
+        });
+    }
+}
+
+#[doc(hidden)]
+// Helper to initialize / empty out state so we can test with a clean system...
+// This is only used in tests, thus is hidden...
+pub fn refresh() {
+    GLOBALFDTABLES.refresh();
+}